@@ -0,0 +1,398 @@
+use std::str::FromStr;
+
+use rand::Rng;
+
+use crate::derivation::DerivationTree;
+use crate::grammar::{Grammar, GrammarRule};
+
+/**
+ * How the value of a constrained field is computed from its source fields.
+ */
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintKind {
+    /* Total byte length of the sources */
+    Length,
+    /* CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF) of the sources */
+    Crc16,
+    /* CRC-32 (IEEE 802.3, as used by zlib and Ethernet) of the sources */
+    Crc32,
+    /* Sum of all source bytes, truncated to the field width */
+    Sum,
+    /* Verbatim copy of the source bytes, the encoding is ignored */
+    Equal,
+}
+
+impl FromStr for ConstraintKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "length" => Ok(ConstraintKind::Length),
+            "crc16" => Ok(ConstraintKind::Crc16),
+            "crc32" => Ok(ConstraintKind::Crc32),
+            "sum" => Ok(ConstraintKind::Sum),
+            "equal" => Ok(ConstraintKind::Equal),
+            _ => Err(format!("unknown constraint '{}'", name)),
+        }
+    }
+}
+
+/**
+ * Byte representation of a computed numeric value.
+ */
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldEncoding {
+    /* Unsigned integer with the given number of bytes, little endian */
+    LittleEndian(FieldWidth),
+    /* Unsigned integer with the given number of bytes, big endian */
+    BigEndian(FieldWidth),
+    /* ASCII decimal number, e.g. for Content-Length headers */
+    Decimal,
+}
+
+/**
+ * Number of bytes of a binary field, 1 to 8. Checked when it is created, so
+ * encoding a value never fails.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldWidth(usize);
+
+impl FieldWidth {
+    pub fn new(bytes: usize) -> Result<FieldWidth, String> {
+        if (1..=8).contains(&bytes) {
+            Ok(FieldWidth(bytes))
+        } else {
+            Err(format!("invalid field width {}, use 1 to 8 bytes", bytes))
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.0
+    }
+}
+
+impl FieldEncoding {
+    pub fn little_endian(bytes: usize) -> Result<FieldEncoding, String> {
+        Ok(FieldEncoding::LittleEndian(FieldWidth::new(bytes)?))
+    }
+
+    pub fn big_endian(bytes: usize) -> Result<FieldEncoding, String> {
+        Ok(FieldEncoding::BigEndian(FieldWidth::new(bytes)?))
+    }
+
+    pub fn encode(&self, value: u64) -> Vec<u8> {
+        match *self {
+            FieldEncoding::LittleEndian(width) => value.to_le_bytes()[..width.bytes()].to_vec(),
+            FieldEncoding::BigEndian(width) => value.to_be_bytes()[8 - width.bytes()..].to_vec(),
+            FieldEncoding::Decimal => value.to_string().into_bytes(),
+        }
+    }
+}
+
+/**
+ * Parses "decimal" or an unsigned integer like "u8", "u16le" or "u32be".
+ */
+impl FromStr for FieldEncoding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let unknown = || format!("unknown field encoding '{}'", name);
+        if name == "decimal" {
+            return Ok(FieldEncoding::Decimal);
+        }
+        let bits = name.strip_prefix('u').ok_or_else(unknown)?;
+        let (bits, big_endian) = match (bits.strip_suffix("le"), bits.strip_suffix("be")) {
+            (Some(bits), _) => (bits, false),
+            (_, Some(bits)) => (bits, true),
+            // a single byte has no byte order
+            _ if bits == "8" => (bits, false),
+            _ => return Err(unknown()),
+        };
+        let bits: usize = bits.parse().map_err(|_| unknown())?;
+        if bits % 8 != 0 {
+            return Err(unknown());
+        }
+        if big_endian { FieldEncoding::big_endian(bits / 8) } else { FieldEncoding::little_endian(bits / 8) }
+    }
+}
+
+/**
+ * Semantic constraint between the elements of one grammar alternative.
+ * Whenever a node `symbol` derived with `alternative` is found in a derivation
+ * tree, the child at index `target` is set to the value computed from the
+ * children at the indices in `sources`. The target must be a terminal or regex
+ * element, e.g. "MAGIC-u16le", the sources can be any elements.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constraint {
+    pub symbol: String,
+    pub alternative: usize,
+    pub target: usize,
+    pub sources: Vec<usize>,
+    pub kind: ConstraintKind,
+    pub encoding: FieldEncoding,
+}
+
+#[allow(unused)]
+impl Constraint {
+    pub fn init(symbol: &str, alternative: usize, target: usize, sources: Vec<usize>, kind: ConstraintKind, encoding: FieldEncoding) -> Constraint {
+        Constraint { symbol: symbol.to_string(), alternative, target, sources, kind, encoding }
+    }
+
+    /**
+     * Target is the byte length of the sources.
+     */
+    pub fn length(symbol: &str, alternative: usize, target: usize, sources: Vec<usize>, encoding: FieldEncoding) -> Constraint {
+        Constraint::init(symbol, alternative, target, sources, ConstraintKind::Length, encoding)
+    }
+
+    /**
+     * Target is a checksum (Crc16, Crc32 or Sum) of the sources.
+     */
+    pub fn checksum(symbol: &str, alternative: usize, target: usize, sources: Vec<usize>, kind: ConstraintKind, encoding: FieldEncoding) -> Constraint {
+        Constraint::init(symbol, alternative, target, sources, kind, encoding)
+    }
+
+    /**
+     * Target repeats the bytes of the source.
+     */
+    pub fn equal(symbol: &str, alternative: usize, target: usize, source: usize) -> Constraint {
+        Constraint::init(symbol, alternative, target, vec![source], ConstraintKind::Equal, FieldEncoding::Decimal)
+    }
+
+    fn compute(&self, children: &[DerivationTree]) -> Vec<u8> {
+        let bytes: Vec<u8> = self.sources.iter().flat_map(|&i| children[i].to_bytes()).collect();
+        match self.kind {
+            ConstraintKind::Length => self.encoding.encode(bytes.len() as u64),
+            ConstraintKind::Crc16 => self.encoding.encode(crc16(&bytes) as u64),
+            ConstraintKind::Crc32 => self.encoding.encode(crc32(&bytes) as u64),
+            ConstraintKind::Sum => self.encoding.encode(bytes.iter().fold(0u64, |sum, &b| sum.wrapping_add(b as u64))),
+            ConstraintKind::Equal => bytes,
+        }
+    }
+}
+
+/**
+ * The constraints of a grammar. They are applied to a derivation tree after it
+ * was generated or mutated, bottom-up so that a checksum covers the already
+ * fixed length fields below it. Constraints of the same alternative are applied
+ * in the order they were added.
+ * With `violation_fraction` > 0 each constraint is deliberately broken with that
+ * probability, to test the checks of the target as well.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ConstraintSet {
+    pub constraints: Vec<Constraint>,
+    pub violation_fraction: f64,
+}
+
+#[allow(unused)]
+impl ConstraintSet {
+    pub fn empty() -> ConstraintSet {
+        ConstraintSet::default()
+    }
+
+    pub fn add(&mut self, constraint: Constraint) {
+        self.constraints.push(constraint);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.constraints.is_empty()
+    }
+
+    /**
+     * Checks that every constraint refers to an existing alternative and element
+     * and that its target is a terminal. Returns a description of every problem found.
+     */
+    pub fn validate(&self, grammar: &Grammar) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        for c in &self.constraints {
+            let Some(rule) = grammar.get(&c.symbol).and_then(|rules| rules.get(c.alternative)) else {
+                errors.push(format!("constraint refers to missing alternative {} of <{}>", c.alternative, c.symbol));
+                continue;
+            };
+            match rule.get(c.target) {
+                Some(GrammarRule::NonTerminal(nt)) => errors.push(format!(
+                    "constraint target <{}> in <{}> is no terminal", nt, c.symbol)),
+                None => errors.push(format!("constraint target {} out of range in <{}>", c.target, c.symbol)),
+                _ => {},
+            }
+            if let Some(source) = c.sources.iter().find(|&&s| s >= rule.len()) {
+                errors.push(format!("constraint source {} out of range in <{}>", source, c.symbol));
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /**
+     * Sets every constrained field in `tree` and returns the number of
+     * constraints which were deliberately violated.
+     */
+    pub fn apply(&self, tree: &mut DerivationTree, rng: &mut impl Rng) -> usize {
+        if self.constraints.is_empty() {
+            return 0;
+        }
+        let mut violations = 0;
+        self.apply_node(tree, rng, &mut violations);
+        violations
+    }
+
+    fn apply_node(&self, tree: &mut DerivationTree, rng: &mut impl Rng, violations: &mut usize) {
+        let DerivationTree::NonTerminal { symbol, alternative, children } = tree else {
+            return;
+        };
+        for child in children.iter_mut() {
+            self.apply_node(child, rng, violations);
+        }
+        for c in self.constraints.iter().filter(|c| c.symbol == *symbol && c.alternative == *alternative) {
+            let mut value = c.compute(children);
+            if self.violation_fraction > 0.0 && rng.gen_bool(self.violation_fraction.min(1.0)) {
+                violate(&mut value, rng);
+                *violations += 1;
+            }
+            if let DerivationTree::Terminal { value: target, .. } = &mut children[c.target] {
+                *target = value;
+            }
+        }
+    }
+}
+
+/*
+ * Changes a correct field value, flipping one bit keeps the field length.
+ */
+fn violate(value: &mut Vec<u8>, rng: &mut impl Rng) {
+    if value.is_empty() {
+        value.push(rng.gen());
+    } else {
+        let index = rng.gen_range(0..value.len());
+        value[index] ^= 1 << rng.gen_range(0..8);
+    }
+}
+
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::grammar::{generate_tree, GRAMMAR_ENTRY};
+    use crate::terminals::TerminalRegistry;
+
+    /*
+     * Start -> "\xAA" Length Payload Crc
+     * Payload -> "MAGIC-Byte" Payload | ""
+     */
+    fn packet_grammar() -> (Grammar, ConstraintSet) {
+        let mut grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![
+            GrammarRule::Terminal(b"\xAA".to_vec()),
+            GrammarRule::Terminal(b"MAGIC-u16le".to_vec()),
+            GrammarRule::NonTerminal("Payload".to_string()),
+            GrammarRule::Terminal(b"MAGIC-u32be".to_vec()),
+        ]]);
+        grammar.insert("Payload".to_string(), vec![
+            vec![GrammarRule::Terminal(b"MAGIC-Byte".to_vec()), GrammarRule::NonTerminal("Payload".to_string())],
+            vec![],
+        ]);
+        let mut constraints = ConstraintSet::empty();
+        constraints.add(Constraint::length(GRAMMAR_ENTRY, 0, 1, vec![2], FieldEncoding::little_endian(2).unwrap()));
+        constraints.add(Constraint::checksum(GRAMMAR_ENTRY, 0, 3, vec![0, 1, 2], ConstraintKind::Crc32, FieldEncoding::big_endian(4).unwrap()));
+        (grammar, constraints)
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_apply_length_and_crc() {
+        let (grammar, constraints) = packet_grammar();
+        assert!(constraints.validate(&grammar).is_ok());
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..20 {
+            let mut tree = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+            assert_eq!(constraints.apply(&mut tree, &mut rng), 0);
+            let bytes = tree.to_bytes();
+            let length = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+            assert_eq!(length, bytes.len() - 7);
+            let (body, crc) = bytes.split_at(bytes.len() - 4);
+            assert_eq!(crc, crc32(body).to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn test_violation_fraction() {
+        let (grammar, mut constraints) = packet_grammar();
+        constraints.violation_fraction = 1.0;
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut tree = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+        assert_eq!(constraints.apply(&mut tree, &mut rng), 2);
+        let bytes = tree.to_bytes();
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        assert_ne!(crc, crc32(body).to_be_bytes());
+    }
+
+    #[test]
+    fn test_equal_and_validate() {
+        let mut grammar: Grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![
+            GrammarRule::regex("[a-z]{3}"),
+            GrammarRule::Terminal(b"=".to_vec()),
+            GrammarRule::regex("[a-z]{3}"),
+        ]]);
+        let mut constraints = ConstraintSet::empty();
+        constraints.add(Constraint::equal(GRAMMAR_ENTRY, 0, 2, 0));
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut tree = generate_tree(&grammar, &TerminalRegistry::init(), GRAMMAR_ENTRY, &mut rng);
+        constraints.apply(&mut tree, &mut rng);
+        let bytes = tree.to_bytes();
+        assert_eq!(bytes[..3], bytes[4..]);
+
+        constraints.add(Constraint::length(GRAMMAR_ENTRY, 1, 0, vec![0], FieldEncoding::Decimal));
+        constraints.add(Constraint::length(GRAMMAR_ENTRY, 0, 5, vec![0], FieldEncoding::Decimal));
+        assert_eq!(constraints.validate(&grammar).unwrap_err().len(), 2);
+        assert!(FieldEncoding::big_endian(9).is_err() && FieldEncoding::little_endian(0).is_err());
+    }
+
+    #[test]
+    fn test_parse_names() {
+        assert_eq!("crc32".parse::<ConstraintKind>(), Ok(ConstraintKind::Crc32));
+        assert!("crc64".parse::<ConstraintKind>().is_err());
+        assert_eq!("u8".parse::<FieldEncoding>(), FieldEncoding::little_endian(1));
+        assert_eq!("u16le".parse::<FieldEncoding>(), FieldEncoding::little_endian(2));
+        assert_eq!("u64be".parse::<FieldEncoding>(), FieldEncoding::big_endian(8));
+        assert_eq!("decimal".parse::<FieldEncoding>(), Ok(FieldEncoding::Decimal));
+        for invalid in ["u12le", "u128be", "u16", "i16le", "le"] {
+            assert!(invalid.parse::<FieldEncoding>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,609 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::derivation::DerivationTree;
+use crate::earley::{EarleyParser, ImportSummary};
+use crate::grammar::{Grammar, GRAMMAR_ENTRY};
+use crate::grammar_coverage::GrammarCoverage;
+use crate::power_schedule::{energy, PowerSchedule};
+use crate::runner::{RunnableProgram, RunnerResult};
+use crate::seeder::{ByteMutation, Seedable};
+use crate::terminals::TerminalRegistry;
+
+/*
+ * Probability to pick a favored entry under the favored policy, as long as
+ * there are favored entries.
+ */
+const FAVORED_PROBABILITY: f64 = 0.9;
+
+/*
+ * Limits of the weight of an entry relative to an average entry under the
+ * weighted policy.
+ */
+const MIN_WEIGHT: f64 = 0.1;
+const MAX_WEIGHT: f64 = 10.0;
+
+/**
+ * How the corpus seeder picks the entry to mutate next.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulePolicy {
+    /* Every entry in turn */
+    RoundRobin,
+    /* Randomly, small and fast entries more often */
+    Weighted,
+    /* Mostly favored entries, the smallest and fastest one of each behavior */
+    Favored,
+}
+
+pub const ALL_SCHEDULE_POLICIES: [SchedulePolicy; 3] = [SchedulePolicy::RoundRobin, SchedulePolicy::Weighted, SchedulePolicy::Favored];
+
+impl SchedulePolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SchedulePolicy::RoundRobin => "round-robin",
+            SchedulePolicy::Weighted => "weighted",
+            SchedulePolicy::Favored => "favored",
+        }
+    }
+}
+
+impl FromStr for SchedulePolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<SchedulePolicy, String> {
+        ALL_SCHEDULE_POLICIES.into_iter()
+            .find(|p| p.name() == name)
+            .ok_or_else(|| format!("unknown schedule policy '{}'", name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorpusEntry {
+    pub input: Vec<u8>,
+    /* Run time and behavior signature of the entry, None until it was run */
+    pub exec_time: Option<Duration>,
+    pub behavior: Option<u64>,
+    pub favored: bool,
+    /* Times the entry was picked */
+    pub selected: u64,
+    /* Entries added from mutants of this entry */
+    pub finds: u64,
+    /* Value of `selected` when the entry last led to a new entry */
+    pub last_find: u64,
+    /* Number of ancestors, 0 for seed files */
+    pub depth: usize,
+    /* Derivation of the input, if it is known or parsed, see parse_trees() */
+    pub tree: Option<DerivationTree>,
+}
+
+/**
+ * Inputs worth mutating: the seed files of the campaign and every input which
+ * showed a behavior signature (see behavior_signature()) not seen before.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Corpus {
+    pub entries: Vec<CorpusEntry>,
+    behaviors: HashSet<u64>,
+    /* Runs per behavior signature */
+    hits: HashMap<u64, u64>,
+}
+
+#[allow(unused)]
+impl Corpus {
+    pub fn new() -> Corpus {
+        Corpus::default()
+    }
+
+    /**
+     * Adds an input unless it is already in the corpus and returns its index.
+     */
+    pub fn add(&mut self, input: Vec<u8>) -> usize {
+        self.add_child(input, None)
+    }
+
+    /**
+     * Adds an input found by mutating the entry `parent`, see add().
+     */
+    pub fn add_child(&mut self, input: Vec<u8>, parent: Option<usize>) -> usize {
+        if let Some(index) = self.entries.iter().position(|e| e.input == input) {
+            return index;
+        }
+        let depth = parent.map_or(0, |p| self.entries[p].depth + 1);
+        self.entries.push(CorpusEntry { input, exec_time: None, behavior: None, favored: false, selected: 0, finds: 0, last_find: 0, depth, tree: None });
+        self.entries.len() - 1
+    }
+
+    /**
+     * Adds every file in `directory`, in the order of the file names, and
+     * returns the number of new entries.
+     */
+    pub fn load_directory(&mut self, directory: &Path) -> io::Result<usize> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let before = self.entries.len();
+        for path in paths {
+            self.add(fs::read(&path)?);
+        }
+        Ok(self.entries.len() - before)
+    }
+
+    /**
+     * Parses the entries without a derivation tree with `grammar`, so that
+     * tree mutations and subtree splicing apply to them. Entries which do not
+     * parse keep no tree.
+     */
+    pub fn parse_trees(&mut self, grammar: &Grammar, terminals: &TerminalRegistry) -> ImportSummary {
+        let parser = EarleyParser::init(grammar, terminals);
+        let mut summary = ImportSummary::default();
+        for (index, entry) in self.entries.iter_mut().enumerate().filter(|(_, e)| e.tree.is_none()) {
+            let name = format!("corpus entry {} {:?}", index, String::from_utf8_lossy(&entry.input));
+            entry.tree = summary.record(&name, parser.parse(GRAMMAR_ENTRY, &entry.input));
+        }
+        summary
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_new_behavior(&self, behavior: u64) -> bool {
+        !self.behaviors.contains(&behavior)
+    }
+
+    /**
+     * Counts a run with the behavior signature `behavior`.
+     */
+    pub fn hit(&mut self, behavior: u64) {
+        *self.hits.entry(behavior).or_insert(0) += 1;
+    }
+
+    /**
+     * Runs with the behavior signature `behavior` so far.
+     */
+    pub fn hits(&self, behavior: u64) -> u64 {
+        self.hits.get(&behavior).copied().unwrap_or(0)
+    }
+
+    /**
+     * Average runs per behavior of the corpus entries.
+     */
+    pub fn mean_hits(&self) -> f64 {
+        if self.behaviors.is_empty() {
+            return 0.0;
+        }
+        self.behaviors.iter().map(|&b| self.hits(b)).sum::<u64>() as f64 / self.behaviors.len() as f64
+    }
+
+    /**
+     * Records the run time and behavior of the entry at `index` from a run of
+     * its unchanged input.
+     */
+    pub fn record(&mut self, index: usize, result: &RunnerResult) {
+        let entry = &mut self.entries[index];
+        entry.exec_time = Some(result.exec_time);
+        entry.behavior = Some(result.behavior);
+        self.behaviors.insert(result.behavior);
+        self.update_favored();
+    }
+
+    /**
+     * Runs every entry which was not run yet once, like the dry run of AFL.
+     * `prepare` turns an entry into the input of the target, e.g. by encoding it.
+     */
+    pub fn calibrate(&mut self, runner: &impl RunnableProgram, prepare: impl Fn(&[u8]) -> Vec<u8>) {
+        for index in 0..self.entries.len() {
+            if self.entries[index].behavior.is_some() {
+                continue;
+            }
+            let mut input = prepare(&self.entries[index].input);
+            runner.sanitize_seed(&mut input);
+            let result = runner.run(&input);
+            self.hit(result.result.behavior);
+            self.record(index, &result.result);
+        }
+    }
+
+    /*
+     * Marks the entry with the smallest product of length and run time of
+     * every behavior as favored.
+     */
+    fn update_favored(&mut self) {
+        let cost = |e: &CorpusEntry| (e.input.len() as u128 + 1) * e.exec_time.unwrap_or_default().as_micros().max(1);
+        for entry in &mut self.entries {
+            entry.favored = false;
+        }
+        for &behavior in &self.behaviors {
+            let best = self.entries.iter_mut()
+                .filter(|e| e.behavior == Some(behavior))
+                .min_by_key(|e| cost(e));
+            if let Some(entry) = best {
+                entry.favored = true;
+            }
+        }
+    }
+
+    /*
+     * Weight under the weighted policy, relative to the average length and
+     * run time. Entries which were not run yet count as average.
+     */
+    fn weights(&self) -> Vec<f64> {
+        let times: Vec<f64> = self.entries.iter().filter_map(|e| e.exec_time).map(|t| t.as_secs_f64()).collect();
+        let mean_time = if times.is_empty() { 0.0 } else { times.iter().sum::<f64>() / times.len() as f64 };
+        let mean_length = self.entries.iter().map(|e| e.input.len() as f64).sum::<f64>() / self.entries.len().max(1) as f64;
+        self.entries.iter()
+            .map(|e| {
+                let time = e.exec_time.map_or(mean_time, |t| t.as_secs_f64());
+                let speed = if time > 0.0 && mean_time > 0.0 { mean_time / time } else { 1.0 };
+                let size = (mean_length + 1.0) / (e.input.len() as f64 + 1.0);
+                (speed * size).clamp(MIN_WEIGHT, MAX_WEIGHT)
+            })
+            .collect()
+    }
+
+    /**
+     * Index of the next entry to mutate. `cursor` is the position of the
+     * round-robin policy.
+     */
+    pub fn choose(&self, policy: SchedulePolicy, cursor: &mut usize, rng: &mut impl Rng) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let index = match policy {
+            SchedulePolicy::RoundRobin => {
+                *cursor %= self.entries.len();
+                *cursor += 1;
+                *cursor - 1
+            },
+            SchedulePolicy::Weighted => {
+                let weights = self.weights();
+                let mut target = rng.gen_range(0.0..weights.iter().sum::<f64>());
+                weights.iter().position(|w| {
+                    target -= w;
+                    target < 0.0
+                }).unwrap_or(self.entries.len() - 1)
+            },
+            SchedulePolicy::Favored => {
+                let favored: Vec<usize> = (0..self.entries.len()).filter(|&i| self.entries[i].favored).collect();
+                if !favored.is_empty() && rng.gen_bool(FAVORED_PROBABILITY) {
+                    favored[rng.gen_range(0..favored.len())]
+                } else {
+                    rng.gen_range(0..self.entries.len())
+                }
+            },
+        };
+        Some(index)
+    }
+
+    /**
+     * One-line summary, e.g. `14 entries, 3 favored, 3 behaviors`.
+     */
+    pub fn report(&self) -> String {
+        let favored = self.entries.iter().filter(|e| e.favored).count();
+        format!("{} entries, {} favored, {} behaviors", self.entries.len(), favored, self.behaviors.len())
+    }
+}
+
+/**
+ * Seeder which picks entries of a corpus by a schedule policy. It yields the
+ * entries unchanged, mutation layers stacked on top of it mutate them. Every
+ * reported result with a new behavior signature adds the input that was run,
+ * rebuilt from the entry and the reported mutations, to the corpus. While the
+ * corpus is empty, seeds come from the inner seeder, e.g. a GrammarSeeder.
+ */
+pub struct CorpusSeeder<S: Seedable, R: Rng> {
+    pub corpus: Corpus,
+    pub policy: SchedulePolicy,
+    /* Seeds per picked entry, one if None */
+    pub power_schedule: Option<PowerSchedule>,
+    pub seeder: S,
+    rng: R,
+    cursor: usize,
+    /* Entry of the last seed, None if the inner seeder produced it */
+    last_entry: Option<usize>,
+    last_seed: Vec<u8>,
+    /* Leading reported mutations which are part of last_seed already */
+    base_mutations: usize,
+    /* Seeds left for the last entry */
+    energy: usize,
+    /* Entries added during the campaign which were not picked yet, picked before all others */
+    queued: VecDeque<usize>,
+}
+
+impl<S: Seedable, R: Rng> CorpusSeeder<S, R> {
+    pub fn init(corpus: Corpus, policy: SchedulePolicy, seeder: S, rng: R) -> CorpusSeeder<S, R> {
+        CorpusSeeder {
+            corpus,
+            policy,
+            seeder,
+            rng,
+            cursor: 0,
+            last_entry: None,
+            last_seed: Vec::new(),
+            base_mutations: 0,
+            power_schedule: None,
+            energy: 0,
+            queued: VecDeque::new(),
+        }
+    }
+
+    /*
+     * Picks the next entry, a queued one or else one by the policy, and
+     * assigns its energy. Entries without energy, which the coe schedule
+     * skips, are picked again, as long as not every entry was tried. Queued
+     * entries are never skipped.
+     */
+    fn pick(&mut self) -> Option<usize> {
+        if let Some(index) = self.queued.pop_front() {
+            self.energy = self.power_schedule.map_or(1, |schedule| energy(&self.corpus, index, schedule)).max(1);
+            self.corpus.entries[index].selected += 1;
+            return Some(index);
+        }
+        let mut index = None;
+        for _ in 0..self.corpus.len() {
+            index = self.corpus.choose(self.policy, &mut self.cursor, &mut self.rng);
+            self.energy = match (index, self.power_schedule) {
+                (Some(index), Some(schedule)) => energy(&self.corpus, index, schedule),
+                _ => 1,
+            };
+            if self.energy > 0 {
+                break;
+            }
+        }
+        let index = index?;
+        self.energy = self.energy.max(1);
+        self.corpus.entries[index].selected += 1;
+        Some(index)
+    }
+
+    /*
+     * Counts the behavior of `result` and adds `input` as a child of `parent`
+     * if the behavior is new. Returns whether a new entry was added.
+     */
+    fn add_find(&mut self, input: Vec<u8>, parent: Option<usize>, result: &RunnerResult) -> bool {
+        self.corpus.hit(result.behavior);
+        if !self.corpus.is_new_behavior(result.behavior) {
+            return false;
+        }
+        let entries = self.corpus.len();
+        let index = self.corpus.add_child(input, parent);
+        self.corpus.record(index, result);
+        if self.corpus.len() == entries {
+            return false;
+        }
+        self.corpus.entries[index].tree = result.derivation_tree.clone();
+        // new entries are picked next, e.g. for their deterministic pass
+        self.queued.push_back(index);
+        true
+    }
+
+    /**
+     * Entry of the last seed, None if the inner seeder produced it.
+     */
+    #[allow(unused)]
+    pub fn last_entry(&self) -> Option<usize> {
+        self.last_entry
+    }
+}
+
+impl<S: Seedable, R: Rng> Seedable for CorpusSeeder<S, R> {
+    fn next_seed(&mut self) -> Vec<u8> {
+        if self.energy == 0 || self.last_entry.is_none() {
+            self.last_entry = self.pick();
+        }
+        self.energy = self.energy.saturating_sub(1);
+        self.last_seed = match self.last_entry {
+            Some(index) => {
+                self.base_mutations = 0;
+                self.corpus.entries[index].input.clone()
+            },
+            None => {
+                let seed = self.seeder.next_seed();
+                self.base_mutations = self.seeder.last_mutations().len();
+                seed
+            },
+        };
+        self.last_seed.clone()
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        match self.last_entry {
+            Some(index) => self.corpus.entries[index].tree.as_ref(),
+            None => self.seeder.last_derivation_tree(),
+        }
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.seeder.grammar_coverage()
+    }
+
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        match self.last_entry {
+            Some(_) => Vec::new(),
+            None => self.seeder.last_mutations(),
+        }
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        let mutations = result.mutations.get(self.base_mutations..).unwrap_or_default();
+        match self.last_entry {
+            Some(index) if mutations.is_empty() && self.corpus.entries[index].behavior.is_none() => self.corpus.record(index, result),
+            Some(_) => {},
+            None => self.seeder.report_result(result),
+        }
+        let input = ByteMutation::replay(&self.last_seed, mutations);
+        if !self.add_find(input, self.last_entry, result) {
+            return;
+        }
+        if let Some(parent) = self.last_entry {
+            let parent = &mut self.corpus.entries[parent];
+            parent.finds += 1;
+            parent.last_find = parent.selected;
+        }
+    }
+
+    fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+        self.add_find(input.to_vec(), None, result);
+        self.seeder.report_foreign(input, result);
+    }
+
+    fn report(&self, out: &mut Vec<String>) {
+        self.seeder.report(out);
+        out.push(format!("Corpus: {}", self.corpus.report()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::deterministic::{DeterministicSeeder, DeterministicStage};
+    use crate::grammar::create_cgi_grammar;
+    use crate::runner::State;
+    use crate::seeder::{MutationSeedModifier, RandomSeeder};
+
+    fn result(seed: Vec<u8>, mutations: Vec<ByteMutation>, micros: u64, behavior: u64) -> RunnerResult {
+        RunnerResult { mutations, exec_time: Duration::from_micros(micros), ..RunnerResult::for_test(State::Pass, &seed, behavior) }
+    }
+
+    fn calibrated_corpus() -> Corpus {
+        let mut corpus = Corpus::new();
+        for (input, micros, behavior) in [(&b"a=1"[..], 100, 1), (b"a=1&b=22", 100, 1), (b"x", 1000, 2), (b"%41", 100, 3)] {
+            let index = corpus.add(input.to_vec());
+            corpus.record(index, &result(input.to_vec(), Vec::new(), micros, behavior));
+        }
+        corpus
+    }
+
+    #[test]
+    fn test_schedule_policies() {
+        let corpus = calibrated_corpus();
+        let favored: Vec<bool> = corpus.entries.iter().map(|e| e.favored).collect();
+        assert_eq!(favored, vec![true, false, true, true]);
+        assert_eq!(corpus.report(), "4 entries, 3 favored, 3 behaviors");
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut cursor = 0;
+        let round_robin: Vec<usize> = (0..6).filter_map(|_| corpus.choose(SchedulePolicy::RoundRobin, &mut cursor, &mut rng)).collect();
+        assert_eq!(round_robin, vec![0, 1, 2, 3, 0, 1]);
+
+        let mut counts = [[0; 4]; 2];
+        for (policy, counts) in [SchedulePolicy::Weighted, SchedulePolicy::Favored].into_iter().zip(&mut counts) {
+            for _ in 0..2000 {
+                counts[corpus.choose(policy, &mut cursor, &mut rng).unwrap()] += 1;
+            }
+        }
+        // weighted: the long and the slow entry less often
+        assert!(2 * counts[0][0] > 3 * counts[0][1] && counts[0][3] > 3 * counts[0][2]);
+        // favored: the non-favored entry rarely
+        assert!(counts[1][1] < counts[1][0] / 5);
+        assert_eq!("favored".parse::<SchedulePolicy>(), Ok(SchedulePolicy::Favored));
+    }
+
+    #[test]
+    fn test_corpus_seeder_adds_new_behaviors() {
+        let mut corpus = Corpus::new();
+        corpus.add(b"id=7".to_vec());
+        let corpus_seeder = CorpusSeeder::init(corpus, SchedulePolicy::RoundRobin, RandomSeeder::<StdRng>::init(1), StdRng::seed_from_u64(2));
+        let mut seeder = MutationSeedModifier::init(corpus_seeder, StdRng::seed_from_u64(3));
+        for round in 0..40u64 {
+            let seed = seeder.next_seed();
+            seeder.report_result(&result(seed, seeder.last_mutations(), 10, round % 4));
+        }
+        let corpus = &seeder.seeder.corpus;
+        assert_eq!(corpus.report().split(", ").last(), Some("4 behaviors"));
+        // entry 0 either got a behavior of its own or all four were found by mutation
+        assert!(corpus.len() == 4 || corpus.len() == 5);
+        assert_eq!(corpus.entries.iter().map(|e| e.finds).sum::<u64>() as usize, corpus.len() - 1);
+        // every added entry is a mutated input which was run, one byte changed per round
+        for entry in &corpus.entries[1..] {
+            assert!(entry.input.len().abs_diff(4) <= 4);
+        }
+
+        // an empty corpus is filled from the inner seeder
+        let mut seeder = CorpusSeeder::init(Corpus::new(), SchedulePolicy::Weighted, RandomSeeder::<StdRng>::init(4), StdRng::seed_from_u64(5));
+        let seed = seeder.next_seed();
+        assert_eq!(seeder.last_entry(), None);
+        seeder.report_result(&result(seed.clone(), Vec::new(), 10, 9));
+        assert_eq!(seeder.next_seed(), seed);
+        assert_eq!(seeder.last_entry(), Some(0));
+    }
+
+    #[test]
+    fn test_parsed_entries_keep_their_tree() {
+        let mut corpus = Corpus::new();
+        corpus.add(b"a+%4F".to_vec());
+        corpus.add(b"a b".to_vec());
+        let summary = corpus.parse_trees(&create_cgi_grammar(), &TerminalRegistry::init());
+        assert_eq!((summary.parsed, summary.failures.len()), (1, 1));
+        assert!(summary.failures[0].starts_with("corpus entry 1 \"a b\": parse error at byte 1"));
+        assert_eq!(summary.report(), "1 of 2 samples parsed, 0 ambiguous");
+
+        let mut seeder = CorpusSeeder::init(corpus, SchedulePolicy::RoundRobin, RandomSeeder::<StdRng>::init(1), StdRng::seed_from_u64(2));
+        let seed = seeder.next_seed();
+        assert_eq!(seeder.last_derivation_tree().map(DerivationTree::to_bytes), Some(seed));
+        seeder.next_seed();
+        assert!(seeder.last_derivation_tree().is_none());
+    }
+
+    #[test]
+    fn test_power_schedule_repeats_entries() {
+        let mut corpus = calibrated_corpus();
+        // behavior 1 dominates, so coe skips entries 0 and 1
+        for _ in 0..20 {
+            corpus.hit(1);
+        }
+        let mut seeder = CorpusSeeder::init(corpus, SchedulePolicy::RoundRobin, RandomSeeder::<StdRng>::init(1), StdRng::seed_from_u64(2));
+        seeder.power_schedule = Some(PowerSchedule::Coe);
+        let mut picked = Vec::new();
+        for _ in 0..64 {
+            seeder.next_seed();
+            picked.push(seeder.last_entry().unwrap());
+        }
+        assert!(picked.iter().all(|&index| index >= 2));
+        // each pick yields a run of seeds from the same entry
+        let runs = 1 + picked.windows(2).filter(|w| w[0] != w[1]).count() as u64;
+        assert_eq!(runs, seeder.corpus.entries.iter().map(|e| e.selected).sum::<u64>());
+        assert!(runs < 16);
+    }
+
+    #[test]
+    fn test_new_entries_get_one_deterministic_pass() {
+        let mut corpus = Corpus::new();
+        let index = corpus.add(b"ab".to_vec());
+        corpus.record(index, &result(b"ab".to_vec(), Vec::new(), 10, 0));
+        let corpus_seeder = CorpusSeeder::init(corpus, SchedulePolicy::RoundRobin, RandomSeeder::<StdRng>::init(1), StdRng::seed_from_u64(2));
+        let mut seeder = DeterministicSeeder::init_with_stages(corpus_seeder, vec![DeterministicStage::ByteFlip], Vec::new());
+        let mut seeds = Vec::new();
+        for _ in 0..6 {
+            let seed = seeder.next_seed();
+            // only flipping the first byte finds something new
+            let behavior = (seed == b"\x9eb") as u64;
+            seeder.report_result(&result(seed.clone(), seeder.last_mutations(), 10, behavior));
+            seeds.push(seed);
+        }
+        // the new entry is mutated right after the pass over "ab", then both are passed on unmutated
+        assert_eq!(seeds[..4], [b"\x9eb".to_vec(), b"a\x9d".to_vec(), b"ab".to_vec(), b"\x9e\x9d".to_vec()]);
+        assert!(seeds[4..].contains(&b"ab".to_vec()) && seeds[4..].contains(&b"\x9eb".to_vec()));
+        assert_eq!(seeder.completed, 2);
+        assert_eq!(seeder.seeder.corpus.len(), 2);
+    }
+}
@@ -0,0 +1,196 @@
+/**
+ * A derivation tree records how an input was produced from a grammar.
+ * Nonterminal nodes keep the symbol and the index of the alternative that was
+ * chosen for it, terminal nodes keep the symbol they were created from
+ * (for example "MAGIC-HexDigit") together with the bytes that were emitted.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DerivationTree {
+    Terminal {
+        symbol: String,
+        value: Vec<u8>,
+    },
+    NonTerminal {
+        symbol: String,
+        /* Index into the alternatives of `symbol` in the grammar */
+        alternative: usize,
+        children: Vec<DerivationTree>,
+    },
+}
+
+impl DerivationTree {
+    pub fn symbol(&self) -> &str {
+        match self {
+            DerivationTree::Terminal { symbol, .. } => symbol,
+            DerivationTree::NonTerminal { symbol, .. } => symbol,
+        }
+    }
+
+    /**
+     * Returns the chosen alternative, or None for terminal nodes.
+     */
+    #[allow(unused)]
+    pub fn alternative(&self) -> Option<usize> {
+        match self {
+            DerivationTree::Terminal { .. } => None,
+            DerivationTree::NonTerminal { alternative, .. } => Some(*alternative),
+        }
+    }
+
+    pub fn children(&self) -> &[DerivationTree] {
+        match self {
+            DerivationTree::Terminal { .. } => &[],
+            DerivationTree::NonTerminal { children, .. } => children,
+        }
+    }
+
+    /**
+     * Serializes the tree into the input it represents by concatenating
+     * all terminal values from left to right.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_bytes(&mut out);
+        out
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        match self {
+            DerivationTree::Terminal { value, .. } => out.extend_from_slice(value),
+            DerivationTree::NonTerminal { children, .. } => {
+                for child in children {
+                    child.write_bytes(out);
+                }
+            }
+        }
+    }
+
+    /**
+     * Returns the node at the given path of child indices, the empty path is the root.
+     */
+    pub fn get(&self, path: &[usize]) -> Option<&DerivationTree> {
+        match path.split_first() {
+            None => Some(self),
+            Some((&index, rest)) => self.children().get(index)?.get(rest),
+        }
+    }
+
+    pub fn get_mut(&mut self, path: &[usize]) -> Option<&mut DerivationTree> {
+        match path.split_first() {
+            None => Some(self),
+            Some((&index, rest)) => match self {
+                DerivationTree::Terminal { .. } => None,
+                DerivationTree::NonTerminal { children, .. } => children.get_mut(index)?.get_mut(rest),
+            },
+        }
+    }
+
+    /**
+     * Replaces the node at the given path and returns the old node.
+     * Returns None and leaves the tree untouched if the path does not exist.
+     */
+    pub fn replace(&mut self, path: &[usize], subtree: DerivationTree) -> Option<DerivationTree> {
+        let node = self.get_mut(path)?;
+        Some(std::mem::replace(node, subtree))
+    }
+
+    /**
+     * Paths of all nodes in pre-order, starting with the root.
+     */
+    pub fn paths(&self) -> Vec<Vec<usize>> {
+        let mut out = Vec::new();
+        self.collect_paths(&mut Vec::new(), &mut out);
+        out
+    }
+
+    fn collect_paths(&self, prefix: &mut Vec<usize>, out: &mut Vec<Vec<usize>>) {
+        out.push(prefix.clone());
+        for (i, child) in self.children().iter().enumerate() {
+            prefix.push(i);
+            child.collect_paths(prefix, out);
+            prefix.pop();
+        }
+    }
+
+    /**
+     * Paths of all nonterminal nodes, optionally only those labeled with `symbol`.
+     */
+    pub fn nonterminal_paths(&self, symbol: Option<&str>) -> Vec<Vec<usize>> {
+        self.paths()
+            .into_iter()
+            .filter(|p| {
+                let node = self.get(p).unwrap();
+                matches!(node, DerivationTree::NonTerminal { .. })
+                    && symbol.is_none_or(|s| node.symbol() == s)
+            })
+            .collect()
+    }
+
+    /**
+     * Number of nodes in the tree, including the root.
+     */
+    #[allow(unused)]
+    pub fn size(&self) -> usize {
+        1 + self.children().iter().map(|c| c.size()).sum::<usize>()
+    }
+
+    /**
+     * Length of the longest path from the root to a leaf, a single node has depth 1.
+     */
+    #[allow(unused)]
+    pub fn depth(&self) -> usize {
+        1 + self.children().iter().map(|c| c.depth()).max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DerivationTree;
+
+    fn terminal(symbol: &str, value: &str) -> DerivationTree {
+        DerivationTree::Terminal { symbol: symbol.to_string(), value: value.as_bytes().to_vec() }
+    }
+
+    #[test]
+    fn test_to_bytes_concatenates_leaves() {
+        let tree = DerivationTree::NonTerminal {
+            symbol: "Percent".to_string(),
+            alternative: 0,
+            children: vec![
+                terminal("%", "%"),
+                terminal("MAGIC-HexDigit", "4"),
+                terminal("MAGIC-HexDigit", "F"),
+            ],
+        };
+        assert_eq!(tree.to_bytes(), b"%4F".to_vec());
+        assert_eq!(tree.size(), 4);
+        assert_eq!(tree.depth(), 2);
+        assert_eq!(tree.alternative(), Some(0));
+        assert_eq!(tree.children()[1].alternative(), None);
+    }
+
+    #[test]
+    fn test_paths_and_replace() {
+        let mut tree = DerivationTree::NonTerminal {
+            symbol: "Start".to_string(),
+            alternative: 0,
+            children: vec![
+                DerivationTree::NonTerminal {
+                    symbol: "Letter".to_string(),
+                    alternative: 1,
+                    children: vec![terminal("a", "a")],
+                },
+                terminal("b", "b"),
+            ],
+        };
+        assert_eq!(tree.paths(), vec![vec![], vec![0], vec![0, 0], vec![1]]);
+        assert_eq!(tree.nonterminal_paths(Some("Letter")), vec![vec![0]]);
+        assert_eq!(tree.get(&[0, 0]).unwrap().symbol(), "a");
+        assert!(tree.get(&[2]).is_none());
+
+        let old = tree.replace(&[0, 0], terminal("x", "xy")).unwrap();
+        assert_eq!(old.symbol(), "a");
+        assert_eq!(tree.to_bytes(), b"xyb".to_vec());
+        assert!(tree.replace(&[1, 0], terminal("z", "z")).is_none());
+    }
+}
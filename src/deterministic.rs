@@ -0,0 +1,450 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use crate::derivation::DerivationTree;
+use crate::grammar_coverage::GrammarCoverage;
+use crate::havoc::{ARITH_MAX, INTERESTING_16, INTERESTING_32, INTERESTING_8};
+use crate::runner::RunnerResult;
+use crate::seeder::{ByteMutation, Seedable};
+
+/**
+ * Deterministic mutation stages in the order they are run, as in AFL.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeterministicStage {
+    /* Flip 1, 2 or 4 consecutive bits at every bit offset */
+    BitFlip1,
+    BitFlip2,
+    BitFlip4,
+    /* Invert every byte */
+    ByteFlip,
+    /* Add and subtract 1 to ARITH_MAX at every byte, word and dword, words and dwords in both byte orders */
+    Arith8,
+    Arith16,
+    Arith32,
+    /* Overwrite every byte, word and dword with each interesting value */
+    Interesting8,
+    Interesting16,
+    Interesting32,
+    /* Overwrite the input with each dictionary token at every offset */
+    DictionaryOverwrite,
+    /* Insert each dictionary token at every offset */
+    DictionaryInsert,
+}
+
+pub const ALL_DETERMINISTIC_STAGES: [DeterministicStage; 12] = [
+    DeterministicStage::BitFlip1,
+    DeterministicStage::BitFlip2,
+    DeterministicStage::BitFlip4,
+    DeterministicStage::ByteFlip,
+    DeterministicStage::Arith8,
+    DeterministicStage::Arith16,
+    DeterministicStage::Arith32,
+    DeterministicStage::Interesting8,
+    DeterministicStage::Interesting16,
+    DeterministicStage::Interesting32,
+    DeterministicStage::DictionaryOverwrite,
+    DeterministicStage::DictionaryInsert,
+];
+
+impl DeterministicStage {
+    /**
+     * Name on the command line, e.g. "flip1".
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeterministicStage::BitFlip1 => "flip1",
+            DeterministicStage::BitFlip2 => "flip2",
+            DeterministicStage::BitFlip4 => "flip4",
+            DeterministicStage::ByteFlip => "flip8",
+            DeterministicStage::Arith8 => "arith8",
+            DeterministicStage::Arith16 => "arith16",
+            DeterministicStage::Arith32 => "arith32",
+            DeterministicStage::Interesting8 => "interest8",
+            DeterministicStage::Interesting16 => "interest16",
+            DeterministicStage::Interesting32 => "interest32",
+            DeterministicStage::DictionaryOverwrite => "dict-overwrite",
+            DeterministicStage::DictionaryInsert => "dict-insert",
+        }
+    }
+
+    /*
+     * Number of bytes a single mutation of the stage changes, 0 for the
+     * dictionary stages.
+     */
+    fn width(&self) -> usize {
+        match self {
+            DeterministicStage::BitFlip1 | DeterministicStage::BitFlip2 | DeterministicStage::BitFlip4 => 0,
+            DeterministicStage::ByteFlip | DeterministicStage::Arith8 | DeterministicStage::Interesting8 => 1,
+            DeterministicStage::Arith16 | DeterministicStage::Interesting16 => 2,
+            DeterministicStage::Arith32 | DeterministicStage::Interesting32 => 4,
+            DeterministicStage::DictionaryOverwrite | DeterministicStage::DictionaryInsert => 0,
+        }
+    }
+
+    /*
+     * Mutations per offset. Words and dwords are tried in both byte orders.
+     */
+    fn variants(&self) -> usize {
+        let orders = if self.width() > 1 { 2 } else { 1 };
+        match self {
+            DeterministicStage::Arith8 | DeterministicStage::Arith16 | DeterministicStage::Arith32 => 2 * ARITH_MAX as usize * orders,
+            DeterministicStage::Interesting8 => INTERESTING_8.len(),
+            DeterministicStage::Interesting16 => INTERESTING_16.len() * orders,
+            DeterministicStage::Interesting32 => INTERESTING_32.len() * orders,
+            _ => 1,
+        }
+    }
+
+    /**
+     * Number of mutations of the stage for an input of `length` bytes. Some of
+     * them may produce the same input as an earlier mutation.
+     */
+    pub fn size(&self, length: usize, dictionary: &[Vec<u8>]) -> usize {
+        match self {
+            DeterministicStage::BitFlip1 => length * 8,
+            DeterministicStage::BitFlip2 => (length * 8).saturating_sub(1),
+            DeterministicStage::BitFlip4 => (length * 8).saturating_sub(3),
+            DeterministicStage::DictionaryOverwrite => dictionary.iter()
+                .filter(|t| !t.is_empty())
+                .map(|t| (length + 1).saturating_sub(t.len()))
+                .sum(),
+            DeterministicStage::DictionaryInsert => dictionary.iter().filter(|t| !t.is_empty()).count() * (length + 1),
+            _ => (length + 1).saturating_sub(self.width()) * self.variants(),
+        }
+    }
+
+    /**
+     * The mutation number `index` of the stage, index < size().
+     */
+    pub fn mutation(&self, input: &[u8], dictionary: &[Vec<u8>], index: usize) -> ByteMutation {
+        match self {
+            DeterministicStage::BitFlip1 | DeterministicStage::BitFlip2 | DeterministicStage::BitFlip4 => {
+                let bits = match self {
+                    DeterministicStage::BitFlip1 => 1,
+                    DeterministicStage::BitFlip2 => 2,
+                    _ => 4,
+                };
+                // bits are counted from the most significant bit of the first byte
+                let position = index / 8;
+                let end = (index + bits - 1) / 8;
+                let mut bytes = input[position..=end].to_vec();
+                for bit in index..index + bits {
+                    bytes[bit / 8 - position] ^= 0x80 >> (bit % 8);
+                }
+                ByteMutation::Overwrite { position, bytes }
+            },
+            DeterministicStage::DictionaryOverwrite | DeterministicStage::DictionaryInsert => {
+                let insert = *self == DeterministicStage::DictionaryInsert;
+                let mut index = index;
+                for token in dictionary.iter().filter(|t| !t.is_empty()) {
+                    let offsets = if insert { input.len() + 1 } else { (input.len() + 1).saturating_sub(token.len()) };
+                    if index < offsets {
+                        return if insert {
+                            ByteMutation::InsertBlock { position: index, bytes: token.clone() }
+                        } else {
+                            ByteMutation::Overwrite { position: index, bytes: token.clone() }
+                        };
+                    }
+                    index -= offsets;
+                }
+                panic!("dictionary mutation index out of range");
+            },
+            _ => {
+                let width = self.width();
+                let position = index / self.variants();
+                let variant = index % self.variants();
+                let (big_endian, variant) = if width > 1 {
+                    (variant >= self.variants() / 2, variant % (self.variants() / 2))
+                } else {
+                    (false, variant)
+                };
+                let field = &input[position..position + width];
+                let mut buffer = [0u8; 8];
+                if big_endian {
+                    buffer[8 - width..].copy_from_slice(field);
+                } else {
+                    buffer[..width].copy_from_slice(field);
+                }
+                let value = if big_endian { u64::from_be_bytes(buffer) } else { u64::from_le_bytes(buffer) };
+                let value = match self {
+                    DeterministicStage::ByteFlip => !value,
+                    DeterministicStage::Arith8 | DeterministicStage::Arith16 | DeterministicStage::Arith32 => {
+                        let delta = (variant / 2 + 1) as u64;
+                        if variant % 2 == 0 { value.wrapping_add(delta) } else { value.wrapping_sub(delta) }
+                    },
+                    DeterministicStage::Interesting8 => INTERESTING_8[variant] as u64,
+                    DeterministicStage::Interesting16 => INTERESTING_16[variant] as u64,
+                    _ => INTERESTING_32[variant] as u64,
+                };
+                let bytes = if big_endian { value.to_be_bytes()[8 - width..].to_vec() } else { value.to_le_bytes()[..width].to_vec() };
+                ByteMutation::Overwrite { position, bytes }
+            },
+        }
+    }
+}
+
+impl FromStr for DeterministicStage {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<DeterministicStage, String> {
+        ALL_DETERMINISTIC_STAGES.into_iter()
+            .find(|s| s.name() == name)
+            .ok_or_else(|| format!("unknown deterministic stage '{}'", name))
+    }
+}
+
+fn input_hash(input: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    input.hash(&mut hasher);
+    hasher.finish()
+}
+
+/**
+ * Runs the deterministic stages on one entry after another and yields every
+ * distinct mutant of an entry exactly once, skipping mutants which equal the
+ * entry or an earlier mutant of it. Entries are taken from the inner seeder,
+ * with a corpus seeder new corpus entries come first. An entry whose pass was
+ * completed before is not mutated again but passed on as it is. The position
+ * in the stages is kept between calls, so the pass over an entry continues
+ * where it stopped and can be interleaved with other seeders.
+ */
+pub struct DeterministicSeeder<S: Seedable> {
+    pub seeder: S,
+    pub stages: Vec<DeterministicStage>,
+    /* Tokens for the dictionary stages */
+    pub dictionary: Vec<Vec<u8>>,
+    /* Number of entries whose pass is complete */
+    pub completed: usize,
+    /* Hashes of the entries whose pass is complete */
+    passed: HashSet<u64>,
+    entry: Option<Vec<u8>>,
+    stage: usize,
+    index: usize,
+    yielded: usize,
+    seen: HashSet<u64>,
+    last_stage: Option<DeterministicStage>,
+    mutation: Option<ByteMutation>,
+}
+
+#[allow(unused)]
+impl<S: Seedable> DeterministicSeeder<S> {
+    pub fn init(seeder: S) -> DeterministicSeeder<S> {
+        DeterministicSeeder::init_with_stages(seeder, ALL_DETERMINISTIC_STAGES.to_vec(), Vec::new())
+    }
+
+    pub fn init_with_stages(seeder: S, stages: Vec<DeterministicStage>, dictionary: Vec<Vec<u8>>) -> DeterministicSeeder<S> {
+        DeterministicSeeder {
+            seeder,
+            stages,
+            dictionary,
+            completed: 0,
+            passed: HashSet::new(),
+            entry: None,
+            stage: 0,
+            index: 0,
+            yielded: 0,
+            seen: HashSet::new(),
+            last_stage: None,
+            mutation: None,
+        }
+    }
+
+    /**
+     * Entry whose mutants are currently yielded.
+     */
+    pub fn current_entry(&self) -> Option<&[u8]> {
+        self.entry.as_deref()
+    }
+
+    /**
+     * Stage of the last mutant, None if the last seed was an unmutated entry.
+     */
+    pub fn last_stage(&self) -> Option<DeterministicStage> {
+        self.last_stage
+    }
+
+    /**
+     * Mutations left in the pass over the current entry, duplicates included.
+     */
+    pub fn remaining(&self) -> usize {
+        let Some(entry) = &self.entry else {
+            return 0;
+        };
+        self.stages[self.stage.min(self.stages.len())..].iter()
+            .map(|s| s.size(entry.len(), &self.dictionary))
+            .sum::<usize>()
+            .saturating_sub(self.index)
+    }
+
+    fn start_entry(&mut self) {
+        let entry = self.seeder.next_seed();
+        let hash = input_hash(&entry);
+        self.seen.clear();
+        self.seen.insert(hash);
+        self.entry = Some(entry);
+        // an entry passed before has no stages left
+        self.stage = if self.passed.contains(&hash) { self.stages.len() } else { 0 };
+        self.index = 0;
+        self.yielded = 0;
+    }
+}
+
+impl<S: Seedable> Seedable for DeterministicSeeder<S> {
+    fn next_seed(&mut self) -> Vec<u8> {
+        loop {
+            if self.entry.is_none() {
+                self.start_entry();
+            }
+            let entry = self.entry.as_ref().unwrap();
+            while let Some(&stage) = self.stages.get(self.stage) {
+                if self.index >= stage.size(entry.len(), &self.dictionary) {
+                    self.stage += 1;
+                    self.index = 0;
+                    continue;
+                }
+                let mutation = stage.mutation(entry, &self.dictionary, self.index);
+                self.index += 1;
+                let mut mutant = entry.clone();
+                mutation.apply(&mut mutant);
+                if self.seen.insert(input_hash(&mutant)) {
+                    self.yielded += 1;
+                    self.last_stage = Some(stage);
+                    self.mutation = Some(mutation);
+                    return mutant;
+                }
+            }
+            let entry = self.entry.take().unwrap();
+            if self.passed.insert(input_hash(&entry)) {
+                self.completed += 1;
+            }
+            // an entry without any mutant, e.g. an empty one or one passed before, is passed on as it is
+            if self.yielded == 0 {
+                self.last_stage = None;
+                self.mutation = None;
+                return entry;
+            }
+        }
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        // only an entry passed on as it is keeps its tree
+        match self.mutation {
+            Some(_) => None,
+            None => self.seeder.last_derivation_tree(),
+        }
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.seeder.grammar_coverage()
+    }
+
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        // the seeder below is not advanced during the pass, so its mutations are those of the entry
+        let mut mutations = self.seeder.last_mutations();
+        mutations.extend(self.mutation.iter().cloned());
+        mutations
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        self.seeder.report_result(result);
+    }
+
+    fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+        self.seeder.report_foreign(input, result);
+    }
+
+    fn report(&self, out: &mut Vec<String>) {
+        self.seeder.report(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::seeder::MutationSeedModifier;
+
+    /* Yields the given inputs in turn */
+    struct ListSeeder(Vec<Vec<u8>>);
+
+    impl Seedable for ListSeeder {
+        fn next_seed(&mut self) -> Vec<u8> {
+            self.0.remove(0)
+        }
+    }
+
+    #[test]
+    fn test_stage_sizes_and_mutations() {
+        let input = b"\x00\x01\x02\x03\x04";
+        let dictionary = vec![b"ab".to_vec()];
+        for stage in ALL_DETERMINISTIC_STAGES {
+            let size = stage.size(input.len(), &dictionary);
+            assert!(size > 0, "{}", stage.name());
+            for index in 0..size {
+                let mut mutant = input.to_vec();
+                stage.mutation(input, &dictionary, index).apply(&mut mutant);
+                let grown = stage == DeterministicStage::DictionaryInsert;
+                assert_eq!(mutant.len(), input.len() + if grown { 2 } else { 0 });
+            }
+            assert_eq!(stage.name().parse::<DeterministicStage>(), Ok(stage));
+        }
+        assert_eq!(DeterministicStage::BitFlip4.mutation(input, &[], 6), ByteMutation::Overwrite { position: 0, bytes: vec![0x03, 0xC1] });
+        assert_eq!(DeterministicStage::Arith16.size(input.len(), &[]), 4 * 140);
+        // 0x0001 big endian minus 2 at offset 0
+        assert_eq!(DeterministicStage::Arith16.mutation(input, &[], 70 + 3), ByteMutation::Overwrite { position: 0, bytes: vec![0xFF, 0xFF] });
+        assert_eq!(DeterministicStage::Interesting16.size(2, &[]), 2 * 19);
+        // 0xFFFF, -1 as a word, which the 16-bit values alone do not contain
+        assert_eq!(DeterministicStage::Interesting16.mutation(input, &[], 1), ByteMutation::Overwrite { position: 0, bytes: vec![0xFF, 0xFF] });
+        assert_eq!(DeterministicStage::Interesting32.size(3, &[]), 0);
+        assert_eq!(DeterministicStage::DictionaryOverwrite.size(1, &dictionary), 0);
+    }
+
+    #[test]
+    fn test_each_mutant_once_and_resumable() {
+        let entries = vec![b"a=1".to_vec(), b"".to_vec(), b"a=1".to_vec(), b"b".to_vec()];
+        let dictionary = vec![b"&".to_vec(), b"=".to_vec()];
+        let mut seeder = DeterministicSeeder::init_with_stages(ListSeeder(entries), ALL_DETERMINISTIC_STAGES.to_vec(), dictionary);
+        let mut first = HashSet::new();
+        while seeder.current_entry().is_none() || seeder.remaining() > 0 {
+            let mutant = seeder.next_seed();
+            assert_eq!(ByteMutation::replay(b"a=1", &seeder.last_mutations()), mutant);
+            assert!(first.insert(mutant));
+        }
+        assert_eq!(seeder.completed, 0);
+        assert!(!first.contains(b"a=1".as_slice()));
+        assert!(first.contains(b"a&1".as_slice()) && first.contains(b"a==1".as_slice()));
+
+        // the empty entry only has dictionary insertions
+        assert_eq!(seeder.next_seed(), b"&".to_vec());
+        assert_eq!(seeder.next_seed(), b"=".to_vec());
+        // "a=1" was passed already and is not mutated again
+        assert_eq!(seeder.next_seed(), b"a=1".to_vec());
+        assert_eq!(seeder.last_stage(), None);
+        assert_eq!(seeder.completed, 2);
+        assert_eq!(seeder.next_seed(), vec![b'b' ^ 0x80]);
+        assert_eq!(seeder.last_stage(), Some(DeterministicStage::BitFlip1));
+        assert_eq!(seeder.current_entry(), Some(b"b".as_slice()));
+        assert!(seeder.remaining() > 0);
+    }
+
+    #[test]
+    fn test_mutations_include_the_seeder_below() {
+        let entries = vec![b"a=1".to_vec(), b"b".to_vec()];
+        let mutated = MutationSeedModifier::init(ListSeeder(entries), StdRng::seed_from_u64(3));
+        let mut seeder = DeterministicSeeder::init_with_stages(mutated, vec![DeterministicStage::ByteFlip], Vec::new());
+        let first = seeder.next_seed();
+        let base = seeder.seeder.last_mutations();
+        assert!(!base.is_empty());
+        assert_eq!(ByteMutation::replay(b"a=1", &seeder.last_mutations()), first);
+        while seeder.remaining() > 0 {
+            let mutant = seeder.next_seed();
+            assert_eq!(ByteMutation::replay(b"a=1", &seeder.last_mutations()), mutant);
+            assert_eq!(seeder.last_mutations()[..base.len()], base[..]);
+        }
+    }
+}
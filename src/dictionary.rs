@@ -0,0 +1,501 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rand::Rng;
+
+use crate::derivation::DerivationTree;
+use crate::grammar::{terminal_name, Grammar, GrammarRule};
+use crate::grammar_coverage::GrammarCoverage;
+use crate::runner::{RunnerResult, State};
+use crate::seeder::{ByteMutation, Seedable};
+use crate::terminals::TerminalRegistry;
+
+/*
+ * Longest token, as MAX_DICT_FILE in AFL.
+ */
+const MAX_TOKEN_LENGTH: usize = 128;
+
+/*
+ * Shortest literal taken from the grammar, single characters are mutated
+ * often enough by the byte mutations.
+ */
+const MIN_GRAMMAR_TOKEN_LENGTH: usize = 2;
+
+/*
+ * Shortest and most strings taken from a binary. Shorter strings are mostly
+ * random byte sequences in the code.
+ */
+const MIN_BINARY_TOKEN_LENGTH: usize = 4;
+const MAX_BINARY_TOKENS: usize = 512;
+
+/*
+ * Tokens listed in the report of a dictionary.
+ */
+const REPORTED_TOKENS: usize = 5;
+
+/**
+ * Origin of a dictionary token.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSource {
+    /* AFL-format .dict file */
+    File,
+    /* Literal terminal of the grammar */
+    Grammar,
+    /* Printable string in the read-only data of the target */
+    Binary,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DictionaryToken {
+    pub token: Vec<u8>,
+    pub source: TokenSource,
+    /* Runs of inputs the token was put into */
+    pub uses: u64,
+    /* Of these, runs which crashed the target */
+    pub finds: u64,
+}
+
+/**
+ * Error in a .dict file, with the line number starting at 1.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DictionaryError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for DictionaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/**
+ * Parses the AFL dictionary format: one token per line as `"value"` or
+ * `name="value"`, where the name may end in `@level`. Values may contain
+ * `\\`, `\"` and `\xNN` escapes. Empty lines and lines starting with # are
+ * ignored, as are tokens whose level is above `max_level`.
+ */
+pub fn parse_dictionary(text: &str, max_level: u32) -> Result<Vec<Vec<u8>>, DictionaryError> {
+    let mut tokens = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: &str| DictionaryError { line: index + 1, message: message.to_string() };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let quote = line.find('"').ok_or_else(|| error("expected a quoted value"))?;
+        let name = line[..quote].trim_end().strip_suffix('=').map(str::trim_end);
+        if name.is_none() && quote > 0 {
+            return Err(error("expected name=\"value\""));
+        }
+        let level = match name.and_then(|n| n.rsplit_once('@')) {
+            Some((_, level)) => level.parse::<u32>().map_err(|_| error("invalid level"))?,
+            None => 0,
+        };
+        let value = line[quote + 1..].strip_suffix('"').ok_or_else(|| error("unterminated value"))?;
+        let mut token = Vec::new();
+        let mut bytes = value.bytes();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'\\' => match bytes.next() {
+                    Some(b'\\') => token.push(b'\\'),
+                    Some(b'"') => token.push(b'"'),
+                    Some(b'x') => {
+                        let digits = [bytes.next(), bytes.next()];
+                        let hex: Option<String> = digits.iter().map(|d| d.map(|d| d as char)).collect();
+                        let byte = hex.and_then(|h| u8::from_str_radix(&h, 16).ok()).ok_or_else(|| error("invalid \\x escape"))?;
+                        token.push(byte);
+                    },
+                    _ => return Err(error("invalid escape")),
+                },
+                b'"' => return Err(error("unescaped quote in value")),
+                b if !(b' '..=b'~').contains(&b) => return Err(error("non-printable character in value")),
+                b => token.push(b),
+            }
+        }
+        if level <= max_level {
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+/*
+ * Section `name` of a little endian ELF file as (offset, size).
+ */
+fn elf_section(data: &[u8], name: &[u8]) -> Option<(usize, usize)> {
+    if data.get(..4)? != b"\x7fELF" || *data.get(5)? != 1 {
+        return None;
+    }
+    let read = |offset: usize, width: usize| -> Option<usize> {
+        let bytes = data.get(offset..offset + width)?;
+        Some(bytes.iter().rev().fold(0usize, |value, &b| value << 8 | b as usize))
+    };
+    // offsets in the file header and section headers of 32 and 64 bit files
+    let (shoff, shentsize, word, name_field, offset_field, size_field) = match data[4] {
+        1 => (read(0x20, 4)?, read(0x2E, 2)?, 4, 0, 0x10, 0x14),
+        2 => (read(0x28, 8)?, read(0x3A, 2)?, 8, 0, 0x18, 0x20),
+        _ => return None,
+    };
+    let shnum = read(if word == 4 { 0x30 } else { 0x3C }, 2)?;
+    let shstrndx = read(if word == 4 { 0x32 } else { 0x3E }, 2)?;
+    let header = |index: usize| shoff + index * shentsize;
+    let strings = read(header(shstrndx) + offset_field, word)?;
+    (0..shnum).find_map(|index| {
+        let start = strings + read(header(index) + name_field, 4)?;
+        let section_name = data.get(start..start + name.len() + 1)?;
+        if &section_name[..name.len()] != name || section_name[name.len()] != 0 {
+            return None;
+        }
+        Some((read(header(index) + offset_field, word)?, read(header(index) + size_field, word)?))
+    })
+}
+
+/**
+ * Runs of printable characters in the .rodata section of the binary at `path`,
+ * or in the whole file if it is not a little endian ELF file, like `strings`.
+ */
+pub fn binary_tokens(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let data = fs::read(path)?;
+    let section = match elf_section(&data, b".rodata") {
+        Some((offset, size)) => data.get(offset..offset.saturating_add(size)).unwrap_or(&[]),
+        None => &data,
+    };
+    let mut seen = HashSet::new();
+    let tokens = section.split(|b| !(b' '..=b'~').contains(b))
+        .filter(|run| (MIN_BINARY_TOKEN_LENGTH..=MAX_TOKEN_LENGTH).contains(&run.len()))
+        .filter(|run| seen.insert(*run))
+        .take(MAX_BINARY_TOKENS)
+        .map(|run| run.to_vec())
+        .collect();
+    Ok(tokens)
+}
+
+/**
+ * Literal terminals of a grammar, without generated and context terminals.
+ */
+pub fn grammar_tokens(grammar: &Grammar, terminals: &TerminalRegistry) -> Vec<Vec<u8>> {
+    let mut tokens: Vec<Vec<u8>> = grammar.values()
+        .flatten()
+        .flatten()
+        .filter_map(|rule| match rule {
+            GrammarRule::Terminal(t) if t.len() >= MIN_GRAMMAR_TOKEN_LENGTH && !terminals.is_generated(t) => Some(t.clone()),
+            _ => None,
+        })
+        .collect();
+    // the grammar is a HashMap, keep the order stable
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+/**
+ * Tokens inserted into or written over inputs, with how often they were used
+ * and how often an input containing them showed a new behavior or crash.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dictionary {
+    pub tokens: Vec<DictionaryToken>,
+}
+
+#[allow(unused)]
+impl Dictionary {
+    pub fn new() -> Dictionary {
+        Dictionary::default()
+    }
+
+    /**
+     * Adds a token unless it is empty, too long or already present.
+     */
+    pub fn add(&mut self, token: Vec<u8>, source: TokenSource) -> bool {
+        if token.is_empty() || token.len() > MAX_TOKEN_LENGTH || self.tokens.iter().any(|t| t.token == token) {
+            return false;
+        }
+        self.tokens.push(DictionaryToken { token, source, uses: 0, finds: 0 });
+        true
+    }
+
+    /**
+     * Adds all tokens and returns the number of new ones.
+     */
+    pub fn extend(&mut self, tokens: Vec<Vec<u8>>, source: TokenSource) -> usize {
+        tokens.into_iter().filter(|t| self.add(t.clone(), source)).count()
+    }
+
+    /**
+     * Loads the tokens of a .dict file up to level `max_level`.
+     */
+    pub fn load_file(&mut self, path: &Path, max_level: u32) -> Result<usize, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let tokens = parse_dictionary(&text, max_level).map_err(|e| e.to_string())?;
+        Ok(self.extend(tokens, TokenSource::File))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /**
+     * The token bytes, e.g. for the deterministic dictionary stages.
+     */
+    pub fn values(&self) -> Vec<Vec<u8>> {
+        self.tokens.iter().map(|t| t.token.clone()).collect()
+    }
+
+    /**
+     * Picks a token index. Tokens which crashed the target are preferred:
+     * the weight of a token is 1 + 8 * finds / (uses + 1).
+     */
+    pub fn choose(&self, rng: &mut impl Rng) -> Option<usize> {
+        let weight = |t: &DictionaryToken| 1.0 + 8.0 * t.finds as f64 / (t.uses + 1) as f64;
+        let total: f64 = self.tokens.iter().map(weight).sum();
+        if self.tokens.is_empty() {
+            return None;
+        }
+        let mut target = rng.gen_range(0.0..total);
+        for (index, token) in self.tokens.iter().enumerate() {
+            target -= weight(token);
+            if target < 0.0 {
+                return Some(index);
+            }
+        }
+        Some(self.tokens.len() - 1)
+    }
+
+    /**
+     * Records a run of an input containing the token at `index`.
+     */
+    pub fn record(&mut self, index: usize, found: bool) {
+        let token = &mut self.tokens[index];
+        token.uses += 1;
+        token.finds += found as u64;
+    }
+
+    /**
+     * One-line summary with the tokens which led to most finds, e.g.
+     * `12 tokens, 2 useful: "%2" 3/40, "Content-Length:" 1/35` (finds/uses).
+     */
+    pub fn report(&self) -> String {
+        let mut useful: Vec<&DictionaryToken> = self.tokens.iter().filter(|t| t.finds > 0).collect();
+        useful.sort_by_key(|t| (std::cmp::Reverse(t.finds), t.uses));
+        let top: Vec<String> = useful.iter()
+            .take(REPORTED_TOKENS)
+            .map(|t| format!("\"{}\" {}/{}", terminal_name(&t.token), t.finds, t.uses))
+            .collect();
+        let mut report = format!("{} tokens, {} useful", self.tokens.len(), useful.len());
+        if !top.is_empty() {
+            report.push_str(": ");
+            report.push_str(&top.join(", "));
+        }
+        report
+    }
+}
+
+/**
+ * Mutation layer inserting a dictionary token at a random position or writing
+ * it over the input, in three of four seeds. Like MutationSeedModifier it can
+ * be stacked on any seeder. The results reported for its seeds update the
+ * usefulness of the tokens: a token finds something if the run shows a
+ * behavior or crash bucket not seen before, as in OperatorScheduler::reward().
+ */
+pub struct DictionarySeedModifier<S: Seedable, R: Rng> {
+    pub seeder: S,
+    pub dictionary: Dictionary,
+    rng: R,
+    /* Token put into the last seed */
+    last_token: Option<usize>,
+    mutations: Vec<ByteMutation>,
+    /* Behaviors and crash behaviors of the runs reported so far */
+    behaviors: HashSet<u64>,
+    crash_behaviors: HashSet<u64>,
+}
+
+impl<S: Seedable, R: Rng> DictionarySeedModifier<S, R> {
+    pub fn init(seeder: S, dictionary: Dictionary, rng: R) -> DictionarySeedModifier<S, R> {
+        DictionarySeedModifier {
+            seeder,
+            dictionary,
+            rng,
+            last_token: None,
+            mutations: Vec::new(),
+            behaviors: HashSet::new(),
+            crash_behaviors: HashSet::new(),
+        }
+    }
+
+    fn choose_mutation(&mut self, input: &[u8]) -> Option<(usize, ByteMutation)> {
+        if self.rng.gen_range(0..4) == 0 {
+            return None;
+        }
+        let index = self.dictionary.choose(&mut self.rng)?;
+        let bytes = self.dictionary.tokens[index].token.clone();
+        let mutation = if input.len() >= bytes.len() && self.rng.gen_bool(0.5) {
+            ByteMutation::Overwrite { position: self.rng.gen_range(0..=input.len() - bytes.len()), bytes }
+        } else {
+            ByteMutation::InsertBlock { position: self.rng.gen_range(0..=input.len()), bytes }
+        };
+        Some((index, mutation))
+    }
+}
+
+impl<S: Seedable, R: Rng> Seedable for DictionarySeedModifier<S, R> {
+    fn next_seed(&mut self) -> Vec<u8> {
+        let mut seed = self.seeder.next_seed();
+        self.mutations.clear();
+        self.last_token = None;
+        if let Some((index, mutation)) = self.choose_mutation(&seed) {
+            mutation.apply(&mut seed);
+            self.mutations.push(mutation);
+            self.last_token = Some(index);
+        }
+        seed
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        if self.mutations.is_empty() {
+            self.seeder.last_derivation_tree()
+        } else {
+            None
+        }
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.seeder.grammar_coverage()
+    }
+
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        let mut mutations = self.seeder.last_mutations();
+        mutations.extend_from_slice(&self.mutations);
+        mutations
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        let new_behavior = self.behaviors.insert(result.behavior);
+        let new_crash = matches!(result.state, State::Fail) && self.crash_behaviors.insert(result.behavior);
+        if let Some(index) = self.last_token {
+            self.dictionary.record(index, new_behavior || new_crash);
+        }
+        self.seeder.report_result(result);
+    }
+
+    fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+        self.seeder.report_foreign(input, result);
+    }
+
+    fn report(&self, out: &mut Vec<String>) {
+        self.seeder.report(out);
+        out.push(format!("Dictionary: {}", self.dictionary.report()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+
+    use super::*;
+    use crate::grammar::create_cgi_grammar;
+    use crate::havoc::HavocSeedModifier;
+    use crate::seeder::RandomSeeder;
+
+    #[test]
+    fn test_parse_dictionary() {
+        let text = "# comment\n\n\"GET\"\nkw1=\"Content-Length:\"\nheader_png@2 = \"\\x89PNG\\\\\\\"\"\n";
+        assert_eq!(parse_dictionary(text, 0).unwrap(), vec![b"GET".to_vec(), b"Content-Length:".to_vec()]);
+        assert_eq!(parse_dictionary(text, 2).unwrap()[2], b"\x89PNG\\\"".to_vec());
+        assert_eq!(parse_dictionary("a=\"x\ny", 0).unwrap_err().line, 1);
+        assert_eq!(parse_dictionary("\"ok\"\nx=\"\\x4\"", 0).unwrap_err().line, 2);
+        assert!(parse_dictionary("a b=\"x\"", 0).is_ok());
+        assert!(parse_dictionary("abc", 0).is_err());
+    }
+
+    /* ELF64 file with the sections .rodata and .shstrtab */
+    fn elf64(rodata: &[u8]) -> Vec<u8> {
+        let names = b"\0.rodata\0.shstrtab\0";
+        let mut data = vec![0u8; 64];
+        data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        data.extend_from_slice(b"leading text outside of .rodata\0");
+        let rodata_offset = data.len();
+        data.extend_from_slice(rodata);
+        let names_offset = data.len();
+        data.extend_from_slice(names);
+        let shoff = data.len();
+        data[0x28..0x30].copy_from_slice(&(shoff as u64).to_le_bytes());
+        data[0x3A..0x3C].copy_from_slice(&64u16.to_le_bytes());
+        data[0x3C..0x3E].copy_from_slice(&3u16.to_le_bytes());
+        data[0x3E..0x40].copy_from_slice(&2u16.to_le_bytes());
+        for (name, offset, size) in [(0u32, 0, 0), (1, rodata_offset, rodata.len()), (9, names_offset, names.len())] {
+            let mut header = vec![0u8; 64];
+            header[..4].copy_from_slice(&name.to_le_bytes());
+            header[0x18..0x20].copy_from_slice(&(offset as u64).to_le_bytes());
+            header[0x20..0x28].copy_from_slice(&(size as u64).to_le_bytes());
+            data.extend_from_slice(&header);
+        }
+        data
+    }
+
+    #[test]
+    fn test_grammar_and_binary_tokens() {
+        let tokens = grammar_tokens(&create_cgi_grammar(), &TerminalRegistry::init());
+        assert!(tokens.iter().all(|t| t.len() >= MIN_GRAMMAR_TOKEN_LENGTH));
+        assert!(tokens.windows(2).all(|w| w[0] < w[1]));
+
+        let rodata = b"\0Content-Length:\0ab\0%s=%d\x01\x02HTTP/1.1\0";
+        let path = std::env::temp_dir().join(format!("ruzzer-elf-{}", std::process::id()));
+        fs::write(&path, elf64(rodata)).unwrap();
+        let tokens = binary_tokens(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(tokens, vec![b"Content-Length:".to_vec(), b"%s=%d".to_vec(), b"HTTP/1.1".to_vec()]);
+        assert_eq!(elf_section(b"#!/bin/sh\n", b".rodata"), None);
+    }
+
+    #[test]
+    fn test_dictionary_layer_tracks_usefulness() {
+        let mut dictionary = Dictionary::new();
+        assert_eq!(dictionary.extend(vec![b"%2".to_vec(), b"AAAA".to_vec(), b"%2".to_vec(), Vec::new()], TokenSource::File), 2);
+        let mut reference = RandomSeeder::<StdRng>::init(5);
+        let mut seeder = DictionarySeedModifier::init(RandomSeeder::<StdRng>::init(5), dictionary, StdRng::seed_from_u64(6));
+        // passing runs all show behavior 0, which is known before any token is used
+        seeder.report_result(&RunnerResult::for_test(State::Pass, b"", 0));
+        let mut crash_behavior = 0;
+        for behavior in 1..200 {
+            let seed = seeder.next_seed();
+            assert_eq!(ByteMutation::replay(&reference.next_seed(), &seeder.last_mutations()), seed);
+            let crashed = seeder.last_token == Some(0);
+            if crashed {
+                crash_behavior = behavior;
+            }
+            seeder.report_result(&RunnerResult::for_test(if crashed { State::Fail } else { State::Pass }, &seed, if crashed { behavior } else { 0 }));
+        }
+        let (percent, a) = (&seeder.dictionary.tokens[0], &seeder.dictionary.tokens[1]);
+        assert_eq!(percent.finds, percent.uses);
+        assert_eq!(a.finds, 0);
+        // crashing tokens are chosen more often
+        assert!(percent.uses > 2 * a.uses);
+        let finds = percent.finds;
+        assert!(seeder.dictionary.report().starts_with("2 tokens, 1 useful: \"%2\" "));
+
+        // a crash in a known bucket is no find
+        seeder.last_token = Some(0);
+        seeder.report_result(&RunnerResult::for_test(State::Fail, b"%2", crash_behavior));
+        assert_eq!(seeder.dictionary.tokens[0].finds, finds);
+
+        // layers report after the seeder below
+        let mut havoc_seeder = HavocSeedModifier::init(seeder, StdRng::seed_from_u64(7));
+        havoc_seeder.enable_scheduler();
+        let mut lines = Vec::new();
+        havoc_seeder.report(&mut lines);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("Dictionary: 2 tokens") && lines[1].starts_with("Operators: "));
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::derivation::DerivationTree;
+use crate::grammar::{Grammar, GrammarRule};
+use crate::terminals::TerminalRegistry;
+
+/*
+ * Default upper bound of derivation trees extracted for one input.
+ * Ambiguous grammars can have exponentially many parses.
+ */
+const DEFAULT_MAX_TREES: usize = 16;
+
+/**
+ * Successful parse of an input.
+ * `trees` holds up to `max_trees` derivations, `ambiguous` is set if there
+ * is more than one derivation for the input.
+ */
+#[derive(Debug)]
+pub struct ParseResult {
+    pub trees: Vec<DerivationTree>,
+    pub ambiguous: bool,
+}
+
+/**
+ * Failed parse of an input.
+ * `position` is the offset of the first byte that could not be consumed,
+ * it equals the input length if the input ended too early.
+ * `expected` lists the terminals that would have been accepted there.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub expected: Vec<String>,
+}
+
+/**
+ * Counts of a batch of parsed samples, for reporting.
+ */
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub parsed: usize,
+    pub ambiguous: usize,
+    /* Each sample which did not parse, with the reason */
+    pub failures: Vec<String>,
+}
+
+impl ImportSummary {
+    /**
+     * Counts the parse of the sample `name` and returns its first derivation.
+     */
+    pub fn record(&mut self, name: &str, result: Result<ParseResult, ParseError>) -> Option<DerivationTree> {
+        match result {
+            Ok(mut result) => {
+                self.parsed += 1;
+                self.ambiguous += result.ambiguous as usize;
+                Some(result.trees.swap_remove(0))
+            },
+            Err(e) => {
+                self.failures.push(format!("{}: parse error at byte {}, expected one of {:?}", name, e.position, e.expected));
+                None
+            },
+        }
+    }
+
+    /**
+     * Summary like "3 of 4 samples parsed, 1 ambiguous".
+     */
+    pub fn report(&self) -> String {
+        format!("{} of {} samples parsed, {} ambiguous", self.parsed, self.parsed + self.failures.len(), self.ambiguous)
+    }
+}
+
+/*
+ * An Earley item: alternative `alternative` of `symbol` with the dot in front
+ * of rule element `dot`, started at input position `origin`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Item<'a> {
+    symbol: &'a str,
+    alternative: usize,
+    dot: usize,
+    origin: usize,
+}
+
+/**
+ * Earley parser turning existing inputs into derivation trees of a grammar.
+ * Works with any context-free grammar, including left recursion, empty
+ * alternatives and ambiguity.
+ */
+pub struct EarleyParser<'a> {
+    grammar: &'a Grammar,
+    terminals: &'a TerminalRegistry,
+    nullable: HashSet<&'a str>,
+    pub max_trees: usize,
+}
+
+/*
+ * Completed items, keyed by (symbol, start), valued with (alternative, end).
+ */
+type Completed<'a> = HashMap<(&'a str, usize), Vec<(usize, usize)>>;
+
+impl<'a> EarleyParser<'a> {
+    pub fn init(grammar: &'a Grammar, terminals: &'a TerminalRegistry) -> EarleyParser<'a> {
+        EarleyParser {
+            grammar,
+            terminals,
+            nullable: nullable_symbols(grammar),
+            max_trees: DEFAULT_MAX_TREES,
+        }
+    }
+
+    /**
+     * Parses `input` as a derivation of `symbol`.
+     */
+    pub fn parse(&self, symbol: &str, input: &[u8]) -> Result<ParseResult, ParseError> {
+        let Some((symbol, rules)) = self.grammar.get_key_value(symbol) else {
+            return Err(ParseError { position: 0, expected: vec![symbol.to_string()] });
+        };
+        let n = input.len();
+        let mut charts: Vec<Vec<Item>> = vec![Vec::new(); n + 1];
+        let mut seen: Vec<HashSet<Item>> = vec![HashSet::new(); n + 1];
+        let mut completed: Completed = HashMap::new();
+
+        for alternative in 0..rules.len() {
+            let item = Item { symbol, alternative, dot: 0, origin: 0 };
+            add_item(&mut charts, &mut seen, 0, item);
+        }
+
+        for i in 0..=n {
+            let mut j = 0;
+            while j < charts[i].len() {
+                let item = charts[i][j];
+                j += 1;
+                let rule = &self.grammar[item.symbol][item.alternative];
+                let advanced = Item { dot: item.dot + 1, ..item };
+
+                let Some(next) = rule.get(item.dot) else {
+                    // complete
+                    let ends = completed.entry((item.symbol, item.origin)).or_default();
+                    if !ends.contains(&(item.alternative, i)) {
+                        ends.push((item.alternative, i));
+                    }
+                    let waiting: Vec<Item> = charts[item.origin].iter()
+                        .filter(|w| matches!(self.grammar[w.symbol][w.alternative].get(w.dot),
+                            Some(GrammarRule::NonTerminal(nt)) if nt == item.symbol))
+                        .copied()
+                        .collect();
+                    for w in waiting {
+                        add_item(&mut charts, &mut seen, i, Item { dot: w.dot + 1, ..w });
+                    }
+                    continue;
+                };
+
+                match next {
+                    GrammarRule::NonTerminal(nt) => match self.grammar.get_key_value(nt.as_str()) {
+                        Some((nt, nt_rules)) => {
+                            // predict
+                            for alternative in 0..nt_rules.len() {
+                                add_item(&mut charts, &mut seen, i, Item { symbol: nt, alternative, dot: 0, origin: i });
+                            }
+                            if self.nullable.contains(nt.as_str()) {
+                                add_item(&mut charts, &mut seen, i, advanced);
+                            }
+                        },
+                        // symbols without rules expand to nothing
+                        None => add_item(&mut charts, &mut seen, i, advanced),
+                    },
+                    terminal => {
+                        // scan
+                        for length in self.match_terminal(terminal, &input[i..]) {
+                            add_item(&mut charts, &mut seen, i + length, advanced);
+                        }
+                    },
+                }
+            }
+        }
+
+        let accepted = completed.get(&(symbol.as_str(), 0))
+            .is_some_and(|ends| ends.iter().any(|&(_, end)| end == n));
+        if !accepted {
+            return Err(self.parse_error(&charts, input));
+        }
+
+        let mut extractor = Extractor { parser: self, input, seen: &seen, completed: &completed, stack: Vec::new() };
+        // Ask for one more tree than needed, to detect ambiguity
+        let mut trees = extractor.symbol_trees(symbol, 0, n, self.max_trees + 1);
+        let ambiguous = trees.len() > 1;
+        trees.truncate(self.max_trees);
+        Ok(ParseResult { trees, ambiguous })
+    }
+
+    /*
+     * Lengths of the prefixes of `input` matched by a terminal or regex rule element.
+     */
+    fn match_terminal(&self, rule: &GrammarRule, input: &[u8]) -> Vec<usize> {
+        match rule {
+            GrammarRule::Terminal(t) => self.terminals.match_prefix(t, input),
+            GrammarRule::Regex(regex) => regex.match_prefix(input),
+            GrammarRule::NonTerminal(_) => Vec::new(),
+        }
+    }
+
+    fn parse_error(&self, charts: &[Vec<Item>], input: &[u8]) -> ParseError {
+        let position = charts.iter().rposition(|c| !c.is_empty()).unwrap_or(0);
+        let mut expected: Vec<String> = charts[position].iter()
+            .filter_map(|item| match self.grammar[item.symbol][item.alternative].get(item.dot) {
+                Some(GrammarRule::NonTerminal(_)) | None => None,
+                Some(terminal) => Some(terminal.name()),
+            })
+            .collect();
+        expected.sort();
+        expected.dedup();
+        // If everything was consumed, the input simply ended too early
+        ParseError { position: position.min(input.len()), expected }
+    }
+}
+
+fn add_item<'a>(charts: &mut [Vec<Item<'a>>], seen: &mut [HashSet<Item<'a>>], position: usize, item: Item<'a>) {
+    if seen[position].insert(item) {
+        charts[position].push(item);
+    }
+}
+
+/**
+ * Nonterminals which can derive the empty input.
+ */
+fn nullable_symbols(grammar: &Grammar) -> HashSet<&str> {
+    let mut nullable = HashSet::new();
+    loop {
+        let mut changed = false;
+        for (symbol, rules) in grammar {
+            if nullable.contains(symbol.as_str()) {
+                continue;
+            }
+            let is_nullable = rules.iter().any(|rule| rule.iter().all(|r| match r {
+                GrammarRule::Terminal(t) => t.is_empty(),
+                GrammarRule::Regex(regex) => regex.match_prefix(b"").contains(&0),
+                GrammarRule::NonTerminal(nt) => !grammar.contains_key(nt) || nullable.contains(nt.as_str()),
+            }));
+            if is_nullable {
+                nullable.insert(symbol.as_str());
+                changed = true;
+            }
+        }
+        if !changed {
+            return nullable;
+        }
+    }
+}
+
+/*
+ * Builds derivation trees from a finished chart. Rules are walked from right
+ * to left, an item with the dot at `d` in chart `k` proves that the first `d`
+ * rule elements derive the input between its origin and `k`, so only splits
+ * which lead to a full derivation are explored.
+ */
+struct Extractor<'p, 'a> {
+    parser: &'p EarleyParser<'a>,
+    input: &'p [u8],
+    seen: &'p [HashSet<Item<'a>>],
+    completed: &'p Completed<'a>,
+    /* Spans currently being extracted, guards against cyclic unit rules */
+    stack: Vec<(&'a str, usize, usize)>,
+}
+
+impl<'a> Extractor<'_, 'a> {
+    fn symbol_trees(&mut self, symbol: &'a str, start: usize, end: usize, limit: usize) -> Vec<DerivationTree> {
+        if self.stack.contains(&(symbol, start, end)) {
+            return Vec::new();
+        }
+        self.stack.push((symbol, start, end));
+        let mut trees = Vec::new();
+        let alternatives: Vec<usize> = self.completed.get(&(symbol, start))
+            .map(|ends| ends.iter().filter(|&&(_, e)| e == end).map(|&(a, _)| a).collect())
+            .unwrap_or_default();
+        for alternative in alternatives {
+            let dot = self.parser.grammar[symbol][alternative].len();
+            let item = Item { symbol, alternative, dot, origin: start };
+            for children in self.prefix_trees(item, end, limit - trees.len()) {
+                trees.push(DerivationTree::NonTerminal { symbol: symbol.to_string(), alternative, children });
+            }
+            if trees.len() >= limit {
+                break;
+            }
+        }
+        self.stack.pop();
+        trees
+    }
+
+    /*
+     * All derivations of the rule elements in front of the dot of `item`,
+     * ending at input position `end`.
+     */
+    fn prefix_trees(&mut self, item: Item<'a>, end: usize, limit: usize) -> Vec<Vec<DerivationTree>> {
+        if item.dot == 0 {
+            return if item.origin == end { vec![Vec::new()] } else { Vec::new() };
+        }
+        let previous = Item { dot: item.dot - 1, ..item };
+        let element = &self.parser.grammar[item.symbol][item.alternative][item.dot - 1];
+        let mut sequences = Vec::new();
+        for split in item.origin..=end {
+            if !self.seen[split].contains(&previous) {
+                continue;
+            }
+            let lasts: Vec<DerivationTree> = match element {
+                GrammarRule::NonTerminal(nt) => match self.parser.grammar.get_key_value(nt.as_str()) {
+                    Some((nt, _)) => self.symbol_trees(nt, split, end, limit),
+                    None if split == end => vec![DerivationTree::Terminal { symbol: nt.clone(), value: Vec::new() }],
+                    None => Vec::new(),
+                },
+                terminal => {
+                    if self.parser.match_terminal(terminal, &self.input[split..]).contains(&(end - split)) {
+                        vec![DerivationTree::Terminal { symbol: terminal.name(), value: self.input[split..end].to_vec() }]
+                    } else {
+                        Vec::new()
+                    }
+                },
+            };
+            for last in lasts {
+                for mut prefix in self.prefix_trees(previous, split, limit - sequences.len()) {
+                    prefix.push(last.clone());
+                    sequences.push(prefix);
+                }
+                if sequences.len() >= limit {
+                    return sequences;
+                }
+            }
+        }
+        sequences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::grammar::{create_cgi_grammar, create_grammar_calculation, generate_tree, GRAMMAR_ENTRY};
+
+    #[test]
+    fn test_parse_cgi_input() {
+        let grammar = create_cgi_grammar();
+        let terminals = TerminalRegistry::init();
+        let parser = EarleyParser::init(&grammar, &terminals);
+        let result = parser.parse(GRAMMAR_ENTRY, b"gh+%2F").unwrap();
+        assert!(!result.ambiguous);
+        assert_eq!(result.trees.len(), 1);
+        assert_eq!(result.trees[0].to_bytes(), b"gh+%2F".to_vec());
+
+        // '5' is both an ascii digit and a hex digit
+        let result = parser.parse(GRAMMAR_ENTRY, b"5").unwrap();
+        assert!(result.ambiguous);
+    }
+
+    #[test]
+    fn test_parse_generated_inputs() {
+        let grammar = create_grammar_calculation();
+        let terminals = TerminalRegistry::init();
+        let parser = EarleyParser::init(&grammar, &terminals);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let input = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng).to_bytes();
+            let result = parser.parse(GRAMMAR_ENTRY, &input).unwrap();
+            assert_eq!(result.trees[0].to_bytes(), input);
+        }
+    }
+
+    #[test]
+    fn test_parse_failure_position() {
+        let grammar = create_cgi_grammar();
+        let terminals = TerminalRegistry::init();
+        let parser = EarleyParser::init(&grammar, &terminals);
+        let error = parser.parse(GRAMMAR_ENTRY, b"ab%zz").unwrap_err();
+        assert_eq!(error.position, 3);
+        assert_eq!(error.expected, vec!["MAGIC-HexDigit".to_string()]);
+
+        let error = parser.parse(GRAMMAR_ENTRY, b"ab%2").unwrap_err();
+        assert_eq!(error.position, 4);
+    }
+
+    #[test]
+    fn test_parse_ambiguous_grammar() {
+        // Expr -> Expr "+" Expr | "1"
+        let mut grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![
+            vec![GrammarRule::NonTerminal(GRAMMAR_ENTRY.to_string()), GrammarRule::Terminal(b"+".to_vec()), GrammarRule::NonTerminal(GRAMMAR_ENTRY.to_string())],
+            vec![GrammarRule::Terminal(b"1".to_vec())],
+        ]);
+        let terminals = TerminalRegistry::empty();
+        let parser = EarleyParser::init(&grammar, &terminals);
+        let result = parser.parse(GRAMMAR_ENTRY, b"1+1+1").unwrap();
+        assert!(result.ambiguous);
+        assert_eq!(result.trees.len(), 2);
+        assert_ne!(result.trees[0], result.trees[1]);
+    }
+}
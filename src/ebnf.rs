@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use crate::grammar::{terminal_name, validate_grammar, Grammar, GrammarRule};
+use crate::regex_terminal::RegexTerminal;
+
+/*
+ * Default upper bound of repetitions added on top of the minimum for
+ * open-ended repetitions like x*, x+ and x{m,}.
+ */
+const DEFAULT_MAX_REPETITIONS: usize = 8;
+
+/**
+ * Rule element of a grammar with EBNF-style operators.
+ * EBNF grammars are converted into plain grammars with convert_ebnf(), every
+ * operator becomes a nonterminal named after the operator expression, so
+ * derivation trees, parsing and tree mutation work on them unchanged.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EbnfRule {
+    Terminal(Vec<u8>),
+    NonTerminal(String),
+    /* One byte out of a set, written [a-f0-9] */
+    CharClass(Vec<u8>),
+    /* Parenthesized alternatives, written ( a | b c ) */
+    Group(Vec<Vec<EbnfRule>>),
+    /* Repetition, written x? x* x+ x{m} x{m,} x{m,n}, max None is open-ended */
+    Repeat { rule: Box<EbnfRule>, min: usize, max: Option<usize> },
+    /* Token generated from a regular expression, written /[0-9A-F]{2}/ */
+    Regex(String),
+}
+
+pub type EbnfGrammar = HashMap<String, Vec<Vec<EbnfRule>>>;
+
+#[allow(unused)]
+impl EbnfRule {
+    pub fn optional(rule: EbnfRule) -> EbnfRule {
+        EbnfRule::Repeat { rule: Box::new(rule), min: 0, max: Some(1) }
+    }
+
+    pub fn star(rule: EbnfRule) -> EbnfRule {
+        EbnfRule::Repeat { rule: Box::new(rule), min: 0, max: None }
+    }
+
+    pub fn plus(rule: EbnfRule) -> EbnfRule {
+        EbnfRule::Repeat { rule: Box::new(rule), min: 1, max: None }
+    }
+
+    pub fn repeat(rule: EbnfRule, min: usize, max: Option<usize>) -> EbnfRule {
+        EbnfRule::Repeat { rule: Box::new(rule), min, max }
+    }
+
+    /**
+     * Character class of the inclusive byte ranges, e.g. [(b'a', b'f'), (b'0', b'9')].
+     */
+    pub fn char_ranges(ranges: &[(u8, u8)]) -> EbnfRule {
+        let mut bytes: Vec<u8> = ranges.iter().flat_map(|&(from, to)| from..=to).collect();
+        bytes.sort();
+        bytes.dedup();
+        EbnfRule::CharClass(bytes)
+    }
+
+    /**
+     * EBNF notation of the element, also used as name of the generated nonterminal.
+     */
+    pub fn to_text(&self) -> String {
+        match self {
+            EbnfRule::Terminal(t) => format!("\"{}\"", terminal_name(t)),
+            EbnfRule::NonTerminal(nt) => format!("<{}>", nt),
+            EbnfRule::CharClass(bytes) => format!("[{}]", class_text(bytes)),
+            EbnfRule::Regex(pattern) => format!("/{}/", pattern.replace('/', "\\/")),
+            EbnfRule::Group(alternatives) => {
+                let alternatives: Vec<String> = alternatives.iter()
+                    .map(|seq| seq.iter().map(|r| r.to_text()).collect::<Vec<String>>().join(" "))
+                    .collect();
+                format!("({})", alternatives.join(" | "))
+            },
+            EbnfRule::Repeat { rule, min, max } => {
+                let operator = match (min, max) {
+                    (0, Some(1)) => "?".to_string(),
+                    (0, None) => "*".to_string(),
+                    (1, None) => "+".to_string(),
+                    (min, None) => format!("{{{},}}", min),
+                    (min, Some(max)) if min == max => format!("{{{}}}", min),
+                    (min, Some(max)) => format!("{{{},{}}}", min, max),
+                };
+                format!("{}{}", rule.to_text(), operator)
+            },
+        }
+    }
+}
+
+/*
+ * Compact notation of a byte set, consecutive bytes are written as ranges.
+ */
+fn class_text(bytes: &[u8]) -> String {
+    let escape = |b: u8| match b {
+        b']' | b'-' | b'^' => format!("\\{}", b as char),
+        b => terminal_name(&[b]),
+    };
+    let mut text = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let mut j = i;
+        while j + 1 < bytes.len() && bytes[j + 1] == bytes[j] + 1 {
+            j += 1;
+        }
+        if j >= i + 2 {
+            text.push_str(&format!("{}-{}", escape(bytes[i]), escape(bytes[j])));
+        } else {
+            for &b in &bytes[i..=j] {
+                text.push_str(&escape(b));
+            }
+        }
+        i = j + 1;
+    }
+    text
+}
+
+/**
+ * Settings which bound the length of generated inputs.
+ * Open-ended repetitions (x*, x+, x{m,}) are generated with at most
+ * `max_repetitions` occurrences more than their minimum.
+ */
+#[derive(Debug, Clone)]
+pub struct LengthBounds {
+    pub max_repetitions: usize,
+}
+
+impl Default for LengthBounds {
+    fn default() -> Self {
+        LengthBounds { max_repetitions: DEFAULT_MAX_REPETITIONS }
+    }
+}
+
+/**
+ * Converts an EBNF grammar into a plain grammar.
+ * Character classes become a nonterminal with one alternative per byte, groups a
+ * nonterminal with the grouped alternatives and repetitions a nonterminal with one
+ * alternative per allowed count, so all counts are equally likely when generating.
+ * Counts above one are split in halves, helper nonterminals `x{k} -> x{k/2} x{k-k/2}`,
+ * so the size of the grammar grows linearly and the depth of trees logarithmically
+ * with the counts.
+ * Regexes stay single tokens, their open-ended repetitions use the same bound.
+ * The result is validated with validate_grammar().
+ */
+pub fn convert_ebnf(grammar: &EbnfGrammar, bounds: &LengthBounds) -> Result<Grammar, Vec<String>> {
+    let mut converter = Converter { bounds, grammar: HashMap::new(), errors: Vec::new() };
+    for (symbol, alternatives) in grammar {
+        let converted = alternatives.iter().map(|seq| converter.sequence(seq)).collect();
+        converter.grammar.insert(symbol.clone(), converted);
+    }
+    if !converter.errors.is_empty() {
+        return Err(converter.errors);
+    }
+    validate_grammar(&converter.grammar)?;
+    Ok(converter.grammar)
+}
+
+struct Converter<'b> {
+    bounds: &'b LengthBounds,
+    grammar: Grammar,
+    errors: Vec<String>,
+}
+
+impl Converter<'_> {
+    fn sequence(&mut self, sequence: &[EbnfRule]) -> Vec<GrammarRule> {
+        sequence.iter().map(|r| self.element(r)).collect()
+    }
+
+    fn element(&mut self, rule: &EbnfRule) -> GrammarRule {
+        let name = rule.to_text();
+        match rule {
+            EbnfRule::Terminal(t) => return GrammarRule::Terminal(t.clone()),
+            EbnfRule::NonTerminal(nt) => return GrammarRule::NonTerminal(nt.clone()),
+            EbnfRule::Regex(pattern) => match RegexTerminal::with_bounds(pattern, self.bounds) {
+                Ok(regex) => return GrammarRule::Regex(regex),
+                Err(error) => {
+                    self.errors.push(error);
+                    return GrammarRule::Terminal(Vec::new());
+                },
+            },
+            _ if self.grammar.contains_key(&name) => {},
+            EbnfRule::CharClass(bytes) => {
+                if bytes.is_empty() {
+                    self.errors.push(format!("empty character class {}", name));
+                }
+                let alternatives = bytes.iter().map(|&b| vec![GrammarRule::Terminal(vec![b])]).collect();
+                self.grammar.insert(name.clone(), alternatives);
+            },
+            EbnfRule::Group(alternatives) => {
+                let converted = alternatives.iter().map(|seq| self.sequence(seq)).collect();
+                self.grammar.insert(name.clone(), converted);
+            },
+            EbnfRule::Repeat { rule, min, max } => {
+                let max = max.unwrap_or(min + self.bounds.max_repetitions);
+                if *min > max {
+                    self.errors.push(format!("repetition {} has minimum larger than maximum", name));
+                }
+                let inner = self.element(rule);
+                let alternatives = (*min..=max).map(|count| self.repeated(rule, &inner, count)).collect();
+                self.grammar.insert(name.clone(), alternatives);
+            },
+        }
+        GrammarRule::NonTerminal(name)
+    }
+
+    /*
+     * Sequence of `count` occurrences of `inner`, the element converted from `rule`.
+     */
+    fn repeated(&mut self, rule: &EbnfRule, inner: &GrammarRule, count: usize) -> Vec<GrammarRule> {
+        match count {
+            0 | 1 => vec![inner.clone(); count],
+            _ => [count / 2, count - count / 2].into_iter().map(|half| self.exact(rule, inner, half)).collect(),
+        }
+    }
+
+    /*
+     * Element for exactly `count` occurrences, `inner` itself or the helper x{count}.
+     */
+    fn exact(&mut self, rule: &EbnfRule, inner: &GrammarRule, count: usize) -> GrammarRule {
+        if count == 1 {
+            return inner.clone();
+        }
+        let name = EbnfRule::repeat(rule.clone(), count, Some(count)).to_text();
+        if !self.grammar.contains_key(&name) {
+            let alternatives = vec![self.repeated(rule, inner, count)];
+            self.grammar.insert(name.clone(), alternatives);
+        }
+        GrammarRule::NonTerminal(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::earley::EarleyParser;
+    use crate::grammar::{generate, GRAMMAR_ENTRY};
+    use crate::terminals::TerminalRegistry;
+
+    fn identifier_grammar() -> EbnfGrammar {
+        // Start -> [a-z] [a-z0-9_]{0,4} ("=" <Value>)?
+        // Value -> [0-9]+
+        let mut grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![
+            EbnfRule::char_ranges(&[(b'a', b'z')]),
+            EbnfRule::repeat(EbnfRule::char_ranges(&[(b'a', b'z'), (b'0', b'9'), (b'_', b'_')]), 0, Some(4)),
+            EbnfRule::optional(EbnfRule::Group(vec![vec![
+                EbnfRule::Terminal(b"=".to_vec()),
+                EbnfRule::NonTerminal("Value".to_string()),
+            ]])),
+        ]]);
+        grammar.insert("Value".to_string(), vec![vec![EbnfRule::plus(EbnfRule::char_ranges(&[(b'0', b'9')]))]]);
+        grammar
+    }
+
+    #[test]
+    fn test_convert_and_generate() {
+        let bounds = LengthBounds { max_repetitions: 3 };
+        let grammar = convert_ebnf(&identifier_grammar(), &bounds).unwrap();
+        assert!(grammar.contains_key("[0-9_a-z]{0,4}"));
+        // one to four digits
+        assert_eq!(grammar["[0-9]+"].len(), 4);
+
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let output = generate(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+            let text = String::from_utf8(output).unwrap();
+            let (name, value) = text.split_once('=').unwrap_or((&text, "1"));
+            assert!((1..=5).contains(&name.len()));
+            assert!(name.starts_with(|c: char| c.is_ascii_lowercase()));
+            assert!((1..=4).contains(&value.len()));
+            assert!(value.bytes().all(|b| b.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn test_parse_converted_grammar() {
+        let grammar = convert_ebnf(&identifier_grammar(), &LengthBounds::default()).unwrap();
+        let terminals = TerminalRegistry::init();
+        let parser = EarleyParser::init(&grammar, &terminals);
+        assert!(parser.parse(GRAMMAR_ENTRY, b"x1_=42").is_ok());
+        assert!(parser.parse(GRAMMAR_ENTRY, b"abcdef").is_err());
+    }
+
+    #[test]
+    fn test_long_repetition_grows_linearly() {
+        let mut grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![EbnfRule::repeat(EbnfRule::Terminal(b"a".to_vec()), 100, Some(200))]]);
+        let grammar = convert_ebnf(&grammar, &LengthBounds::default()).unwrap();
+        let size: usize = grammar.values().flatten().map(|alternative| alternative.len()).sum();
+        assert!(size < 10 * 200);
+        assert_eq!(grammar["\"a\"{3}"], vec![vec![GrammarRule::Terminal(b"a".to_vec()), GrammarRule::NonTerminal("\"a\"{2}".to_string())]]);
+
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        let output = generate(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+        assert!((100..=200).contains(&output.len()) && output.iter().all(|&b| b == b'a'));
+        let parser = EarleyParser::init(&grammar, &terminals);
+        assert!(parser.parse(GRAMMAR_ENTRY, &[b'a'; 150]).is_ok());
+        assert!(parser.parse(GRAMMAR_ENTRY, &[b'a'; 99]).is_err());
+    }
+
+    #[test]
+    fn test_convert_errors() {
+        let mut grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![
+            EbnfRule::repeat(EbnfRule::Terminal(b"a".to_vec()), 3, Some(1)),
+            EbnfRule::CharClass(Vec::new()),
+        ]]);
+        assert_eq!(convert_ebnf(&grammar, &LengthBounds::default()).unwrap_err().len(), 2);
+    }
+
+    #[test]
+    fn test_to_text() {
+        let rule = EbnfRule::repeat(EbnfRule::char_ranges(&[(b'a', b'f'), (b'-', b'-')]), 2, None);
+        assert_eq!(rule.to_text(), "[\\-a-f]{2,}");
+        let rule = EbnfRule::optional(EbnfRule::Group(vec![
+            vec![EbnfRule::Terminal(b"\x00".to_vec())],
+            vec![EbnfRule::NonTerminal("A".to_string())],
+        ]));
+        assert_eq!(rule.to_text(), "(\"\\x00\" | <A>)?");
+    }
+}
@@ -0,0 +1,405 @@
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::constraints::FieldEncoding;
+use crate::derivation::DerivationTree;
+use crate::grammar_coverage::GrammarCoverage;
+use crate::runner::{RunnableProgram, RunnerProgramResult, RunnerResult};
+use crate::seeder::{ByteMutation, Seedable};
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/* SLIP special bytes, RFC 1055 */
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/**
+ * Transformation applied to a finished input before it is passed to the target,
+ * e.g. because the target expects base64 or a framed packet.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoder {
+    /* Percent-encoding of every byte except A-Z a-z 0-9 - . _ ~ */
+    Url,
+    /* Standard base64 with padding */
+    Base64,
+    /* Two lowercase hex digits per byte */
+    Hex,
+    /* Raw deflate stream */
+    Deflate,
+    /* Gzip member with header and CRC */
+    Gzip,
+    /* SLIP frame: escaped bytes followed by END */
+    Slip,
+    /* COBS frame: stuffed bytes followed by 0x00 */
+    Cobs,
+    /* Length of the input in front of it */
+    LengthPrefix(FieldEncoding),
+}
+
+impl Encoder {
+    /**
+     * Whether the output can contain NUL bytes, which a command-line argument
+     * cannot carry.
+     */
+    pub fn outputs_nul(&self) -> bool {
+        match self {
+            Encoder::Deflate | Encoder::Gzip | Encoder::Cobs => true,
+            Encoder::LengthPrefix(encoding) => *encoding != FieldEncoding::Decimal,
+            // SLIP keeps the bytes of the input, NUL only if the input has one
+            Encoder::Url | Encoder::Base64 | Encoder::Hex | Encoder::Slip => false,
+        }
+    }
+
+    pub fn encode(&self, input: &[u8]) -> Vec<u8> {
+        match self {
+            Encoder::Url => url_encode(input),
+            Encoder::Base64 => base64_encode(input),
+            Encoder::Hex => input.iter().flat_map(|b| format!("{:02x}", b).into_bytes()).collect(),
+            Encoder::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(input).and_then(|_| encoder.finish()).expect("compressing into memory")
+            },
+            Encoder::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(input).and_then(|_| encoder.finish()).expect("compressing into memory")
+            },
+            Encoder::Slip => slip_encode(input),
+            Encoder::Cobs => cobs_encode(input),
+            Encoder::LengthPrefix(encoding) => {
+                let mut output = encoding.encode(input.len() as u64);
+                output.extend_from_slice(input);
+                output
+            },
+        }
+    }
+}
+
+/**
+ * Parses the names used on the command line: url, base64, hex, deflate, gzip,
+ * slip, cobs, and length-u8, length-u16le, length-u32be, ... or length-decimal
+ * for length prefixes.
+ */
+impl FromStr for Encoder {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Encoder, String> {
+        let encoder = match name {
+            "url" => Encoder::Url,
+            "base64" => Encoder::Base64,
+            "hex" => Encoder::Hex,
+            "deflate" => Encoder::Deflate,
+            "gzip" => Encoder::Gzip,
+            "slip" => Encoder::Slip,
+            "cobs" => Encoder::Cobs,
+            "length-decimal" => Encoder::LengthPrefix(FieldEncoding::Decimal),
+            "length-u8" => Encoder::LengthPrefix(FieldEncoding::little_endian(1).unwrap()),
+            _ => {
+                let field = name.strip_prefix("length-u").and_then(|f| {
+                    let (bits, endian) = f.split_at(f.len().checked_sub(2)?);
+                    let width = match bits.parse::<usize>().ok()? {
+                        bits @ (16 | 32 | 64) => bits / 8,
+                        _ => return None,
+                    };
+                    match endian {
+                        "le" => FieldEncoding::little_endian(width).ok(),
+                        "be" => FieldEncoding::big_endian(width).ok(),
+                        _ => None,
+                    }
+                });
+                match field {
+                    Some(field) => Encoder::LengthPrefix(field),
+                    None => return Err(format!("unknown encoder '{}'", name)),
+                }
+            },
+        };
+        Ok(encoder)
+    }
+}
+
+fn url_encode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    for &b in input {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            output.push(b);
+        } else {
+            output.extend_from_slice(format!("%{:02X}", b).as_bytes());
+        }
+    }
+    output
+}
+
+fn base64_encode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize]);
+            } else {
+                output.push(b'=');
+            }
+        }
+    }
+    output
+}
+
+fn slip_encode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() + 1);
+    for &b in input {
+        match b {
+            SLIP_END => output.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => output.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            b => output.push(b),
+        }
+    }
+    output.push(SLIP_END);
+    output
+}
+
+/*
+ * Consistent Overhead Byte Stuffing: every block of up to 254 non-zero bytes
+ * is preceded by its length + 1, which also encodes the zero byte after it.
+ */
+fn cobs_encode(input: &[u8]) -> Vec<u8> {
+    let mut output = vec![0];
+    let mut code_index = 0;
+    let mut code: u8 = 1;
+    for &b in input {
+        if b != 0 {
+            output.push(b);
+            code += 1;
+        }
+        if b == 0 || code == 0xFF {
+            output[code_index] = code;
+            code_index = output.len();
+            output.push(0);
+            code = 1;
+        }
+    }
+    output[code_index] = code;
+    output.push(0);
+    output
+}
+
+/**
+ * Encoders applied one after another, e.g. gzip then base64.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncoderChain {
+    pub encoders: Vec<Encoder>,
+}
+
+impl EncoderChain {
+    pub fn encode(&self, input: &[u8]) -> Vec<u8> {
+        self.encoders.iter().fold(input.to_vec(), |data, encoder| encoder.encode(&data))
+    }
+
+    /**
+     * Last encoder of the chain if its output can contain NUL bytes, see
+     * Encoder::outputs_nul().
+     */
+    pub fn nul_output(&self) -> Option<Encoder> {
+        self.encoders.last().copied().filter(Encoder::outputs_nul)
+    }
+}
+
+/**
+ * Comma-separated encoder names in the order they are applied, e.g. "gzip,base64".
+ */
+impl FromStr for EncoderChain {
+    type Err = String;
+
+    fn from_str(names: &str) -> Result<EncoderChain, String> {
+        let encoders = names.split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(Encoder::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(EncoderChain { encoders })
+    }
+}
+
+/**
+ * Encodes the seeds of another seeder. Generation and mutation happen on the
+ * decoded data, only the input passed to the target is encoded. The derivation
+ * tree and coverage are those of the decoded input.
+ */
+pub struct EncodingSeeder<S: Seedable> {
+    pub seeder: S,
+    pub chain: EncoderChain,
+}
+
+impl<S: Seedable> EncodingSeeder<S> {
+    pub fn init(seeder: S, chain: EncoderChain) -> EncodingSeeder<S> {
+        EncodingSeeder { seeder, chain }
+    }
+}
+
+impl<S: Seedable> Seedable for EncodingSeeder<S> {
+    fn next_seed(&mut self) -> Vec<u8> {
+        self.chain.encode(&self.seeder.next_seed())
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        self.seeder.last_derivation_tree()
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.seeder.grammar_coverage()
+    }
+
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        self.seeder.last_mutations()
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        self.seeder.report_result(result);
+    }
+
+    fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+        self.seeder.report_foreign(input, result);
+    }
+
+    fn report(&self, out: &mut Vec<String>) {
+        self.seeder.report(out);
+    }
+}
+
+/**
+ * Encodes the inputs of a target before they are run, so that e.g. the
+ * minimizer works on the decoded input. The results carry the decoded input.
+ */
+pub struct EncodingTarget<R: RunnableProgram> {
+    pub target: R,
+    pub chain: EncoderChain,
+}
+
+impl<R: RunnableProgram> EncodingTarget<R> {
+    pub fn init(target: R, chain: EncoderChain) -> EncodingTarget<R> {
+        EncodingTarget { target, chain }
+    }
+}
+
+impl<R: RunnableProgram> RunnableProgram for EncodingTarget<R> {
+    fn sanitize_seed(&self, seed: &mut Vec<u8>) {
+        // encoded inputs are sanitized in run()
+        if self.chain.encoders.is_empty() {
+            self.target.sanitize_seed(seed);
+        }
+    }
+
+    fn run(&self, seed: &[u8]) -> RunnerProgramResult {
+        let mut encoded = self.chain.encode(seed);
+        self.target.sanitize_seed(&mut encoded);
+        let mut result = self.target.run(&encoded);
+        result.result.seed = seed.to_vec();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::{DeflateDecoder, GzDecoder};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::io::Read;
+
+    use super::*;
+    use crate::fuzzer::FuzzerProgram;
+    use crate::grammar::create_cgi_grammar;
+    use crate::minimizer::{minimize, same_crash_bucket};
+    use crate::runner::{ConstantSeeder, FnTarget, RunnerProgram, State};
+    use crate::seeder::GrammarSeeder;
+
+    #[test]
+    fn test_text_encoders() {
+        let encode = |name: &str, input: &[u8]| String::from_utf8(Encoder::from_str(name).unwrap().encode(input)).unwrap();
+        assert_eq!(encode("base64", b""), "");
+        assert_eq!(encode("base64", b"f"), "Zg==");
+        assert_eq!(encode("base64", b"fo"), "Zm8=");
+        assert_eq!(encode("base64", b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode("base64", &[0xFF, 0xFE]), "//4=");
+        assert_eq!(encode("url", b"a b&c=%~\xff"), "a%20b%26c%3D%25~%FF");
+        assert_eq!(encode("hex", b"\x00\xabZ"), "00ab5a");
+    }
+
+    #[test]
+    fn test_framing_encoders() {
+        assert_eq!(Encoder::Slip.encode(&[1, SLIP_END, SLIP_ESC]), vec![1, SLIP_ESC, SLIP_ESC_END, SLIP_ESC, SLIP_ESC_ESC, SLIP_END]);
+        assert_eq!(Encoder::Cobs.encode(&[]), vec![1, 0]);
+        assert_eq!(Encoder::Cobs.encode(&[0]), vec![1, 1, 0]);
+        assert_eq!(Encoder::Cobs.encode(&[0x11, 0x22, 0x00, 0x33]), vec![3, 0x11, 0x22, 2, 0x33, 0]);
+        let long: Vec<u8> = (1..=255).collect();
+        let encoded = Encoder::Cobs.encode(&long);
+        assert_eq!((encoded[0], encoded[255], encoded.len()), (0xFF, 2, 258));
+        assert!(!encoded[..encoded.len() - 1].contains(&0));
+
+        assert_eq!(Encoder::from_str("length-u16be").unwrap().encode(b"abc"), b"\x00\x03abc".to_vec());
+        assert_eq!(Encoder::from_str("length-u32le").unwrap().encode(b"a"), b"\x01\x00\x00\x00a".to_vec());
+        assert_eq!(Encoder::from_str("length-decimal").unwrap().encode(b"ab"), b"2ab".to_vec());
+        assert!(Encoder::from_str("length-u24le").is_err());
+        assert!(Encoder::from_str("rot13").is_err());
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let input = b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".repeat(10);
+        let mut decoded = Vec::new();
+        GzDecoder::new(Encoder::Gzip.encode(&input).as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        let compressed = Encoder::Deflate.encode(&input);
+        assert!(compressed.len() < input.len() / 10);
+        decoded.clear();
+        DeflateDecoder::new(compressed.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_encoding_seeder() {
+        let chain: EncoderChain = "length-u8, hex".parse().unwrap();
+        assert_eq!(chain.encode(b"\x10"), b"0110".to_vec());
+        let grammar_seeder = GrammarSeeder::init(create_cgi_grammar(), StdRng::seed_from_u64(0));
+        let mut seeder = EncodingSeeder::init(grammar_seeder, "base64".parse().unwrap());
+        for _ in 0..10 {
+            let seed = seeder.next_seed();
+            let decoded = seeder.last_derivation_tree().unwrap().to_bytes();
+            assert_eq!(seed, Encoder::Base64.encode(&decoded));
+        }
+    }
+
+    #[test]
+    fn test_encoded_bytes_reach_target() {
+        // echo prints its argument, so stdout shows the bytes the target received
+        let input = vec![b'a', SLIP_END, 0xFF, b'\n'];
+        let seeder = EncodingSeeder::init(ConstantSeeder(input.clone()), "slip".parse().unwrap());
+        let mut fuzzer = FuzzerProgram::init(RunnerProgram::init("echo"), seeder);
+        let result = fuzzer.run_one_time();
+        let mut expected = Encoder::Slip.encode(&input);
+        assert_eq!(result.result.seed, expected);
+        expected.push(b'\n');
+        assert_eq!(result.output_stdout, expected);
+
+        assert_eq!("gzip,base64".parse::<EncoderChain>().unwrap().nul_output(), None);
+        assert_eq!("slip,cobs".parse::<EncoderChain>().unwrap().nul_output(), Some(Encoder::Cobs));
+        assert_eq!("length-u16be".parse::<EncoderChain>().unwrap().nul_output(), Some(Encoder::LengthPrefix(FieldEncoding::big_endian(2).unwrap())));
+        assert_eq!("length-decimal".parse::<EncoderChain>().unwrap().nul_output(), None);
+    }
+
+    #[test]
+    fn test_minimize_decoded_input() {
+        // crashes if the hex encoded input contains "zz"
+        let target = EncodingTarget::init(FnTarget(|seed: &[u8]| {
+            let crashed = seed.windows(4).any(|w| w == b"7a7a");
+            (if crashed { State::Fail } else { State::Pass }, crashed as u64)
+        }), "hex".parse().unwrap());
+        let original = target.run(b"a=1&b=zz\x00");
+        assert_eq!(original.result.seed, b"a=1&b=zz\x00");
+        let minimized = minimize(b"a=1&b=zz\x00", &target, same_crash_bucket(&original.result));
+        assert_eq!(minimized.input, b"zz");
+    }
+}
@@ -0,0 +1,231 @@
+use std::str::FromStr;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+
+use crate::derivation::DerivationTree;
+use crate::grammar_coverage::GrammarCoverage;
+use crate::operator_scheduler::OperatorScheduler;
+use crate::runner::RunnerResult;
+use crate::seeder::{ByteMutation, Seedable};
+
+/**
+ * Kinds of seeders an ensemble can be built from on the command line.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SeederKind {
+    /* Inputs generated from the grammar */
+    Grammar,
+    /* Random bytes */
+    Random,
+    /* Entries of the corpus, see --corpus */
+    Corpus,
+    /* Mutated derivation trees of generated inputs */
+    Tree,
+}
+
+pub const ALL_SEEDER_KINDS: [SeederKind; 4] = [
+    SeederKind::Grammar,
+    SeederKind::Random,
+    SeederKind::Corpus,
+    SeederKind::Tree,
+];
+
+impl SeederKind {
+    /**
+     * Name on the command line, e.g. "tree".
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            SeederKind::Grammar => "grammar",
+            SeederKind::Random => "random",
+            SeederKind::Corpus => "corpus",
+            SeederKind::Tree => "tree",
+        }
+    }
+}
+
+/**
+ * Member of an ensemble on the command line: a seeder kind with an optional
+ * weight, e.g. "grammar" or "tree=3".
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnsembleSpec {
+    pub kind: SeederKind,
+    pub weight: f64,
+}
+
+impl FromStr for EnsembleSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<EnsembleSpec, String> {
+        let (name, weight) = match spec.split_once('=') {
+            Some((name, weight)) => (name, weight.parse::<f64>().ok().filter(|w| w.is_finite() && *w > 0.0)
+                .ok_or_else(|| format!("invalid weight '{}' of seeder '{}'", weight, name))?),
+            None => (spec, 1.0),
+        };
+        let kind = ALL_SEEDER_KINDS.into_iter()
+            .find(|k| k.name() == name)
+            .ok_or_else(|| format!("unknown seeder '{}'", name))?;
+        Ok(EnsembleSpec { kind, weight })
+    }
+}
+
+/**
+ * Child seeder of an ensemble.
+ */
+pub struct EnsembleMember {
+    pub name: String,
+    pub seeder: Box<dyn Seedable>,
+    /* Share of the seeds under fixed weights, relative to the other members */
+    pub weight: f64,
+}
+
+/**
+ * Seeder which combines several child seeders in one campaign. Each seed comes
+ * from one child, picked by the fixed weights of the children or, if adaptive,
+ * by the same bandit which schedules mutation operators: children whose seeds
+ * lead to new behavior or new crashes are picked more often. Results are passed
+ * to the child which produced the seed, and runs, finds and crashes are
+ * counted per child in either mode.
+ */
+pub struct EnsembleSeeder<R: Rng> {
+    pub members: Vec<EnsembleMember>,
+    pub adaptive: bool,
+    /* Runs, finds and crashes per member, and the weights if adaptive */
+    pub scheduler: OperatorScheduler,
+    rng: R,
+    /* Member which produced the last seed */
+    last_member: usize,
+}
+
+#[allow(unused)]
+impl<R: Rng> EnsembleSeeder<R> {
+    pub fn init(members: Vec<EnsembleMember>, adaptive: bool, rng: R) -> EnsembleSeeder<R> {
+        assert!(!members.is_empty() && members.iter().all(|m| m.weight > 0.0));
+        let names: Vec<&str> = members.iter().map(|m| m.name.as_str()).collect();
+        let scheduler = OperatorScheduler::init(&names);
+        EnsembleSeeder { members, adaptive, scheduler, rng, last_member: 0 }
+    }
+
+    /**
+     * Index of the member which produced the last seed.
+     */
+    pub fn last_member(&self) -> usize {
+        self.last_member
+    }
+
+    /**
+     * Probability of picking the member `index`.
+     */
+    pub fn probability(&self, index: usize) -> f64 {
+        if self.adaptive {
+            return self.scheduler.probability(index);
+        }
+        self.members[index].weight / self.members.iter().map(|m| m.weight).sum::<f64>()
+    }
+
+    /**
+     * Summary like "grammar 40%: 1200 runs, 5 finds, 1 crash; random 60%: ...".
+     */
+    pub fn summary(&self) -> String {
+        let members: Vec<String> = self.scheduler.operators.iter().enumerate().map(|(index, stats)| {
+            let crashes = if stats.crashes == 1 { "crash" } else { "crashes" };
+            format!("{} {:.0}%: {} runs, {} finds, {} {}", stats.name, 100.0 * self.probability(index), stats.uses, stats.finds, stats.crashes, crashes)
+        }).collect();
+        members.join("; ")
+    }
+}
+
+impl<R: Rng> Seedable for EnsembleSeeder<R> {
+    fn next_seed(&mut self) -> Vec<u8> {
+        self.last_member = if self.adaptive {
+            self.scheduler.choose(&mut self.rng)
+        } else {
+            WeightedIndex::new(self.members.iter().map(|m| m.weight)).unwrap().sample(&mut self.rng)
+        };
+        self.members[self.last_member].seeder.next_seed()
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        self.members[self.last_member].seeder.last_derivation_tree()
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.members.iter().find_map(|m| m.seeder.grammar_coverage())
+    }
+
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        self.members[self.last_member].seeder.last_mutations()
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        self.scheduler.reward(&[self.last_member], result);
+        self.members[self.last_member].seeder.report_result(result);
+    }
+
+    fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+        for member in &mut self.members {
+            member.seeder.report_foreign(input, result);
+        }
+    }
+
+    fn report(&self, out: &mut Vec<String>) {
+        for member in &self.members {
+            member.seeder.report(out);
+        }
+        out.push(format!("Seeders: {}", self.summary()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::runner::{ConstantSeeder, State};
+
+    fn member(name: &str, seed: &'static [u8], weight: f64) -> EnsembleMember {
+        EnsembleMember { name: name.to_string(), seeder: Box::new(ConstantSeeder(seed.to_vec())), weight }
+    }
+
+    /* Behavior of a run: new for every seed of "b", always the same for "a" */
+    fn run(seeder: &mut EnsembleSeeder<StdRng>, round: u64) -> Vec<u8> {
+        let seed = seeder.next_seed();
+        let behavior = if seed == b"b" { round } else { u64::MAX };
+        seeder.report_result(&RunnerResult::for_test(State::Pass, &seed, behavior));
+        seed
+    }
+
+    #[test]
+    fn test_fixed_weights_and_attribution() {
+        let mut seeder = EnsembleSeeder::init(vec![member("a", b"a", 3.0), member("b", b"b", 1.0)], false, StdRng::seed_from_u64(0));
+        let mut counts = [0u64; 2];
+        for round in 0..400 {
+            let seed = run(&mut seeder, round);
+            assert_eq!(seed, [b"a".as_slice(), b"b"][seeder.last_member()]);
+            counts[seeder.last_member()] += 1;
+        }
+        assert!(counts[0] > 2 * counts[1] && counts[1] > 50);
+        let stats = &seeder.scheduler.operators;
+        assert_eq!((stats[0].uses, stats[1].uses), (counts[0], counts[1]));
+        // "a" found its single behavior once, every run of "b" was new
+        assert_eq!((stats[0].finds, stats[1].finds), (1, counts[1]));
+        assert!(seeder.summary().starts_with("a 75%: "));
+    }
+
+    #[test]
+    fn test_adaptive_prefers_productive_member() {
+        let mut seeder = EnsembleSeeder::init(vec![member("a", b"a", 1.0), member("b", b"b", 1.0)], true, StdRng::seed_from_u64(1));
+        for round in 0..300 {
+            run(&mut seeder, round);
+        }
+        assert!(seeder.probability(1) > 0.8);
+        let mut lines = Vec::new();
+        seeder.report(&mut lines);
+        assert_eq!(lines, vec![format!("Seeders: {}", seeder.summary())]);
+        assert_eq!("tree=2.5".parse::<EnsembleSpec>(), Ok(EnsembleSpec { kind: SeederKind::Tree, weight: 2.5 }));
+        assert!("tree=0".parse::<EnsembleSpec>().is_err() && "bytes".parse::<EnsembleSpec>().is_err());
+    }
+}
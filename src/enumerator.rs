@@ -0,0 +1,353 @@
+use log::info;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::{HashMap, HashSet};
+
+use crate::constraints::ConstraintSet;
+use crate::derivation::DerivationTree;
+use crate::grammar::{Grammar, GrammarRule, GRAMMAR_ENTRY};
+use crate::seeder::Seedable;
+use crate::terminals::TerminalRegistry;
+
+/*
+ * Terminals whose values cannot be listed, like regexes or MAGIC-u32, are
+ * enumerated with this many values drawn from a fixed seed.
+ */
+const ENUMERATION_SAMPLES: usize = 4;
+
+/**
+ * Limits of an enumeration. Only derivation trees with at most `max_depth`
+ * levels (see DerivationTree::depth()) and inputs with at most `max_length`
+ * bytes are enumerated.
+ */
+#[derive(Debug, Clone)]
+pub struct EnumerationBounds {
+    pub max_depth: usize,
+    pub max_length: usize,
+}
+
+/**
+ * Enumerates every input derivable from `symbol` within the bounds, shortest
+ * first and byte-wise sorted within the same length. Inputs with several
+ * derivations appear once, with the first derivation found.
+ * The inputs are generated one length at a time, only the inputs of the
+ * current length are held in memory. Meant for small grammars, the number of
+ * inputs grows exponentially with the bounds.
+ */
+pub fn enumerate_trees(grammar: Grammar, terminals: TerminalRegistry, symbol: &str, bounds: &EnumerationBounds) -> Enumeration {
+    Enumeration {
+        grammar,
+        terminals,
+        symbol: symbol.to_string(),
+        bounds: bounds.clone(),
+        length: 0,
+        current: Vec::new().into_iter(),
+        trees: HashMap::new(),
+    }
+}
+
+/**
+ * Iterator over the inputs of enumerate_trees().
+ */
+pub struct Enumeration {
+    grammar: Grammar,
+    terminals: TerminalRegistry,
+    symbol: String,
+    bounds: EnumerationBounds,
+    /* Length of the inputs enumerated next */
+    length: usize,
+    /* Remaining inputs of the previous length */
+    current: std::vec::IntoIter<DerivationTree>,
+    /* Trees of a symbol with at most the given depth and exactly the given length */
+    trees: HashMap<(String, usize, usize), Vec<DerivationTree>>,
+}
+
+impl Enumeration {
+    /**
+     * Starts over with the shortest inputs. Subtrees enumerated so far are kept.
+     */
+    pub fn restart(&mut self) {
+        self.length = 0;
+        self.current = Vec::new().into_iter();
+    }
+
+    fn symbol_trees(&mut self, symbol: &str, depth: usize, length: usize) -> Vec<DerivationTree> {
+        let key = (symbol.to_string(), depth, length);
+        if let Some(trees) = self.trees.get(&key) {
+            return trees.clone();
+        }
+        let trees = self.expand(symbol, depth, length);
+        self.trees.insert(key, trees.clone());
+        trees
+    }
+
+    /*
+     * Trees of `symbol` of exactly `length` bytes, without the cache.
+     */
+    fn expand(&mut self, symbol: &str, depth: usize, length: usize) -> Vec<DerivationTree> {
+        if depth == 0 {
+            return Vec::new();
+        }
+        let Some(rules) = self.grammar.get(symbol).cloned() else {
+            // symbols without rules expand to nothing
+            return match length {
+                0 => vec![DerivationTree::Terminal { symbol: symbol.to_string(), value: Vec::new() }],
+                _ => Vec::new(),
+            };
+        };
+        let mut trees = Vec::new();
+        for (alternative, rule) in rules.iter().enumerate() {
+            for children in self.sequences(rule, depth - 1, length) {
+                trees.push(DerivationTree::NonTerminal { symbol: symbol.to_string(), alternative, children });
+            }
+        }
+        trees
+    }
+
+    /*
+     * All combinations of subtrees for the elements of `rule` with exactly
+     * `length` bytes. The first element takes every length for which it has
+     * trees, the rest of the rule gets the remaining bytes.
+     */
+    fn sequences(&mut self, rule: &[GrammarRule], depth: usize, length: usize) -> Vec<Vec<DerivationTree>> {
+        let Some((first, rest)) = rule.split_first() else {
+            return match length {
+                0 => vec![Vec::new()],
+                _ => Vec::new(),
+            };
+        };
+        let mut sequences = Vec::new();
+        for first_length in 0..=length {
+            let heads = self.element_trees(first, depth, first_length);
+            if heads.is_empty() {
+                continue;
+            }
+            let tails = self.sequences(rest, depth, length - first_length);
+            for head in &heads {
+                for tail in &tails {
+                    let mut sequence = Vec::with_capacity(rule.len());
+                    sequence.push(head.clone());
+                    sequence.extend(tail.iter().cloned());
+                    sequences.push(sequence);
+                }
+            }
+        }
+        sequences
+    }
+
+    fn element_trees(&mut self, element: &GrammarRule, depth: usize, length: usize) -> Vec<DerivationTree> {
+        if depth == 0 {
+            return Vec::new();
+        }
+        let values = match element {
+            GrammarRule::NonTerminal(nt) => return self.symbol_trees(nt, depth, length),
+            GrammarRule::Terminal(t) => self.terminals.values(t).unwrap_or_else(|| {
+                let mut rng = StdRng::seed_from_u64(0);
+                (0..ENUMERATION_SAMPLES).map(|_| self.terminals.expand(t, &mut rng)).collect()
+            }),
+            GrammarRule::Regex(regex) => {
+                let mut rng = StdRng::seed_from_u64(0);
+                (0..ENUMERATION_SAMPLES).map(|_| regex.generate(&mut rng)).collect()
+            },
+        };
+        let symbol = element.name();
+        let mut seen = HashSet::new();
+        values.into_iter()
+            .filter(|v| v.len() == length && seen.insert(v.clone()))
+            .map(|value| DerivationTree::Terminal { symbol: symbol.clone(), value })
+            .collect()
+    }
+}
+
+impl Iterator for Enumeration {
+    type Item = DerivationTree;
+
+    fn next(&mut self) -> Option<DerivationTree> {
+        loop {
+            if let Some(tree) = self.current.next() {
+                return Some(tree);
+            }
+            if self.length > self.bounds.max_length {
+                return None;
+            }
+            let symbol = self.symbol.clone();
+            let mut trees = self.expand(&symbol, self.bounds.max_depth, self.length);
+            self.length += 1;
+            // stable, so the first derivation of an input is kept
+            trees.sort_by_cached_key(DerivationTree::to_bytes);
+            trees.dedup_by(|a, b| a.to_bytes() == b.to_bytes());
+            self.current = trees.into_iter();
+        }
+    }
+}
+
+/**
+ * Deterministic seeder which returns every input of the grammar within the
+ * bounds, in increasing length. After the last input it starts over. The
+ * constraints of the grammar are applied to every input, inputs which are
+ * the same afterwards are returned once.
+ */
+#[allow(unused)]
+pub struct EnumerationSeeder {
+    enumeration: Enumeration,
+    constraints: ConstraintSet,
+    /* Inputs returned in this round, only kept if constraints can make inputs equal */
+    seen: HashSet<Vec<u8>>,
+    /* Next input, enumerated ahead to notice the end of a round */
+    pending: Option<DerivationTree>,
+    /* Number of inputs returned in this round */
+    pub position: usize,
+    /* Number of times all inputs were returned */
+    pub rounds: usize,
+    last: Option<DerivationTree>,
+}
+
+#[allow(unused)]
+impl EnumerationSeeder {
+    pub fn init(grammar: Grammar, terminals: TerminalRegistry, constraints: &ConstraintSet, bounds: &EnumerationBounds) -> Result<EnumerationSeeder, Vec<String>> {
+        constraints.validate(&grammar)?;
+        info!("Enumerating inputs with depth <= {} and length <= {}", bounds.max_depth, bounds.max_length);
+        let mut seeder = EnumerationSeeder {
+            enumeration: enumerate_trees(grammar, terminals, GRAMMAR_ENTRY, bounds),
+            constraints: constraints.clone(),
+            seen: HashSet::new(),
+            pending: None,
+            position: 0,
+            rounds: 0,
+            last: None,
+        };
+        seeder.pending = seeder.next_input();
+        Ok(seeder)
+    }
+
+    /**
+     * Whether every input has been returned at least once.
+     */
+    pub fn is_exhausted(&self) -> bool {
+        self.rounds > 0
+    }
+
+    /*
+     * Next input of this round with the constraints applied, skipping inputs
+     * which were already returned after applying them.
+     */
+    fn next_input(&mut self) -> Option<DerivationTree> {
+        if self.constraints.is_empty() {
+            return self.enumeration.next();
+        }
+        // violations are not enumerated, so the rng is never used
+        let mut rng = StdRng::seed_from_u64(0);
+        for mut tree in self.enumeration.by_ref() {
+            self.constraints.apply(&mut tree, &mut rng);
+            if self.seen.insert(tree.to_bytes()) {
+                return Some(tree);
+            }
+        }
+        None
+    }
+}
+
+impl Seedable for EnumerationSeeder {
+    fn next_seed(&mut self) -> Vec<u8> {
+        self.last = self.pending.take();
+        let Some(seed) = self.last.as_ref().map(DerivationTree::to_bytes) else {
+            return Vec::new();
+        };
+        self.position += 1;
+        self.pending = self.next_input();
+        if self.pending.is_none() {
+            info!("Enumeration of all {} inputs complete, starting over", self.position);
+            self.position = 0;
+            self.rounds += 1;
+            self.seen.clear();
+            self.enumeration.restart();
+            self.pending = self.next_input();
+        }
+        seed
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        self.last.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraints::{Constraint, FieldEncoding};
+    use crate::grammar::create_grammar_calculation;
+
+    #[test]
+    fn test_enumerate_calculator() {
+        let grammar = create_grammar_calculation();
+        let bounds = EnumerationBounds { max_depth: 6, max_length: 3 };
+        let inputs: Vec<Vec<u8>> = enumerate_trees(grammar, TerminalRegistry::init(), GRAMMAR_ENTRY, &bounds)
+            .map(|t| t.to_bytes())
+            .collect();
+        assert_eq!(inputs[0], b"0".to_vec());
+        assert!(inputs.windows(2).all(|w| w[0].len() <= w[1].len() && w[0] != w[1]));
+        // single numbers 0-255 and "()"
+        assert_eq!(inputs.iter().filter(|i| i.len() <= 2).count(), 10 + 90 + 1);
+        assert!(inputs.contains(&b"1+2".to_vec()));
+        assert!(inputs.contains(&b"255".to_vec()));
+        assert!(!inputs.contains(&b"()*()".to_vec()));
+    }
+
+    #[test]
+    fn test_depth_bound() {
+        let grammar = create_grammar_calculation();
+        // Start -> Term -> Factor -> "MAGIC-u8" needs four levels
+        let bounds = EnumerationBounds { max_depth: 3, max_length: 10 };
+        assert!(enumerate_trees(grammar.clone(), TerminalRegistry::init(), GRAMMAR_ENTRY, &bounds).next().is_none());
+        let bounds = EnumerationBounds { max_depth: 4, max_length: 10 };
+        for tree in enumerate_trees(grammar, TerminalRegistry::init(), GRAMMAR_ENTRY, &bounds) {
+            assert!(tree.depth() <= 4);
+        }
+    }
+
+    #[test]
+    fn test_enumeration_is_lazy() {
+        // far too many inputs to enumerate at once, the shortest come quickly
+        let bounds = EnumerationBounds { max_depth: 30, max_length: 1000 };
+        let inputs: Vec<Vec<u8>> = enumerate_trees(create_grammar_calculation(), TerminalRegistry::init(), GRAMMAR_ENTRY, &bounds)
+            .take(11)
+            .map(|t| t.to_bytes())
+            .collect();
+        assert_eq!(inputs[..2], [b"0".to_vec(), b"1".to_vec()]);
+        assert_eq!(inputs[10], b"()".to_vec());
+    }
+
+    #[test]
+    fn test_enumeration_seeder_repeats() {
+        let grammar = create_grammar_calculation();
+        let bounds = EnumerationBounds { max_depth: 6, max_length: 2 };
+        let mut seeder = EnumerationSeeder::init(grammar, TerminalRegistry::init(), &ConstraintSet::empty(), &bounds).unwrap();
+        let mut first_round = Vec::new();
+        while !seeder.is_exhausted() {
+            first_round.push(seeder.next_seed());
+        }
+        assert_eq!(first_round.len(), 10 + 90 + 1);
+        assert_eq!(seeder.next_seed(), first_round[0]);
+        assert_eq!(seeder.last_derivation_tree().unwrap().to_bytes(), first_round[0]);
+    }
+
+    #[test]
+    fn test_enumeration_applies_constraints() {
+        // Start -> "MAGIC-u8" Digits, the first byte is the length of the digits
+        let mut grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![GrammarRule::Terminal(b"MAGIC-u8".to_vec()), GrammarRule::NonTerminal("Digits".to_string())]]);
+        grammar.insert("Digits".to_string(), vec![
+            vec![GrammarRule::Terminal(b"1".to_vec()), GrammarRule::NonTerminal("Digits".to_string())],
+            vec![],
+        ]);
+        let mut constraints = ConstraintSet::empty();
+        constraints.add(Constraint::length(GRAMMAR_ENTRY, 0, 0, vec![1], FieldEncoding::little_endian(1).unwrap()));
+        let bounds = EnumerationBounds { max_depth: 5, max_length: 4 };
+        let mut seeder = EnumerationSeeder::init(grammar, TerminalRegistry::init(), &constraints, &bounds).unwrap();
+        let mut inputs = Vec::new();
+        while !seeder.is_exhausted() {
+            inputs.push(seeder.next_seed());
+        }
+        assert_eq!(inputs, vec![b"\x00".to_vec(), b"\x011".to_vec(), b"\x0211".to_vec(), b"\x03111".to_vec()]);
+    }
+}
@@ -1,12 +1,116 @@
-use crate::{logger::{log_crash, log_internal_error}, runner::{RunnableProgram, RunnerProgramResult, RunnerResult}, seeder::Seedable};
+use std::collections::HashMap;
+use std::path::PathBuf;
 
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::{logger::{log_crash, log_crash_tree, log_internal_error}, runner::{RunnableProgram, RunnerProgramResult, RunnerResult}, seeder::Seedable};
+use crate::derivation::DerivationTree;
+use crate::havoc::{havoc_mutation, HavocOperator, ALL_HAVOC_OPERATORS};
+use crate::seeder::ByteMutation;
+use crate::splicing::{replacement_mutations, splice, splice_trees, SpliceOperator, ALL_SPLICE_OPERATORS};
+
+
+const PRINT_STATUS_EVERY_RUN: u32 = 100;
 
 /**
- * A fuzzer uses a runner and fuzzes the given input.
+ * Settings of the genetic loop, see FuzzerProgram::run_genetic().
  */
+#[derive(Debug, Clone)]
+pub struct GeneticConfig {
+    pub population_size: usize,
+    /* Individuals compared when selecting a parent */
+    pub tournament_size: usize,
+    /* Probability that a child gets a havoc mutation after the crossover */
+    pub mutation_probability: f64,
+    /* Fresh seeds of the seeder joining in every generation */
+    pub immigrants: usize,
+    pub operators: Vec<SpliceOperator>,
+}
 
-const PRINT_STATUS_EVERY_RUN: u32 = 100;
+impl Default for GeneticConfig {
+    fn default() -> Self {
+        GeneticConfig {
+            population_size: 32,
+            tournament_size: 3,
+            mutation_probability: 0.5,
+            immigrants: 4,
+            operators: ALL_SPLICE_OPERATORS.to_vec(),
+        }
+    }
+}
 
+/*
+ * Member of the population of the genetic loop.
+ */
+struct Individual {
+    input: Vec<u8>,
+    tree: Option<DerivationTree>,
+    behavior: u64,
+    crashed: bool,
+}
+
+impl Individual {
+    fn from_result(result: &RunnerResult, seen: &mut HashMap<u64, u64>) -> Individual {
+        *seen.entry(result.behavior).or_insert(0) += 1;
+        Individual {
+            input: result.seed.clone(),
+            tree: result.derivation_tree.clone(),
+            behavior: result.behavior,
+            crashed: matches!(result.state, crate::runner::State::Fail),
+        }
+    }
+
+    /*
+     * Rarer behaviors are fitter, crashes count extra.
+     */
+    fn fitness(&self, seen: &HashMap<u64, u64>) -> f64 {
+        1.0 / seen[&self.behavior] as f64 + if self.crashed { 1.0 } else { 0.0 }
+    }
+}
+
+/*
+ * Index of the fittest of `size` random individuals.
+ */
+fn tournament(population: &[Individual], seen: &HashMap<u64, u64>, size: usize, rng: &mut impl Rng) -> usize {
+    (0..size.max(1))
+        .map(|_| rng.gen_range(0..population.len()))
+        .max_by(|&a, &b| population[a].fitness(seen).total_cmp(&population[b].fitness(seen)))
+        .unwrap()
+}
+
+/*
+ * Child of two individuals: a crossover with a random operator, possibly
+ * followed by a havoc mutation. If no crossover applies, the first parent
+ * is mutated instead. Returns the input, its tree if it is still valid and the
+ * mutations relative to the first parent.
+ */
+fn breed(first: &Individual, second: &Individual, config: &GeneticConfig, rng: &mut impl Rng) -> (Vec<u8>, Option<DerivationTree>, Vec<ByteMutation>) {
+    let both_trees = first.tree.as_ref().zip(second.tree.as_ref());
+    let operators: Vec<SpliceOperator> = config.operators.iter().copied().filter(|o| !o.needs_trees() || both_trees.is_some()).collect();
+    let child = operators.choose(rng).and_then(|&operator| match (operator, both_trees) {
+        (SpliceOperator::Subtree, Some((a, b))) => splice_trees(a, b, rng).map(|tree| (tree.to_bytes(), Some(tree))),
+        _ => splice(operator, &first.input, &second.input, rng).map(|input| (input, None)),
+    });
+    let crossed = child.is_some();
+    let (mut input, mut tree) = child.unwrap_or_else(|| (first.input.clone(), None));
+    let mut mutations = replacement_mutations(&first.input, &input);
+    if !crossed || rng.gen_bool(config.mutation_probability) {
+        // splicing with a corpus entry does not apply here
+        let operators: Vec<HavocOperator> = ALL_HAVOC_OPERATORS.into_iter().filter(|&o| o != HavocOperator::Splice).collect();
+        let operator = *operators.choose(rng).unwrap();
+        for mutation in havoc_mutation(operator, &input, rng) {
+            mutation.apply(&mut input);
+            mutations.push(mutation);
+            tree = None;
+        }
+    }
+    (input, tree, mutations)
+}
+
+/**
+ * A fuzzer uses a runner and fuzzes the given input.
+ */
 #[allow(unused)]
  pub struct FuzzerProgram<R: RunnableProgram, S: Seedable> {
     pub runnable_instance: R,
@@ -15,6 +119,8 @@ const PRINT_STATUS_EVERY_RUN: u32 = 100;
     pub crash: Vec<RunnerResult>,
     pub no_crash: Vec<RunnerResult>,
     pub unknown_crash_status: Vec<RunnerResult>,
+    /* Directory for the DOT files of the derivation trees of crashing inputs */
+    pub crash_tree_directory: Option<PathBuf>,
 }    
 impl<R: RunnableProgram, T: Seedable> FuzzerProgram<R, T> {
     pub fn init(runnable_instance: R, seedable_instance: T) -> Self {
@@ -25,28 +131,39 @@ impl<R: RunnableProgram, T: Seedable> FuzzerProgram<R, T> {
             crash: Vec::new(),
             no_crash: Vec::new(),
             unknown_crash_status: Vec::new(),
+            crash_tree_directory: None,
         }
     }
 
     pub fn run_forever(&mut self) {
         loop {
             let result = self.run_one_time();
-            match result.result.state {
-                crate::runner::State::Fail => {
-                    log_crash(&result);
-                },
-                crate::runner::State::InternalError => {
-                    log_internal_error(&result);
-                }
-                _ => {
+            self.process_result(&result);
+        }
+    }
+
+    /*
+     * Logs crashes and internal errors and prints the status regularly.
+     */
+    fn process_result(&self, result: &RunnerProgramResult) {
+        match result.result.state {
+            crate::runner::State::Fail => {
+                log_crash(result);
+                if let Some(directory) = &self.crash_tree_directory {
+                    log_crash_tree(result, directory, self.crash.len());
                 }
+            },
+            crate::runner::State::InternalError => {
+                log_internal_error(result);
             }
-            
-            //regular printing of status:
-            if (self.count_run % (PRINT_STATUS_EVERY_RUN as u64)) == 0 {
-                self.print_results();
+            _ => {
             }
         }
+
+        //regular printing of status:
+        if (self.count_run % (PRINT_STATUS_EVERY_RUN as u64)) == 0 {
+            self.print_results();
+        }
     }
 
 
@@ -68,14 +185,28 @@ impl<R: RunnableProgram, T: Seedable> FuzzerProgram<R, T> {
 
     pub fn run_one_time(&mut self) -> RunnerProgramResult {
         //generate seed
-        let mut seed = self.seedable_instance.next_seed();
+        let seed = self.seedable_instance.next_seed();
         //println!("Seed: {:?}", String::from_utf8_lossy(&seed));
+        let tree = self.seedable_instance.last_derivation_tree().cloned();
+        let mutations = self.seedable_instance.last_mutations();
+        let result = self.run_seed(seed, tree, mutations);
+        self.seedable_instance.report_result(&result.result);
+        result
+    }
 
+    /*
+     * Runs a single input and records the result.
+     */
+    fn run_seed(&mut self, mut seed: Vec<u8>, tree: Option<DerivationTree>, mutations: Vec<ByteMutation>) -> RunnerProgramResult {
         //sanitize seed to make it SUT ready
+        let unsanitized = seed.clone();
         self.runnable_instance.sanitize_seed(&mut seed);
 
         //feed it to the runner
-        let result = self.runnable_instance.run(&seed);
+        let mut result = self.runnable_instance.run(&seed);
+        // the tree no longer describes the seed if sanitizing changed it
+        result.result.derivation_tree = tree.filter(|_| seed == unsanitized);
+        result.result.mutations = mutations;
         //process result
         self.count_run = self.count_run + 1;
         match result.result.state {
@@ -92,10 +223,59 @@ impl<R: RunnableProgram, T: Seedable> FuzzerProgram<R, T> {
         result
     }
 
+    /**
+     * Genetic loop: the first population are seeds of the seeder. Every
+     * generation breeds `population_size` children from two parents chosen by
+     * tournament (see breed()) and adds `immigrants` fresh seeds, then the
+     * fittest individuals of parents and children survive. Fitness is the rarity
+     * of the behavior signature of a run, crashes count extra. Runs the given
+     * number of generations, or forever.
+     */
+    pub fn run_genetic(&mut self, config: &GeneticConfig, generations: Option<usize>, rng: &mut impl Rng) {
+        let mut seen = HashMap::new();
+        let mut population = Vec::new();
+        for _ in 0..config.population_size.max(1) {
+            let result = self.run_one_time();
+            self.process_result(&result);
+            population.push(Individual::from_result(&result.result, &mut seen));
+        }
+        let mut generation = 0;
+        while generations.is_none_or(|g| generation < g) {
+            let mut children = Vec::new();
+            for _ in 0..config.immigrants {
+                let result = self.run_one_time();
+                self.process_result(&result);
+                children.push(Individual::from_result(&result.result, &mut seen));
+            }
+            for _ in 0..config.population_size {
+                let first = &population[tournament(&population, &seen, config.tournament_size, rng)];
+                let second = &population[tournament(&population, &seen, config.tournament_size, rng)];
+                let (input, tree, mutations) = breed(first, second, config, rng);
+                let result = self.run_seed(input.clone(), tree, mutations);
+                self.seedable_instance.report_foreign(&input, &result.result);
+                self.process_result(&result);
+                children.push(Individual::from_result(&result.result, &mut seen));
+            }
+            population.extend(children);
+            // stable, so older individuals win ties
+            population.sort_by(|a, b| b.fitness(&seen).total_cmp(&a.fitness(&seen)));
+            population.truncate(config.population_size.max(1));
+            generation += 1;
+        }
+    }
+
     pub fn print_results(&self) {
         println!("--------------------------REPORT--------------------------");
         println!("Total runs: {}", self.count_run);
         println!("Number crashes: {}", self.crash.len());
+        if let Some(coverage) = self.seedable_instance.grammar_coverage() {
+            println!("Grammar coverage: {}", coverage.report());
+        }
+        let mut lines = Vec::new();
+        self.seedable_instance.report(&mut lines);
+        for line in lines {
+            println!("{}", line);
+        }
         
         if self.crash.len() != 0 {
             println!("Crashes:");
@@ -111,3 +291,109 @@ impl<R: RunnableProgram, T: Seedable> FuzzerProgram<R, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::havoc::HavocSeedModifier;
+    use crate::runner::{FnTarget, State};
+
+    /* Crashes on inputs containing both "ab" and "cd", behavior is the length */
+    fn two_part_target(seed: &[u8]) -> (State, u64) {
+        let crashed = seed.windows(2).any(|w| w == b"ab") && seed.windows(2).any(|w| w == b"cd");
+        (if crashed { State::Fail } else { State::Pass }, seed.len() as u64)
+    }
+
+    /* Each seed holds one of the two parts, reported results are checked and counted */
+    struct AlternatingSeeder(usize, usize, usize);
+
+    impl AlternatingSeeder {
+        fn seed(&self) -> Vec<u8> {
+            if self.0.is_multiple_of(2) { b"ab=1".to_vec() } else { b"x=cd".to_vec() }
+        }
+    }
+
+    impl Seedable for AlternatingSeeder {
+        fn next_seed(&mut self) -> Vec<u8> {
+            self.0 += 1;
+            self.seed()
+        }
+
+        fn report_result(&mut self, result: &RunnerResult) {
+            assert_eq!(ByteMutation::replay(&self.seed(), &result.mutations), result.seed);
+            self.1 += 1;
+        }
+
+        fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+            assert_eq!(input, result.seed);
+            self.2 += 1;
+        }
+    }
+
+    /* Like the CGI runner, removes nul bytes */
+    struct NulFreeTarget;
+
+    impl RunnableProgram for NulFreeTarget {
+        fn sanitize_seed(&self, seed: &mut Vec<u8>) {
+            seed.retain(|&byte| byte != 0);
+        }
+
+        fn run(&self, seed: &[u8]) -> RunnerProgramResult {
+            FnTarget(two_part_target).run(seed)
+        }
+    }
+
+    /* Yields the bytes of its tree */
+    struct TreeSeeder(DerivationTree);
+
+    impl Seedable for TreeSeeder {
+        fn next_seed(&mut self) -> Vec<u8> {
+            self.0.to_bytes()
+        }
+
+        fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_tree_kept_only_for_unchanged_seeds() {
+        let leaf = |value: &[u8]| DerivationTree::Terminal { symbol: "Start".to_string(), value: value.to_vec() };
+        let mut fuzzer = FuzzerProgram::init(NulFreeTarget, TreeSeeder(leaf(b"a=1")));
+        assert!(fuzzer.run_one_time().result.derivation_tree.is_some());
+        let mut fuzzer = FuzzerProgram::init(NulFreeTarget, TreeSeeder(leaf(b"a=\x001")));
+        let result = fuzzer.run_one_time().result;
+        assert_eq!(result.seed, b"a=1");
+        assert!(result.derivation_tree.is_none());
+    }
+
+    #[test]
+    fn test_genetic_loop_combines_seeds() {
+        let mut fuzzer = FuzzerProgram::init(FnTarget(two_part_target), AlternatingSeeder(0, 0, 0));
+        let config = GeneticConfig { population_size: 8, immigrants: 2, mutation_probability: 0.0, ..GeneticConfig::default() };
+        fuzzer.run_genetic(&config, Some(5), &mut StdRng::seed_from_u64(1));
+        assert_eq!(fuzzer.count_run, 8 + 5 * (2 + 8));
+        // children are reported apart from the seeder's own seeds
+        assert_eq!((fuzzer.seedable_instance.1, fuzzer.seedable_instance.2), (8 + 5 * 2, 5 * 8));
+        assert!(!fuzzer.crash.is_empty());
+        // the seeder alone never crashes, the first crash was bred from two seeds
+        assert!(!fuzzer.crash[0].mutations.is_empty());
+    }
+
+    #[test]
+    fn test_genetic_loop_rewards_only_own_seeds() {
+        let mut havoc_seeder = HavocSeedModifier::init_with_operators(AlternatingSeeder(0, 0, 0), StdRng::seed_from_u64(2), vec![HavocOperator::BitFlip], 1);
+        havoc_seeder.enable_scheduler();
+        let mut fuzzer = FuzzerProgram::init(FnTarget(two_part_target), havoc_seeder);
+        let config = GeneticConfig { population_size: 8, immigrants: 2, ..GeneticConfig::default() };
+        fuzzer.run_genetic(&config, Some(5), &mut StdRng::seed_from_u64(3));
+        assert_eq!(fuzzer.count_run, 8 + 5 * (2 + 8));
+        // one bit flip on each seed of the seeder, none credited for the children
+        let uses = fuzzer.seedable_instance.scheduler.as_ref().unwrap().operators[0].uses;
+        assert_eq!(uses, 8 + 5 * 2);
+        assert_eq!(fuzzer.seedable_instance.seeder.2, 5 * 8);
+    }
+}
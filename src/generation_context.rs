@@ -0,0 +1,251 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::derivation::DerivationTree;
+
+/*
+ * Attempts to find a value for MAGIC-Unique which was not used before.
+ */
+const UNIQUE_ATTEMPTS: usize = 32;
+
+/**
+ * State shared by all terminals while one input is generated, so terminals can
+ * depend on values generated earlier in the same input. Besides the built-in
+ * context terminals (see ContextTerminal), generators registered in the
+ * TerminalRegistry can read and write it in generate_with_context().
+ */
+#[derive(Debug, Clone, Default)]
+pub struct GenerationContext {
+    variables: HashMap<String, Vec<u8>>,
+    counters: HashMap<String, u64>,
+    used: HashMap<String, HashSet<Vec<u8>>>,
+}
+
+#[allow(unused)]
+impl GenerationContext {
+    pub fn new() -> GenerationContext {
+        GenerationContext::default()
+    }
+
+    /**
+     * Context as it was after generating `tree`, rebuilt from the values of its
+     * context terminals. Used to generate replacement subtrees consistent with
+     * the rest of a tree.
+     */
+    pub fn from_tree(tree: &DerivationTree) -> GenerationContext {
+        let mut context = GenerationContext::new();
+        context.replay(tree);
+        context
+    }
+
+    /**
+     * Context as it was when the subtree at `path` of `tree` was about to be
+     * generated, rebuilt from the context terminals left of it. Used to
+     * generate a replacement for that subtree, so e.g. counters continue from
+     * the values before it.
+     */
+    pub fn before(tree: &DerivationTree, path: &[usize]) -> GenerationContext {
+        let mut context = GenerationContext::new();
+        let mut node = tree;
+        for &index in path {
+            let DerivationTree::NonTerminal { children, .. } = node else {
+                break;
+            };
+            for child in &children[..index] {
+                context.replay(child);
+            }
+            node = &children[index];
+        }
+        context
+    }
+
+    /**
+     * Records the values of the context terminals in `tree`, as if it was
+     * generated with this context.
+     */
+    pub fn replay(&mut self, tree: &DerivationTree) {
+        match tree {
+            DerivationTree::Terminal { symbol, value } => match ContextTerminal::parse(symbol.as_bytes()) {
+                Some(ContextTerminal::Counter(name)) => {
+                    let next = std::str::from_utf8(value).ok().and_then(|v| v.parse::<u64>().ok()).map_or(0, |v| v + 1);
+                    let counter = self.counters.entry(name.to_string()).or_insert(0);
+                    *counter = (*counter).max(next);
+                },
+                Some(ContextTerminal::Set(name, _) | ContextTerminal::Var(name, _)) => self.set(name, value.clone()),
+                Some(ContextTerminal::Unique(name, _)) => {
+                    self.insert_unique(name, value);
+                },
+                Some(ContextTerminal::Get(_)) | None => {},
+            },
+            DerivationTree::NonTerminal { children, .. } => {
+                for child in children {
+                    self.replay(child);
+                }
+            },
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.variables.get(name).map(|v| v.as_slice())
+    }
+
+    pub fn set(&mut self, name: &str, value: Vec<u8>) {
+        self.variables.insert(name.to_string(), value);
+    }
+
+    /**
+     * Current value of the counter `name`, starting at 0, and increments it.
+     */
+    pub fn next_counter(&mut self, name: &str) -> u64 {
+        let counter = self.counters.entry(name.to_string()).or_insert(0);
+        *counter += 1;
+        *counter - 1
+    }
+
+    /**
+     * Records `value` as used for `name`, false if it was used before.
+     */
+    pub fn insert_unique(&mut self, name: &str, value: &[u8]) -> bool {
+        self.used.entry(name.to_string()).or_default().insert(value.to_vec())
+    }
+}
+
+/**
+ * Terminals which read or write the generation context. Their text names a
+ * variable and, where a value is generated, the terminal generating it, which
+ * may be a generator like MAGIC-u32 or a literal:
+ *
+ * - "MAGIC-Counter:seq" is 0 at the first use in an input, then 1, 2, ...
+ * - "MAGIC-Set:session:MAGIC-u32" generates a value and stores it as `session`.
+ * - "MAGIC-Get:session" repeats the value stored last, or nothing if none was.
+ * - "MAGIC-Var:token:MAGIC-Alphanumeric" generates a value at the first use and
+ *   repeats it in every later use within the same input.
+ * - "MAGIC-Unique:name:MAGIC-LowerCaseLetter" generates a value which differs
+ *   from all earlier values of `name` in the input, as far as possible.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextTerminal<'t> {
+    Counter(&'t str),
+    Set(&'t str, &'t [u8]),
+    Get(&'t str),
+    Var(&'t str, &'t [u8]),
+    Unique(&'t str, &'t [u8]),
+}
+
+impl<'t> ContextTerminal<'t> {
+    pub fn parse(terminal: &'t [u8]) -> Option<ContextTerminal<'t>> {
+        let text = std::str::from_utf8(terminal).ok()?;
+        let (kind, rest) = text.strip_prefix("MAGIC-")?.split_once(':')?;
+        let (name, inner) = match rest.split_once(':') {
+            Some((name, inner)) => (name, Some(inner.as_bytes())),
+            None => (rest, None),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        match (kind, inner) {
+            ("Counter", None) => Some(ContextTerminal::Counter(name)),
+            ("Get", None) => Some(ContextTerminal::Get(name)),
+            ("Set", Some(inner)) => Some(ContextTerminal::Set(name, inner)),
+            ("Var", Some(inner)) => Some(ContextTerminal::Var(name, inner)),
+            ("Unique", Some(inner)) => Some(ContextTerminal::Unique(name, inner)),
+            _ => None,
+        }
+    }
+
+    /**
+     * Value of the terminal, `generate` expands the inner terminal.
+     */
+    pub fn expand(&self, context: &mut GenerationContext, generate: &mut dyn FnMut(&[u8], &mut GenerationContext) -> Vec<u8>) -> Vec<u8> {
+        match *self {
+            ContextTerminal::Counter(name) => context.next_counter(name).to_string().into_bytes(),
+            ContextTerminal::Get(name) => context.get(name).unwrap_or_default().to_vec(),
+            ContextTerminal::Set(name, inner) => {
+                let value = generate(inner, context);
+                context.set(name, value.clone());
+                value
+            },
+            ContextTerminal::Var(name, inner) => match context.get(name) {
+                Some(value) => value.to_vec(),
+                None => {
+                    let value = generate(inner, context);
+                    context.set(name, value.clone());
+                    value
+                },
+            },
+            ContextTerminal::Unique(name, inner) => {
+                let mut value = generate(inner, context);
+                for _ in 1..UNIQUE_ATTEMPTS {
+                    if !context.used.get(name).is_some_and(|used| used.contains(&value)) {
+                        break;
+                    }
+                    value = generate(inner, context);
+                }
+                context.insert_unique(name, &value);
+                value
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::grammar::{generate_tree_with_context, Grammar, GrammarRule};
+    use crate::terminals::TerminalRegistry;
+
+    fn message_grammar() -> Grammar {
+        let terminal = |t: &str| GrammarRule::Terminal(t.as_bytes().to_vec());
+        let mut grammar = Grammar::new();
+        grammar.insert("Start".to_string(), vec![vec![
+            terminal("MAGIC-Set:session:MAGIC-u32"), terminal(";"), GrammarRule::NonTerminal("Messages".to_string()),
+        ]]);
+        grammar.insert("Messages".to_string(), vec![
+            vec![GrammarRule::NonTerminal("Message".to_string())],
+            vec![GrammarRule::NonTerminal("Message".to_string()), GrammarRule::NonTerminal("Messages".to_string())],
+        ]);
+        grammar.insert("Message".to_string(), vec![vec![
+            terminal("MAGIC-Counter:seq"), terminal(","), terminal("MAGIC-Get:session"), terminal(","),
+            terminal("MAGIC-Var:user:MAGIC-LowerCaseLetter"), terminal(","), terminal("MAGIC-Unique:id:MAGIC-AsciiDigit"), terminal(";"),
+        ]]);
+        grammar
+    }
+
+    #[test]
+    fn test_context_terminals() {
+        let grammar = message_grammar();
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..50 {
+            let tree = generate_tree_with_context(&grammar, &terminals, &mut GenerationContext::new(), "Start", &mut rng);
+            let output = String::from_utf8(tree.to_bytes()).unwrap();
+            let mut parts = output.trim_end_matches(';').split(';');
+            let session = parts.next().unwrap();
+            let messages: Vec<Vec<&str>> = parts.map(|m| m.split(',').collect()).collect();
+            let mut ids = HashSet::new();
+            for (index, message) in messages.iter().enumerate() {
+                assert_eq!(message[0], index.to_string());
+                assert_eq!(message[1], session);
+                assert_eq!(message[2], messages[0][2]);
+                assert!(ids.insert(message[3]) || index >= 10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_context_from_tree() {
+        let grammar = message_grammar();
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut generated = GenerationContext::new();
+        let tree = generate_tree_with_context(&grammar, &terminals, &mut generated, "Start", &mut rng);
+        let mut replayed = GenerationContext::from_tree(&tree);
+        assert_eq!(replayed.get("session"), generated.get("session"));
+        assert_eq!(replayed.get("user"), generated.get("user"));
+        assert_eq!(replayed.next_counter("seq"), generated.next_counter("seq"));
+        assert_eq!(ContextTerminal::parse(b"MAGIC-Get:session:x"), None);
+        assert_eq!(ContextTerminal::parse(b"MAGIC-u8"), None);
+    }
+}
@@ -1,15 +1,51 @@
-use rand::seq::SliceRandom;
 use rand::Rng;
 use std::collections::HashMap;
-use std::u8;
+
+use crate::derivation::DerivationTree;
+use crate::generation_context::GenerationContext;
+use crate::regex_terminal::RegexTerminal;
+use crate::terminals::TerminalRegistry;
+use crate::tree_mutator::shortest_derivations;
 
 
 pub const GRAMMAR_ENTRY: &str = "Start";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum GrammarRule {
-    Terminal(String),
+    /* Raw bytes, or the name of a generator registered in the TerminalRegistry */
+    Terminal(Vec<u8>),
     NonTerminal(String),
+    /* Token generated from a regular expression */
+    Regex(RegexTerminal),
+}
+
+impl GrammarRule {
+    /**
+     * Terminal generated from `pattern`, panics if the pattern is invalid.
+     */
+    #[allow(unused)]
+    pub fn regex(pattern: &str) -> GrammarRule {
+        GrammarRule::Regex(RegexTerminal::init(pattern).unwrap())
+    }
+
+    /**
+     * Symbol of the derivation tree node produced by this element.
+     */
+    pub fn name(&self) -> String {
+        match self {
+            GrammarRule::Terminal(t) => terminal_name(t),
+            GrammarRule::NonTerminal(nt) => nt.clone(),
+            GrammarRule::Regex(regex) => regex.name(),
+        }
+    }
+}
+
+/**
+ * Printable name of a terminal, used as symbol of terminal nodes in derivation trees.
+ * Non-printable bytes are escaped like in grammar files, e.g. "\x00".
+ */
+pub fn terminal_name(terminal: &[u8]) -> String {
+    terminal.iter().flat_map(|b| std::ascii::escape_default(*b)).map(|b| b as char).collect()
 }
 
 pub type Grammar = HashMap<String, Vec<Vec<GrammarRule>>>;
@@ -47,15 +83,15 @@ pub fn create_cgi_grammar() -> Grammar {
         vec![GrammarRule::NonTerminal("Other".to_string())], 
     ]);
     grammar.insert("Plus".to_string(), vec![
-        vec![GrammarRule::Terminal("+".to_string())],
+        vec![GrammarRule::Terminal(b"+".to_vec())],
     ]);
     grammar.insert("Percent".to_string(), vec![
-        vec![GrammarRule::Terminal("%".to_string()), GrammarRule::Terminal("MAGIC-HexDigit".to_string()), GrammarRule::Terminal("MAGIC-HexDigit".to_string())],
+        vec![GrammarRule::Terminal(b"%".to_vec()), GrammarRule::Terminal(b"MAGIC-HexDigit".to_vec()), GrammarRule::Terminal(b"MAGIC-HexDigit".to_vec())],
     ]);
     grammar.insert("Other".to_string(), vec![
-        vec![GrammarRule::Terminal("MAGIC-AsciiDigit".to_string())],
-        vec![GrammarRule::Terminal("MAGIC-LowerCaseLetter".to_string())],
-        vec![GrammarRule::Terminal("MAGIC-HexDigit".to_string())], 
+        vec![GrammarRule::Terminal(b"MAGIC-AsciiDigit".to_vec())],
+        vec![GrammarRule::Terminal(b"MAGIC-LowerCaseLetter".to_vec())],
+        vec![GrammarRule::Terminal(b"MAGIC-HexDigit".to_vec())], 
     ]);
     grammar
 }
@@ -67,96 +103,116 @@ pub fn create_grammar_calculation() -> Grammar {
         vec![GrammarRule::NonTerminal("Term".to_string()), GrammarRule::NonTerminal("ExprTail".to_string())],
     ]);
     grammar.insert("ExprTail".to_string(), vec![
-        vec![GrammarRule::Terminal("+".to_string()), GrammarRule::NonTerminal("Term".to_string()), GrammarRule::NonTerminal("ExprTail".to_string())],
-        vec![GrammarRule::Terminal("-".to_string()), GrammarRule::NonTerminal("Term".to_string()), GrammarRule::NonTerminal("ExprTail".to_string())],
+        vec![GrammarRule::Terminal(b"+".to_vec()), GrammarRule::NonTerminal("Term".to_string()), GrammarRule::NonTerminal("ExprTail".to_string())],
+        vec![GrammarRule::Terminal(b"-".to_vec()), GrammarRule::NonTerminal("Term".to_string()), GrammarRule::NonTerminal("ExprTail".to_string())],
         vec![],
     ]);
     grammar.insert("Term".to_string(), vec![
         vec![GrammarRule::NonTerminal("Factor".to_string()), GrammarRule::NonTerminal("TermTail".to_string())],
     ]);
     grammar.insert("TermTail".to_string(), vec![
-        vec![GrammarRule::Terminal("*".to_string()), GrammarRule::NonTerminal("Factor".to_string()), GrammarRule::NonTerminal("TermTail".to_string())],
-        vec![GrammarRule::Terminal("/".to_string()), GrammarRule::NonTerminal("Factor".to_string()), GrammarRule::NonTerminal("TermTail".to_string())],
+        vec![GrammarRule::Terminal(b"*".to_vec()), GrammarRule::NonTerminal("Factor".to_string()), GrammarRule::NonTerminal("TermTail".to_string())],
+        vec![GrammarRule::Terminal(b"/".to_vec()), GrammarRule::NonTerminal("Factor".to_string()), GrammarRule::NonTerminal("TermTail".to_string())],
         vec![],
     ]);
     grammar.insert("Factor".to_string(), vec![
-        vec![GrammarRule::Terminal("MAGIC-u8".to_string())],
-        vec![GrammarRule::Terminal("(".to_string()), GrammarRule::Terminal(")".to_string())],
+        vec![GrammarRule::Terminal(b"MAGIC-u8".to_vec())],
+        vec![GrammarRule::Terminal(b"(".to_vec()), GrammarRule::Terminal(b")".to_vec())],
     ]);
     grammar
 }
 
 /**
- * Helper function for grammar generation.
- * Defines special strings, which will be substituted in the generation phase.
- * Either a new created String will be returned, or None if the expression cannot be unfolded.
+ * Checks that the entry symbol is defined, every referenced nonterminal is defined
+ * and every nonterminal can derive a finite input.
+ * Returns a description of every problem found.
  */
-fn match_special_expression(expr: &str, rng: &mut impl Rng) -> Option<String> {
-    match expr {
-        "MAGIC-u8" => {
-            Some(rng.gen_range(00..u8::MAX).to_string())
-        },
-        "MAGIC-u16" => {
-            Some(rng.gen_range(00..u16::MAX).to_string())
-        },
-        "MAGIC-u32" => {
-            Some(rng.gen_range(00..u32::MAX).to_string())
-        },
-        "MAGIC-AsciiDigit" => {
-            Some( (rng.gen_range(0..10) as u8 + b'0').to_string() )
-        },
-        "MAGIC-LowerCaseLetter" => {
-            Some(((rng.gen_range(0..26) as u8 + b'a') as char).to_string() )
-        },
-        "MAGIC-HexDigit" => {
-            let mut num = rng.gen_range(0..16) as u8;
-            if num > 10 {
-                num = (num - 10) + b'A';
-            } else {
-                num = num + b'0';
+pub fn validate_grammar(grammar: &Grammar) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    if !grammar.contains_key(GRAMMAR_ENTRY) {
+        errors.push(format!("missing entry rule <{}>", GRAMMAR_ENTRY));
+    }
+    let mut symbols: Vec<&String> = grammar.keys().collect();
+    symbols.sort();
+    for symbol in &symbols {
+        for rule in grammar[*symbol].iter().flatten() {
+            if let GrammarRule::NonTerminal(nt) = rule {
+                if !grammar.contains_key(nt) {
+                    errors.push(format!("undefined nonterminal <{}> used in <{}>", nt, symbol));
+                }
             }
-            Some((num as char).to_string())
-        },
-        _ => None
+        }
     }
+    let shortest = shortest_derivations(grammar);
+    for symbol in symbols {
+        if !shortest.contains_key(symbol) {
+            errors.push(format!("nonterminal <{}> cannot derive a finite input", symbol));
+        }
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
 }
 
 /**
  * Generates an input from the given grammar
  */
-pub fn generate(grammar: &Grammar, symbol: &str, rng: &mut impl Rng) -> String {
-    //println!("Start generating");
+#[allow(unused)]
+pub fn generate(grammar: &Grammar, terminals: &TerminalRegistry, symbol: &str, rng: &mut impl Rng) -> Vec<u8> {
+    generate_tree(grammar, terminals, symbol, rng).to_bytes()
+}
+
+/**
+ * Expands a single terminal into a leaf of the derivation tree.
+ * Terminals registered in `terminals`, like MAGIC-u8, are substituted with generated values.
+ */
+pub fn generate_terminal(terminal: &[u8], terminals: &TerminalRegistry, context: &mut GenerationContext, rng: &mut impl Rng) -> DerivationTree {
+    let value = terminals.expand_with_context(terminal, context, rng);
+    DerivationTree::Terminal { symbol: terminal_name(terminal), value }
+}
+
+/**
+ * Expands a single rule element into a subtree of the derivation tree.
+ */
+pub fn generate_element(grammar: &Grammar, terminals: &TerminalRegistry, context: &mut GenerationContext, rule: &GrammarRule, rng: &mut impl Rng) -> DerivationTree {
+    match rule {
+        GrammarRule::Terminal(t) => generate_terminal(t, terminals, context, rng),
+        GrammarRule::NonTerminal(nt) => generate_tree_with_context(grammar, terminals, context, nt, rng),
+        GrammarRule::Regex(regex) => DerivationTree::Terminal { symbol: regex.name(), value: regex.generate(rng) },
+    }
+}
+
+/**
+ * Generates a derivation tree from the given grammar.
+ * Symbols without rules in the grammar are treated as leaves that expand to nothing.
+ */
+pub fn generate_tree(grammar: &Grammar, terminals: &TerminalRegistry, symbol: &str, rng: &mut impl Rng) -> DerivationTree {
+    generate_tree_with_context(grammar, terminals, &mut GenerationContext::new(), symbol, rng)
+}
+
+/**
+ * Generates a derivation tree like generate_tree(), terminals share `context`
+ * with everything generated before, left to right.
+ */
+pub fn generate_tree_with_context(grammar: &Grammar, terminals: &TerminalRegistry, context: &mut GenerationContext,
+    symbol: &str, rng: &mut impl Rng) -> DerivationTree {
     if let Some(rules) = grammar.get(symbol) {
         // Choose a random rule
-        let rule = rules.choose(rng).unwrap();
-        //println!("Chose rule: {:?}", rule);
-        rule.iter()
-            .map(|r| match r {
-                GrammarRule::Terminal(ref t) => {
-                    // Replace NUMBER with a random number for example
-                    let s = match_special_expression(t, rng);
-                    match s {
-                        Some(s) => {
-                            s
-                        },
-                        None => {
-                            t.clone()
-                        }
-                    }
-
-                }
-                GrammarRule::NonTerminal(ref nt) => generate(grammar, nt, rng),
-            })
-            .collect::<Vec<String>>()
-            .join("")
+        let alternative = rng.gen_range(0..rules.len());
+        let children = rules[alternative].iter()
+            .map(|r| generate_element(grammar, terminals, context, r, rng))
+            .collect();
+        DerivationTree::NonTerminal { symbol: symbol.to_string(), alternative, children }
     } else {
-        String::new()
+        DerivationTree::Terminal { symbol: symbol.to_string(), value: Vec::new() }
     }
 }
 
-mod tests {
+pub(crate) mod tests {
+    #[allow(unused_imports)]
+    use crate::grammar::{generate, generate_tree, terminal_name, validate_grammar, Grammar, GrammarRule};
     #[allow(unused_imports)]
-    use crate::grammar::generate;
+    use crate::derivation::DerivationTree;
+    #[allow(unused_imports)]
+    use crate::terminals::TerminalRegistry;
     #[allow(unused_imports)]
     use super::{create_cgi_grammar, create_grammar_calculation};    
 
@@ -164,25 +220,78 @@ mod tests {
     fn test_grammar_calculation() {
         let mut rng = rand::thread_rng();
         let grammar = create_grammar_calculation();
+        let terminals = TerminalRegistry::init();
         let output_count = 10; // Number of outputs to generate
     
         for _ in 0..output_count {
-            let output = generate(&grammar, "Start", &mut rng);
+            let output = generate(&grammar, &terminals, "Start", &mut rng);
             assert!(!output.is_empty());
-            println!("{}", output);
+            println!("{}", String::from_utf8_lossy(&output));
         }    
     }
 
+    #[test]
+    fn test_generate_tree_matches_alternatives() {
+        let mut rng = rand::thread_rng();
+        let grammar = create_cgi_grammar();
+        let terminals = TerminalRegistry::init();
+        for _ in 0..50 {
+            let tree = generate_tree(&grammar, &terminals, "Start", &mut rng);
+            assert_eq!(tree.symbol(), "Start");
+            assert!(!tree.to_bytes().is_empty());
+            assert_tree_follows_grammar(&grammar, &tree);
+        }
+    }
+
+    /**
+     * Asserts that every node of `tree` matches the alternative of the grammar
+     * it records.
+     */
+    #[cfg(test)]
+    pub(crate) fn assert_tree_follows_grammar(grammar: &Grammar, tree: &DerivationTree) {
+        if let DerivationTree::NonTerminal { symbol, alternative, children } = tree {
+            let rule = &grammar[symbol][*alternative];
+            assert_eq!(rule.len(), children.len());
+            for (r, child) in rule.iter().zip(children) {
+                match r {
+                    GrammarRule::Terminal(t) => assert_eq!(terminal_name(t), child.symbol()),
+                    GrammarRule::NonTerminal(nt) => {
+                        assert_eq!(nt, child.symbol());
+                        assert_tree_follows_grammar(grammar, child);
+                    }
+                    GrammarRule::Regex(regex) => assert_eq!(regex.name(), child.symbol()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_grammar() {
+        assert!(validate_grammar(&create_cgi_grammar()).is_ok());
+        assert!(validate_grammar(&create_grammar_calculation()).is_ok());
+
+        let mut grammar = create_cgi_grammar();
+        grammar.remove("Plus");
+        // A -> A never terminates
+        grammar.insert("Loop".to_string(), vec![vec![GrammarRule::NonTerminal("Loop".to_string())]]);
+        let errors = validate_grammar(&grammar).unwrap_err();
+        assert_eq!(errors, vec![
+            "undefined nonterminal <Plus> used in <Letter>".to_string(),
+            "nonterminal <Loop> cannot derive a finite input".to_string(),
+        ]);
+    }
+
     #[test]
     fn test_cgi_grammar() {
         let mut rng = rand::thread_rng();
         let grammar = create_cgi_grammar();
+        let terminals = TerminalRegistry::init();
         let output_count = 100; // Number of outputs to generate
     
         for _ in 0..output_count {
-            let output = generate(&grammar, "Start", &mut rng);
+            let output = generate(&grammar, &terminals, "Start", &mut rng);
             assert!(!output.is_empty());
-            println!("{}", output);
+            println!("{}", String::from_utf8_lossy(&output));
         }   
     }
 
@@ -0,0 +1,256 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+
+use crate::derivation::DerivationTree;
+use crate::generation_context::GenerationContext;
+use crate::grammar::{generate_element, generate_tree_with_context, Grammar, GrammarRule, GRAMMAR_ENTRY};
+use crate::terminals::TerminalRegistry;
+
+/*
+ * Default length of the expansion paths tracked by GrammarCoverage.
+ */
+pub const DEFAULT_COVERAGE_K: usize = 2;
+
+/*
+ * Use of one alternative of a nonterminal, (symbol, alternative).
+ */
+type Expansion = (String, usize);
+
+/**
+ * Tracks which parts of a grammar have been exercised by generated inputs.
+ * An expansion is the use of one alternative of a nonterminal, a k-path is a
+ * chain of k expansions where each one derives the next, e.g. for k = 2
+ * (String, 1) -> (Letter, 0). Coverage is relative to the expansions and
+ * k-paths reachable from the entry symbol.
+ */
+#[derive(Debug, Clone)]
+pub struct GrammarCoverage {
+    pub k: usize,
+    expansions: HashSet<Expansion>,
+    paths: HashSet<Vec<Expansion>>,
+    total_expansions: usize,
+    total_paths: usize,
+}
+
+#[allow(unused)]
+impl GrammarCoverage {
+    pub fn init(grammar: &Grammar, k: usize) -> GrammarCoverage {
+        assert!(k >= 1);
+        let reachable = reachable_expansions(grammar);
+        // chains[e] is the number of paths of the current length starting with e
+        let mut chains: HashMap<&Expansion, usize> = reachable.iter().map(|e| (e, 1)).collect();
+        for _ in 1..k {
+            chains = reachable.iter()
+                .map(|e| {
+                    let count = successors(grammar, e).iter()
+                        .map(|next| chains.get(next).copied().unwrap_or(0))
+                        .fold(0usize, |sum, c| sum.saturating_add(c));
+                    (e, count)
+                })
+                .collect();
+        }
+        GrammarCoverage {
+            k,
+            expansions: HashSet::new(),
+            paths: HashSet::new(),
+            total_expansions: reachable.len(),
+            total_paths: chains.values().fold(0usize, |sum, &c| sum.saturating_add(c)),
+        }
+    }
+
+    /**
+     * Marks all expansions and k-paths of `tree` as covered.
+     */
+    pub fn record(&mut self, tree: &DerivationTree) {
+        self.record_node(tree, &mut Vec::new());
+    }
+
+    fn record_node(&mut self, tree: &DerivationTree, ancestors: &mut Vec<Expansion>) {
+        if let DerivationTree::NonTerminal { symbol, alternative, children } = tree {
+            ancestors.push((symbol.clone(), *alternative));
+            self.cover(ancestors);
+            for child in children {
+                self.record_node(child, ancestors);
+            }
+            ancestors.pop();
+        }
+    }
+
+    /*
+     * Covers the last expansion of `chain` and the k-path ending with it.
+     */
+    fn cover(&mut self, chain: &[Expansion]) {
+        self.expansions.insert(chain[chain.len() - 1].clone());
+        if chain.len() >= self.k {
+            self.paths.insert(chain[chain.len() - self.k..].to_vec());
+        }
+    }
+
+    /*
+     * Whether using `expansion` below `ancestors` would cover anything new.
+     */
+    fn is_new(&self, ancestors: &[Expansion], expansion: &Expansion) -> bool {
+        if !self.expansions.contains(expansion) {
+            return true;
+        }
+        if ancestors.len() + 1 < self.k {
+            return false;
+        }
+        let mut path = ancestors[ancestors.len() + 1 - self.k..].to_vec();
+        path.push(expansion.clone());
+        !self.paths.contains(&path)
+    }
+
+    /**
+     * Number of covered and of reachable expansions.
+     */
+    pub fn expansion_coverage(&self) -> (usize, usize) {
+        (self.expansions.len(), self.total_expansions)
+    }
+
+    /**
+     * Number of covered and of reachable k-paths.
+     */
+    pub fn path_coverage(&self) -> (usize, usize) {
+        (self.paths.len(), self.total_paths)
+    }
+
+    /**
+     * Whether every reachable k-path was generated, more inputs only repeat known structure.
+     */
+    pub fn is_exhausted(&self) -> bool {
+        self.paths.len() >= self.total_paths && self.expansions.len() >= self.total_expansions
+    }
+
+    pub fn report(&self) -> String {
+        let percent = |(covered, total): (usize, usize)| if total == 0 { 100.0 } else { 100.0 * covered as f64 / total as f64 };
+        format!("expansions {}/{} ({:.1}%), {}-paths {}/{} ({:.1}%){}",
+            self.expansions.len(), self.total_expansions, percent(self.expansion_coverage()),
+            self.k, self.paths.len(), self.total_paths, percent(self.path_coverage()),
+            if self.is_exhausted() { ", grammar exhausted" } else { "" })
+    }
+}
+
+/*
+ * Expansions of the nonterminals that `expansion` refers to.
+ */
+fn successors(grammar: &Grammar, (symbol, alternative): &Expansion) -> Vec<Expansion> {
+    let mut symbols: Vec<&String> = grammar[symbol][*alternative].iter()
+        .filter_map(|r| match r {
+            GrammarRule::NonTerminal(nt) if grammar.contains_key(nt) => Some(nt),
+            _ => None,
+        })
+        .collect();
+    symbols.sort();
+    symbols.dedup();
+    symbols.into_iter()
+        .flat_map(|nt| (0..grammar[nt].len()).map(move |a| (nt.clone(), a)))
+        .collect()
+}
+
+fn reachable_expansions(grammar: &Grammar) -> Vec<Expansion> {
+    let Some(rules) = grammar.get(GRAMMAR_ENTRY) else {
+        return Vec::new();
+    };
+    let mut reachable: Vec<Expansion> = (0..rules.len()).map(|a| (GRAMMAR_ENTRY.to_string(), a)).collect();
+    let mut seen: HashSet<Expansion> = reachable.iter().cloned().collect();
+    let mut i = 0;
+    while i < reachable.len() {
+        for next in successors(grammar, &reachable[i]) {
+            if seen.insert(next.clone()) {
+                reachable.push(next);
+            }
+        }
+        i += 1;
+    }
+    reachable
+}
+
+/**
+ * Generates a derivation tree like generate_tree(), but prefers alternatives
+ * whose expansion or k-path is not covered yet. Everything generated is
+ * recorded in `coverage` right away, so recursive alternatives are only
+ * preferred until they were used once at that position.
+ */
+pub fn generate_tree_guided(grammar: &Grammar, terminals: &TerminalRegistry, coverage: &mut GrammarCoverage,
+    symbol: &str, rng: &mut impl Rng) -> DerivationTree {
+    generate_guided(grammar, terminals, coverage, &mut GenerationContext::new(), symbol, &mut Vec::new(), rng)
+}
+
+fn generate_guided(grammar: &Grammar, terminals: &TerminalRegistry, coverage: &mut GrammarCoverage, context: &mut GenerationContext,
+    symbol: &str, ancestors: &mut Vec<Expansion>, rng: &mut impl Rng) -> DerivationTree {
+    let Some(rules) = grammar.get(symbol) else {
+        return generate_tree_with_context(grammar, terminals, context, symbol, rng);
+    };
+    let uncovered: Vec<usize> = (0..rules.len())
+        .filter(|&a| coverage.is_new(ancestors, &(symbol.to_string(), a)))
+        .collect();
+    let alternative = match uncovered.choose(rng) {
+        Some(&a) => a,
+        None => rng.gen_range(0..rules.len()),
+    };
+    ancestors.push((symbol.to_string(), alternative));
+    coverage.cover(ancestors);
+    let children = rules[alternative].iter()
+        .map(|r| match r {
+            GrammarRule::NonTerminal(nt) => generate_guided(grammar, terminals, coverage, context, nt, ancestors, rng),
+            r => generate_element(grammar, terminals, context, r, rng),
+        })
+        .collect();
+    ancestors.pop();
+    DerivationTree::NonTerminal { symbol: symbol.to_string(), alternative, children }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::grammar::{create_cgi_grammar, create_grammar_calculation, generate_tree};
+
+    #[test]
+    fn test_totals() {
+        let grammar = create_cgi_grammar();
+        // Start 1, String 2, Letter 3, Plus 1, Percent 1, Other 3
+        let coverage = GrammarCoverage::init(&grammar, 1);
+        assert_eq!(coverage.expansion_coverage(), (0, 11));
+        assert_eq!(coverage.path_coverage(), (0, 11));
+        // paths starting at Start: 2, String0: 3, String1: 3 + 2, Letter0-2: 1 + 1 + 3
+        let coverage = GrammarCoverage::init(&grammar, 2);
+        assert_eq!(coverage.path_coverage().1, 2 + 3 + 5 + 5);
+    }
+
+    #[test]
+    fn test_guided_generation_exhausts_grammar() {
+        let grammar = create_cgi_grammar();
+        let terminals = TerminalRegistry::init();
+        let mut guided = GrammarCoverage::init(&grammar, 2);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10 {
+            generate_tree_guided(&grammar, &terminals, &mut guided, GRAMMAR_ENTRY, &mut rng);
+        }
+        assert!(guided.is_exhausted(), "{}", guided.report());
+
+        let mut random = GrammarCoverage::init(&grammar, 2);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10 {
+            random.record(&generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng));
+        }
+        assert!(random.path_coverage().0 <= guided.path_coverage().0);
+    }
+
+    #[test]
+    fn test_record_matches_generated_tree() {
+        let grammar = create_grammar_calculation();
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut guided = GrammarCoverage::init(&grammar, 3);
+        let tree = generate_tree_guided(&grammar, &terminals, &mut guided, GRAMMAR_ENTRY, &mut rng);
+        let mut recorded = GrammarCoverage::init(&grammar, 3);
+        recorded.record(&tree);
+        assert_eq!(recorded.expansion_coverage(), guided.expansion_coverage());
+        assert_eq!(recorded.path_coverage(), guided.path_coverage());
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::derivation::DerivationTree;
+use crate::ebnf::{EbnfGrammar, EbnfRule};
+use crate::grammar::{terminal_name, Grammar, GrammarRule, GRAMMAR_ENTRY};
+use crate::grammar_file::format_ebnf_grammar;
+
+/*
+ * Defined symbols with the entry symbol first and the others sorted.
+ */
+fn sorted_symbols(grammar: &Grammar) -> Vec<&String> {
+    let mut symbols: Vec<&String> = grammar.keys().collect();
+    symbols.sort_by_key(|s| (s.as_str() != GRAMMAR_ENTRY, s.as_str()));
+    symbols
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/*
+ * Nonterminals referenced by the rules of `symbol`.
+ */
+fn referenced(grammar: &Grammar, symbol: &str) -> Vec<String> {
+    grammar.get(symbol).into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|r| match r {
+            GrammarRule::NonTerminal(nt) => Some(nt.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/*
+ * Symbols reachable from `symbol` in one or more steps.
+ */
+fn reachable(grammar: &Grammar, symbol: &str) -> HashSet<String> {
+    let mut seen = HashSet::new();
+    let mut stack = referenced(grammar, symbol);
+    while let Some(next) = stack.pop() {
+        if seen.insert(next.clone()) {
+            stack.extend(referenced(grammar, &next));
+        }
+    }
+    seen
+}
+
+/**
+ * Renders a grammar as Graphviz DOT. Nonterminals are ellipses, terminals,
+ * generators and regexes boxes. An edge leads from a symbol to every element
+ * of its alternatives and is labeled with the alternative numbers. Edges which
+ * are part of a cycle, i.e. recursion, are red. Referenced symbols without
+ * rules are dashed.
+ */
+pub fn grammar_to_dot(grammar: &Grammar) -> String {
+    let symbols = sorted_symbols(grammar);
+    let reachability: HashMap<&String, HashSet<String>> = symbols.iter().map(|&s| (s, reachable(grammar, s))).collect();
+    let mut ids: HashMap<String, String> = HashMap::new();
+    let mut nodes = String::new();
+    let mut edges = String::new();
+    for (index, symbol) in symbols.iter().enumerate() {
+        let style = if symbol.as_str() == GRAMMAR_ENTRY { ", style=bold" } else { "" };
+        nodes.push_str(&format!("    n{} [label=\"{}\"{}];\n", index, dot_escape(symbol), style));
+        ids.insert(format!("<{}>", symbol), format!("n{}", index));
+    }
+    for (index, symbol) in symbols.iter().enumerate() {
+        // targets in order of first use, with the alternatives using them
+        let mut targets: Vec<(String, bool, Vec<usize>)> = Vec::new();
+        for (alternative, rule) in grammar[*symbol].iter().enumerate() {
+            for element in rule {
+                let (key, cyclic) = match element {
+                    GrammarRule::NonTerminal(nt) => (format!("<{}>", nt), reachability.get(nt).is_some_and(|r| r.contains(*symbol))),
+                    GrammarRule::Terminal(t) => (format!("\"{}\"", terminal_name(t)), false),
+                    r => (r.name(), false),
+                };
+                match targets.iter_mut().find(|(k, _, _)| *k == key) {
+                    Some((_, _, alternatives)) if alternatives.last() == Some(&alternative) => {},
+                    Some((_, _, alternatives)) => alternatives.push(alternative),
+                    None => targets.push((key, cyclic, vec![alternative])),
+                }
+            }
+        }
+        for (key, cyclic, alternatives) in targets {
+            if !ids.contains_key(&key) {
+                let id = format!("t{}", ids.len() - symbols.len());
+                let label = match key.strip_prefix('<').and_then(|k| k.strip_suffix('>')) {
+                    Some(undefined) => format!("    {} [label=\"{}\", style=dashed];\n", id, dot_escape(undefined)),
+                    None => format!("    {} [label=\"{}\", shape=box];\n", id, dot_escape(&key)),
+                };
+                nodes.push_str(&label);
+                ids.insert(key.clone(), id);
+            }
+            let label: Vec<String> = alternatives.iter().map(|a| a.to_string()).collect();
+            let color = if cyclic { ", color=red" } else { "" };
+            edges.push_str(&format!("    n{} -> {} [label=\"{}\"{}];\n", index, ids[&key], label.join(","), color));
+        }
+    }
+    format!("digraph grammar {{\n{}{}}}\n", nodes, edges)
+}
+
+/**
+ * Pretty-prints a grammar in the grammar file syntax, one alternative per line.
+ * Symbols created by convert_ebnf() keep their EBNF names like `<[a-z]+>`.
+ */
+pub fn grammar_to_bnf(grammar: &Grammar) -> String {
+    let ebnf: EbnfGrammar = grammar.iter()
+        .map(|(symbol, alternatives)| {
+            let alternatives = alternatives.iter()
+                .map(|rule| rule.iter().map(|r| match r {
+                    GrammarRule::Terminal(t) => EbnfRule::Terminal(t.clone()),
+                    GrammarRule::NonTerminal(nt) => EbnfRule::NonTerminal(nt.clone()),
+                    GrammarRule::Regex(regex) => EbnfRule::Regex(regex.pattern().to_string()),
+                }).collect())
+                .collect();
+            (symbol.clone(), alternatives)
+        })
+        .collect();
+    format_ebnf_grammar(&ebnf)
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' || ('\x7f'..='\u{ff}').contains(&c) => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/**
+ * Renders a grammar as JSON: an object mapping every symbol to its list of
+ * alternatives, each a list of elements like {"terminal": "a"},
+ * {"nonterminal": "Letter"} or {"regex": "[0-9]+"}. Terminal bytes are written
+ * as the code points U+0000 to U+00FF.
+ */
+pub fn grammar_to_json(grammar: &Grammar) -> String {
+    let rules: Vec<String> = sorted_symbols(grammar).into_iter()
+        .map(|symbol| {
+            let alternatives: Vec<String> = grammar[symbol].iter()
+                .map(|rule| {
+                    let elements: Vec<String> = rule.iter().map(|r| match r {
+                        GrammarRule::Terminal(t) => {
+                            let text: String = t.iter().map(|&b| b as char).collect();
+                            format!("{{\"terminal\": {}}}", json_string(&text))
+                        },
+                        GrammarRule::NonTerminal(nt) => format!("{{\"nonterminal\": {}}}", json_string(nt)),
+                        GrammarRule::Regex(regex) => format!("{{\"regex\": {}}}", json_string(regex.pattern())),
+                    }).collect();
+                    format!("    [{}]", elements.join(", "))
+                })
+                .collect();
+            format!("  {}: [\n{}\n  ]", json_string(symbol), alternatives.join(",\n"))
+        })
+        .collect();
+    format!("{{\n{}\n}}\n", rules.join(",\n"))
+}
+
+/**
+ * Renders a derivation tree as Graphviz DOT. Nonterminals are labeled with
+ * their symbol and the alternative used, terminals with their value and,
+ * for generators and regexes, the symbol they were generated from.
+ */
+pub fn tree_to_dot(tree: &DerivationTree) -> String {
+    let mut lines = Vec::new();
+    tree_nodes(tree, &mut 0, &mut lines);
+    format!("digraph tree {{\n{}}}\n", lines.concat())
+}
+
+fn tree_nodes(tree: &DerivationTree, next_id: &mut usize, lines: &mut Vec<String>) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    match tree {
+        DerivationTree::Terminal { symbol, value } => {
+            let name = terminal_name(value);
+            let label = if *symbol == name { format!("\"{}\"", name) } else { format!("{}\n\"{}\"", symbol, name) };
+            lines.push(format!("    n{} [label=\"{}\", shape=box];\n", id, dot_escape(&label).replace('\n', "\\n")));
+        },
+        DerivationTree::NonTerminal { symbol, alternative, children } => {
+            lines.push(format!("    n{} [label=\"{} ({})\"];\n", id, dot_escape(symbol), alternative));
+            for child in children {
+                let child_id = tree_nodes(child, next_id, lines);
+                lines.push(format!("    n{} -> n{};\n", id, child_id));
+            }
+        },
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::ebnf::LengthBounds;
+    use crate::grammar::{create_cgi_grammar, generate_tree};
+    use crate::grammar_file::parse_grammar;
+    use crate::terminals::TerminalRegistry;
+
+    #[test]
+    fn test_grammar_to_dot_marks_cycles() {
+        let dot = grammar_to_dot(&create_cgi_grammar());
+        assert!(dot.starts_with("digraph grammar {\n    n0 [label=\"Start\", style=bold];\n"));
+        // <String> ::= <Letter> | <Letter> <String>
+        let string = dot.lines().position(|l| l.contains("label=\"String\"")).unwrap();
+        assert!(dot.contains(&format!("n{0} -> n{0} [label=\"1\", color=red];", string - 1)));
+        assert!(dot.contains("[label=\"0,1\"];"));
+        assert!(dot.contains("[label=\"\\\"+\\\"\", shape=box];"));
+        assert_eq!(dot.matches("color=red").count(), 1);
+    }
+
+    #[test]
+    fn test_grammar_to_bnf_and_json() {
+        let text = "<Start> ::= \"a\\\"\\x01\" <Digits> /[0-9]+/\n<Digits> ::= \"\" | \"MAGIC-u8\"\n";
+        let grammar = parse_grammar(text, &LengthBounds::default()).unwrap().0;
+        let bnf = grammar_to_bnf(&grammar);
+        assert_eq!(bnf, "<Start>  ::= \"a\\\"\\x01\" <Digits> /[0-9]+/\n<Digits> ::= \"\"\n           | \"MAGIC-u8\"\n");
+        assert_eq!(parse_grammar(&bnf, &LengthBounds::default()).unwrap().0, grammar);
+        assert_eq!(grammar_to_json(&grammar), concat!(
+            "{\n",
+            "  \"Start\": [\n    [{\"terminal\": \"a\\\"\\u0001\"}, {\"nonterminal\": \"Digits\"}, {\"regex\": \"[0-9]+\"}]\n  ],\n",
+            "  \"Digits\": [\n    [],\n    [{\"terminal\": \"MAGIC-u8\"}]\n  ]\n",
+            "}\n"));
+    }
+
+    #[test]
+    fn test_tree_to_dot() {
+        let grammar = create_cgi_grammar();
+        let tree = generate_tree(&grammar, &TerminalRegistry::init(), GRAMMAR_ENTRY, &mut StdRng::seed_from_u64(0));
+        let dot = tree_to_dot(&tree);
+        assert_eq!(dot.matches("shape=box").count(), tree.paths().len() - tree.nonterminal_paths(None).len());
+        assert_eq!(dot.matches(" -> ").count(), tree.size() - 1);
+        assert!(dot.contains("n0 [label=\"Start (0)\"];"));
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars as Chars_;
+
+use crate::constraints::{Constraint, ConstraintKind, ConstraintSet, FieldEncoding};
+use crate::ebnf::{convert_ebnf, EbnfGrammar, EbnfRule, LengthBounds};
+use crate::grammar::{Grammar, GRAMMAR_ENTRY};
+use crate::regex_terminal::RegexTerminal;
+
+/*
+ * Grammar files use an EBNF-like syntax, one rule per line:
+ *
+ *   # comment
+ *   <Start>   ::= <Header> <Payload>
+ *   <Header>  ::= "\xAA\x55" "MAGIC-u16le"
+ *   <Payload> ::= "" | "MAGIC-Byte" <Payload>
+ *             | "\x00" ( <Name> "=" [0-9a-f]{2} )*
+ *
+ * Nonterminals are written in angle brackets, terminals in double quotes.
+ * Terminals may contain the escapes \xHH, \n, \r, \t, \0, \\, \" and \'.
+ * A quoted generator name like "MAGIC-u8" is expanded by the TerminalRegistry.
+ * [a-z_] and [^\x00] are character classes, ( a | b ) groups alternatives and
+ * ?, *, +, {m}, {m,} and {m,n} repeat the element in front of them.
+ * /[0-9A-F]{2}/ is a token generated from a regular expression, '/' inside
+ * the regex is written as \/.
+ * Lines starting with '|' add alternatives to the rule of the previous line.
+ *
+ * Annotations at the end of a line add constraints to the last alternative
+ * on that line, elements are counted from 0:
+ *
+ *   <Start> ::= "\xAA" "MAGIC-u16le" <Payload> "MAGIC-u32be" @length(1, 2, u16le) @crc32(3, 0, 1, 2, u32be)
+ *
+ * @length, @crc16, @crc32 and @sum take the target element, the source elements
+ * and the encoding: u8, u16le, u16be, ... u64be or decimal. @equal(target, source)
+ * repeats the source. The target must be a quoted terminal or a regex.
+ */
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct GrammarFileError {
+    /* 1-based line number, 0 if the error is not tied to a line */
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for GrammarFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for GrammarFileError {}
+
+/**
+ * Reads and parses a grammar file, with the constraints of its annotations.
+ */
+pub fn load_grammar(path: &Path, bounds: &LengthBounds) -> Result<(Grammar, ConstraintSet), GrammarFileError> {
+    let text = fs::read_to_string(path).map_err(|e| GrammarFileError {
+        line: 0,
+        message: format!("cannot read {}: {}", path.display(), e),
+    })?;
+    parse_grammar(&text, bounds)
+}
+
+/**
+ * Parses the text of a grammar file and converts its EBNF operators into a plain grammar.
+ * The grammar must define the entry symbol and every referenced nonterminal.
+ * Conversion keeps the alternatives and their elements in place, so the
+ * constraints of the annotations refer to the plain grammar as well.
+ */
+pub fn parse_grammar(text: &str, bounds: &LengthBounds) -> Result<(Grammar, ConstraintSet), GrammarFileError> {
+    let (grammar, constraints) = parse_rules(text)?;
+    let grammar = convert_ebnf(&grammar, bounds).map_err(|errors| GrammarFileError { line: 0, message: errors.join("; ") })?;
+    Ok((grammar, constraints))
+}
+
+/**
+ * Parses the text of a grammar file, keeping the EBNF operators. Annotations are checked but dropped.
+ */
+#[allow(unused)]
+pub fn parse_ebnf_grammar(text: &str) -> Result<EbnfGrammar, GrammarFileError> {
+    parse_rules(text).map(|(grammar, _)| grammar)
+}
+
+fn parse_rules(text: &str) -> Result<(EbnfGrammar, ConstraintSet), GrammarFileError> {
+    let mut grammar: EbnfGrammar = HashMap::new();
+    let mut constraints = ConstraintSet::empty();
+    // line where each nonterminal is first referenced, to report undefined ones
+    let mut references: Vec<(String, usize)> = Vec::new();
+    let mut current: Option<String> = None;
+
+    for (index, raw_line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: String| GrammarFileError { line: line_number, message };
+        let line = raw_line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (symbol, body) = if let Some(rest) = line.strip_prefix('|') {
+            match &current {
+                Some(symbol) => (symbol.clone(), rest),
+                None => return Err(error("'|' without a preceding rule".to_string())),
+            }
+        } else {
+            let Some((head, body)) = line.split_once("::=") else {
+                return Err(error("expected '<Symbol> ::= ...'".to_string()));
+            };
+            let head = head.trim();
+            let symbol = head.strip_prefix('<').and_then(|h| h.strip_suffix('>'))
+                .filter(|s| !s.is_empty())
+                .ok_or_else(|| error(format!("invalid rule name '{}'", head)))?;
+            (symbol.to_string(), body)
+        };
+
+        let mut chars = body.chars().peekable();
+        let alternatives = parse_alternatives(&mut chars, false).map_err(error)?;
+        let annotated = grammar.get(&symbol).map_or(0, Vec::len) + alternatives.len() - 1;
+        for constraint in parse_annotations(&mut chars, &symbol, annotated, alternatives.last().unwrap()).map_err(error)? {
+            constraints.add(constraint);
+        }
+        collect_references(&alternatives, line_number, &mut references);
+        grammar.entry(symbol.clone()).or_default().extend(alternatives);
+        current = Some(symbol);
+    }
+
+    if !grammar.contains_key(GRAMMAR_ENTRY) {
+        return Err(GrammarFileError { line: 0, message: format!("missing entry rule <{}>", GRAMMAR_ENTRY) });
+    }
+    if let Some((nt, line)) = references.into_iter().find(|(nt, _)| !grammar.contains_key(nt)) {
+        return Err(GrammarFileError { line, message: format!("undefined nonterminal <{}>", nt) });
+    }
+    Ok((grammar, constraints))
+}
+
+/**
+ * Writes an EBNF grammar in the grammar file syntax, so parse_ebnf_grammar()
+ * reads it back unchanged. The entry rule comes first, the others are sorted
+ * by name and every alternative is written on its own line.
+ */
+pub fn format_ebnf_grammar(grammar: &EbnfGrammar) -> String {
+    let mut symbols: Vec<&String> = grammar.keys().collect();
+    symbols.sort_by_key(|s| (s.as_str() != GRAMMAR_ENTRY, s.as_str()));
+    let width = symbols.iter().map(|s| s.len()).max().unwrap_or(0);
+    let mut text = String::new();
+    for symbol in symbols {
+        let head = format!("<{}>", symbol);
+        for (index, sequence) in grammar[symbol].iter().enumerate() {
+            let body = if sequence.is_empty() {
+                "\"\"".to_string()
+            } else {
+                sequence.iter().map(|r| r.to_text()).collect::<Vec<String>>().join(" ")
+            };
+            if index == 0 {
+                text.push_str(&format!("{:<w$} ::= {}\n", head, body, w = width + 2));
+            } else {
+                text.push_str(&format!("{:<w$}   | {}\n", "", body, w = width + 2));
+            }
+        }
+    }
+    text
+}
+
+fn collect_references(alternatives: &[Vec<EbnfRule>], line: usize, references: &mut Vec<(String, usize)>) {
+    for rule in alternatives.iter().flatten() {
+        let mut rule = rule;
+        while let EbnfRule::Repeat { rule: inner, .. } = rule {
+            rule = inner;
+        }
+        match rule {
+            EbnfRule::NonTerminal(nt) => references.push((nt.clone(), line)),
+            EbnfRule::Group(inner) => collect_references(inner, line, references),
+            _ => {},
+        }
+    }
+}
+
+type Chars<'t> = Peekable<Chars_<'t>>;
+
+/*
+ * Parses alternatives separated by '|' up to the end of the line or the first
+ * annotation, or up to the closing parenthesis if `in_group` is set.
+ */
+fn parse_alternatives(chars: &mut Chars, in_group: bool) -> Result<Vec<Vec<EbnfRule>>, String> {
+    let mut alternatives = vec![Vec::new()];
+    loop {
+        let c = match chars.peek() {
+            None | Some('#') if in_group => return Err("missing ')'".to_string()),
+            None | Some('#') => return Ok(alternatives),
+            Some('@') if !in_group => return Ok(alternatives),
+            Some(&c) => c,
+        };
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            },
+            '|' => {
+                chars.next();
+                alternatives.push(Vec::new());
+            },
+            ')' if in_group => {
+                chars.next();
+                return Ok(alternatives);
+            },
+            _ => {
+                // "" stands for the empty alternative
+                if let Some(element) = parse_element(chars)? {
+                    alternatives.last_mut().unwrap().push(element);
+                }
+            },
+        }
+    }
+}
+
+/*
+ * Parses the annotations at the end of a line into constraints on
+ * `alternative` of `symbol`, whose elements are `sequence`.
+ */
+fn parse_annotations(chars: &mut Chars, symbol: &str, alternative: usize, sequence: &[EbnfRule]) -> Result<Vec<Constraint>, String> {
+    let mut constraints = Vec::new();
+    loop {
+        match chars.next() {
+            None | Some('#') => return Ok(constraints),
+            Some(c) if c.is_whitespace() => {},
+            Some('@') => constraints.push(parse_annotation(chars, symbol, alternative, sequence)?),
+            Some(c) => return Err(format!("unexpected character '{}' after annotation", c)),
+        }
+    }
+}
+
+/*
+ * Parses an annotation like @length(1, 2, u16le) after the '@'.
+ */
+fn parse_annotation(chars: &mut Chars, symbol: &str, alternative: usize, sequence: &[EbnfRule]) -> Result<Constraint, String> {
+    let name = parse_until(chars, '(').ok_or_else(|| "annotation without arguments".to_string())?;
+    let kind: ConstraintKind = name.trim().parse()?;
+    let arguments = parse_until(chars, ')').ok_or_else(|| format!("unterminated annotation @{}", name))?;
+    let mut arguments: Vec<&str> = arguments.split(',').map(str::trim).collect();
+    let encoding = match kind {
+        ConstraintKind::Equal => FieldEncoding::Decimal,
+        _ => arguments.pop().unwrap().parse()?,
+    };
+    let indices = arguments.iter()
+        .map(|a| a.parse::<usize>().map_err(|_| format!("invalid element index '{}' in @{}", a, name)))
+        .collect::<Result<Vec<usize>, String>>()?;
+    let (target, sources) = match (kind, indices.split_first()) {
+        (ConstraintKind::Equal, Some((&target, sources))) if sources.len() == 1 => (target, sources),
+        (ConstraintKind::Equal, _) => return Err("@equal needs a target and one source".to_string()),
+        (_, Some((&target, sources))) if !sources.is_empty() => (target, sources),
+        _ => return Err(format!("@{} needs a target, sources and an encoding", name)),
+    };
+    match sequence.get(target) {
+        Some(EbnfRule::Terminal(_) | EbnfRule::Regex(_)) => {},
+        Some(rule) => return Err(format!("constraint target {} is no terminal or regex", rule.to_text())),
+        None => return Err(format!("constraint target {} out of range", target)),
+    }
+    if let Some(source) = sources.iter().find(|&&s| s >= sequence.len()) {
+        return Err(format!("constraint source {} out of range", source));
+    }
+    Ok(Constraint::init(symbol, alternative, target, sources.to_vec(), kind, encoding))
+}
+
+/*
+ * Parses an element with its postfix operators, returns None for "".
+ */
+fn parse_element(chars: &mut Chars) -> Result<Option<EbnfRule>, String> {
+    let mut element = match chars.next() {
+        Some('<') => {
+            let name = parse_until(chars, '>').ok_or_else(|| "unterminated nonterminal".to_string())?;
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(format!("invalid nonterminal <{}>", name));
+            }
+            EbnfRule::NonTerminal(name)
+        },
+        Some('"') => {
+            let terminal = parse_quoted(chars)?;
+            if terminal.is_empty() {
+                return Ok(None);
+            }
+            EbnfRule::Terminal(terminal)
+        },
+        Some('[') => EbnfRule::CharClass(parse_class(chars)?),
+        Some('(') => EbnfRule::Group(parse_alternatives(chars, true)?),
+        Some('/') => EbnfRule::Regex(parse_regex(chars)?),
+        Some(c) => return Err(format!("unexpected character '{}'", c)),
+        None => return Err("unexpected end of line".to_string()),
+    };
+    loop {
+        let (min, max) = match chars.peek() {
+            Some('?') => (0, Some(1)),
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('{') => {
+                chars.next();
+                let bounds = parse_until(chars, '}').ok_or_else(|| "unterminated repetition".to_string())?;
+                let parse = |n: &str| n.trim().parse::<usize>().map_err(|_| format!("invalid repetition {{{}}}", bounds));
+                let (min, max) = match bounds.split_once(',') {
+                    None => (parse(&bounds)?, Some(parse(&bounds)?)),
+                    Some((min, max)) if max.trim().is_empty() => (parse(min)?, None),
+                    Some((min, max)) => (parse(min)?, Some(parse(max)?)),
+                };
+                element = EbnfRule::repeat(element, min, max);
+                continue;
+            },
+            _ => return Ok(Some(element)),
+        };
+        chars.next();
+        element = EbnfRule::repeat(element, min, max);
+    }
+}
+
+/*
+ * Text up to the closing `end`, which is consumed. None if the line ends before it.
+ */
+fn parse_until(chars: &mut Chars, end: char) -> Option<String> {
+    let mut text = String::new();
+    for c in chars.by_ref() {
+        if c == end {
+            return Some(text);
+        }
+        text.push(c);
+    }
+    None
+}
+
+/*
+ * Parses an escape sequence after the backslash.
+ */
+fn parse_escape(chars: &mut Chars) -> Result<u8, String> {
+    match chars.next() {
+        Some('x') => {
+            let hex: String = chars.by_ref().take(2).collect();
+            u8::from_str_radix(&hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 2)
+                .ok_or_else(|| format!("invalid hex escape '\\x{}'", hex))
+        },
+        Some('n') => Ok(b'\n'),
+        Some('r') => Ok(b'\r'),
+        Some('t') => Ok(b'\t'),
+        Some('0') => Ok(0),
+        Some(c @ ('\\' | '"' | '\'' | ']' | '-' | '^')) => Ok(c as u8),
+        Some(c) => Err(format!("unknown escape '\\{}'", c)),
+        None => Err("unterminated escape".to_string()),
+    }
+}
+
+/*
+ * Parses a quoted terminal after the opening quote and returns its bytes.
+ */
+fn parse_quoted(chars: &mut Chars) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    loop {
+        match chars.next() {
+            None => return Err("unterminated string".to_string()),
+            Some('"') => return Ok(bytes),
+            Some('\\') => bytes.push(parse_escape(chars)?),
+            Some(c) => {
+                let mut buffer = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            },
+        }
+    }
+}
+
+/*
+ * Parses a regex after the opening slash and checks that it compiles.
+ */
+fn parse_regex(chars: &mut Chars) -> Result<String, String> {
+    let mut pattern = String::new();
+    loop {
+        match chars.next() {
+            None => return Err("unterminated regex".to_string()),
+            Some('/') => break,
+            Some('\\') if chars.next_if_eq(&'/').is_some() => pattern.push('/'),
+            Some('\\') => {
+                // other escapes belong to the regex syntax
+                pattern.push('\\');
+                pattern.extend(chars.next());
+            },
+            Some(c) => pattern.push(c),
+        }
+    }
+    RegexTerminal::init(&pattern)?;
+    Ok(pattern)
+}
+
+/*
+ * Parses a character class like [a-f0-9_] or [^\x00] after the opening bracket.
+ */
+fn parse_class(chars: &mut Chars) -> Result<Vec<u8>, String> {
+    let negated = chars.next_if_eq(&'^').is_some();
+    let mut bytes = Vec::new();
+    let next_byte = |chars: &mut Chars| -> Result<Option<u8>, String> {
+        match chars.next() {
+            None => Err("missing ']'".to_string()),
+            Some(']') => Ok(None),
+            Some('\\') => parse_escape(chars).map(Some),
+            Some(c) if c.is_ascii() => Ok(Some(c as u8)),
+            Some(c) => Err(format!("non-ASCII character '{}' in class, use \\xHH", c)),
+        }
+    };
+    while let Some(from) = next_byte(chars)? {
+        if chars.peek() == Some(&'-') {
+            chars.next();
+            match next_byte(chars)? {
+                Some(to) if from <= to => bytes.extend(from..=to),
+                Some(to) => return Err(format!("invalid range {}-{}", from as char, to as char)),
+                // trailing '-' is literal
+                None => {
+                    bytes.extend([from, b'-']);
+                    break;
+                },
+            }
+        } else {
+            bytes.push(from);
+        }
+    }
+    if negated {
+        bytes = (0..=255).filter(|b| !bytes.contains(b)).collect();
+    }
+    bytes.sort();
+    bytes.dedup();
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::earley::EarleyParser;
+    use crate::constraints::crc32;
+    use crate::grammar::{generate, generate_tree, GrammarRule};
+    use crate::terminals::TerminalRegistry;
+
+    #[test]
+    fn test_parse_binary_grammar() {
+        let text = r#"
+            # binary frame
+            <Start>   ::= <Header> <Payload>
+            <Header>  ::= "\xAA\x55" "MAGIC-u16le"
+            <Payload> ::= "" | "\x00\xff" <Payload>
+                      | "\"\\"
+        "#;
+        let grammar = parse_grammar(text, &LengthBounds::default()).unwrap().0;
+        assert_eq!(grammar["Payload"].len(), 3);
+        assert!(grammar["Payload"][0].is_empty());
+        assert!(matches!(&grammar["Header"][0][0], GrammarRule::Terminal(t) if t == &[0xAA, 0x55]));
+        assert!(matches!(&grammar["Payload"][2][0], GrammarRule::Terminal(t) if t == b"\"\\"));
+
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let output = generate(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+            assert_eq!(&output[..2], &[0xAA, 0x55]);
+            assert!(output.len() >= 4);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let bounds = LengthBounds::default();
+        assert_eq!(parse_grammar("<Start> ::= \"\\xZZ\"", &bounds).unwrap_err().line, 1);
+        assert_eq!(parse_grammar("<Start> ::= <Missing>", &bounds).unwrap_err(),
+            GrammarFileError { line: 1, message: "undefined nonterminal <Missing>".to_string() });
+        assert_eq!(parse_grammar("<Other> ::= \"a\"", &bounds).unwrap_err().line, 0);
+        assert_eq!(parse_grammar("\n| \"a\"", &bounds).unwrap_err().line, 2);
+        assert!(parse_grammar("<Start> ::= \"abc", &bounds).is_err());
+        assert!(parse_grammar("<Start> ::= (\"a\" | \"b\"", &bounds).is_err());
+        assert!(parse_grammar("<Start> ::= [z-a]", &bounds).is_err());
+        assert!(parse_grammar("<Start> ::= \"a\"{x}", &bounds).is_err());
+        assert!(parse_grammar("<Start> ::= ( <Missing> )+", &bounds).is_err());
+        assert_eq!(parse_grammar("<Start> ::= \"a\" <Start", &bounds).unwrap_err(),
+            GrammarFileError { line: 1, message: "unterminated nonterminal".to_string() });
+        assert_eq!(parse_grammar("<Start> ::= \"a\"{2,3", &bounds).unwrap_err(),
+            GrammarFileError { line: 1, message: "unterminated repetition".to_string() });
+    }
+
+    #[test]
+    fn test_parse_ebnf_operators() {
+        let text = r#"
+            <Start> ::= <Key> ( "=" <Value> )? ( "&" <Key> "=" <Value> )*
+            <Key>   ::= [a-z_]+
+            <Value> ::= ( [0-9A-F]{2} | "+" | "%" [0-9a-f]{2} ){1,4}
+        "#;
+        let grammar = parse_ebnf_grammar(text).unwrap();
+        assert_eq!(grammar["Key"][0][0], EbnfRule::plus(EbnfRule::char_ranges(&[(b'a', b'z'), (b'_', b'_')])));
+        assert_eq!(grammar[GRAMMAR_ENTRY][0].len(), 3);
+
+        let bounds = LengthBounds { max_repetitions: 2 };
+        let grammar = parse_grammar(text, &bounds).unwrap().0;
+        // Key+ allows one to three repetitions
+        assert_eq!(grammar["[_a-z]+"].len(), 3);
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..50 {
+            let output = generate(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+            assert!(output[0].is_ascii_lowercase() || output[0] == b'_');
+        }
+    }
+
+    #[test]
+    fn test_parse_regex_terminals() {
+        let text = r#"
+            <Start> ::= <Date> "&to=" /[a-z]+@[a-z]+\.(com|de)/
+            <Date>  ::= /[0-9]{4}-(0[1-9]|1[0-2])-[0-9]{2}/ /(\/[a-z]{1,3})?/
+        "#;
+        let grammar = parse_grammar(text, &LengthBounds::default()).unwrap().0;
+        assert_eq!(grammar["Date"][0][1].name(), "/(/[a-z]{1,3})?/");
+        let terminals = TerminalRegistry::init();
+        let parser = EarleyParser::init(&grammar, &terminals);
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..50 {
+            let output = generate(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+            assert_eq!(output[4], b'-');
+            assert!(parser.parse(GRAMMAR_ENTRY, &output).is_ok());
+        }
+        let error = parse_grammar("<Start> ::= /[0-9/", &LengthBounds::default()).unwrap_err();
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn test_format_roundtrip() {
+        let text = r#"
+            <Start> ::= <Key> ( "=" <Value> )? ( "&" <Key> "=" <Value> )*
+                    | "'\x00\"" /[a-z\/]+/
+            <Key>   ::= [a-z_]+
+            <Value> ::= ( [0-9A-F]{2} | "+" | "%" [0-9a-f]{2} ){1,4} | ""
+        "#;
+        let grammar = parse_ebnf_grammar(text).unwrap();
+        let formatted = format_ebnf_grammar(&grammar);
+        assert!(formatted.starts_with("<Start> ::= <Key>"));
+        assert_eq!(parse_ebnf_grammar(&formatted).unwrap(), grammar);
+    }
+
+    #[test]
+    fn test_parse_annotations() {
+        let text = r#"
+            <Start>   ::= "\xAA" "MAGIC-u16le" <Payload> "MAGIC-u32be" @length(1, 2, u16le) @crc32(3, 0, 1, 2, u32be) # frame
+            <Payload> ::= "" | "MAGIC-Byte" <Payload>
+                      | [a-z]{3} "=" /[a-z]{3}/ @equal(2, 0)
+        "#;
+        let (grammar, constraints) = parse_grammar(text, &LengthBounds::default()).unwrap();
+        assert_eq!(constraints.constraints, vec![
+            Constraint::length(GRAMMAR_ENTRY, 0, 1, vec![2], FieldEncoding::little_endian(2).unwrap()),
+            Constraint::checksum(GRAMMAR_ENTRY, 0, 3, vec![0, 1, 2], ConstraintKind::Crc32, FieldEncoding::big_endian(4).unwrap()),
+            Constraint::equal("Payload", 2, 2, 0),
+        ]);
+        assert!(constraints.validate(&grammar).is_ok());
+
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut tree = generate_tree(&grammar, &TerminalRegistry::init(), GRAMMAR_ENTRY, &mut rng);
+        constraints.apply(&mut tree, &mut rng);
+        let bytes = tree.to_bytes();
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        assert_eq!(u16::from_le_bytes([bytes[1], bytes[2]]) as usize, body.len() - 3);
+        assert_eq!(crc, crc32(body).to_be_bytes());
+    }
+
+    #[test]
+    fn test_annotation_errors() {
+        let error = |text: &str| parse_grammar(text, &LengthBounds::default()).unwrap_err().message;
+        assert_eq!(error("<Start> ::= <Start> \"a\" @length(0, 1, u8)"), "constraint target <Start> is no terminal or regex");
+        assert_eq!(error("<Start> ::= \"a\" \"b\" @length(1, 2, u8)"), "constraint source 2 out of range");
+        assert_eq!(error("<Start> ::= \"a\" @length(0, u8)"), "@length needs a target, sources and an encoding");
+        assert_eq!(error("<Start> ::= \"a\" \"b\" @equal(0, 1, 1)"), "@equal needs a target and one source");
+        assert_eq!(error("<Start> ::= \"a\" \"b\" @length(0, 1, u24)"), "unknown field encoding 'u24'");
+        assert_eq!(error("<Start> ::= \"a\" \"b\" @md5(0, 1, u8)"), "unknown constraint 'md5'");
+        assert_eq!(error("<Start> ::= \"a\" @equal(0, 0) \"b\""), "unexpected character '\"' after annotation");
+        assert_eq!(error("<Start> ::= ( \"a\" @equal(0, 0) )"), "unexpected character '@'");
+    }
+
+    #[test]
+    fn test_parse_negated_class() {
+        let grammar = parse_ebnf_grammar("<Start> ::= [^\\x00-\\xfe]").unwrap();
+        assert_eq!(grammar[GRAMMAR_ENTRY][0][0], EbnfRule::CharClass(vec![0xff]));
+    }
+}
@@ -0,0 +1,450 @@
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::{fs, io, path::Path};
+
+use crate::ebnf::{EbnfGrammar, EbnfRule};
+use crate::grammar::GRAMMAR_ENTRY;
+
+/*
+ * A word position with at most this many different values, each seen twice
+ * on average, is kept as a choice of keywords instead of a character class.
+ */
+const MAX_KEYWORDS: usize = 4;
+
+/*
+ * Longest sequence of tokens which is detected as a repeated unit.
+ */
+const MAX_UNIT_LENGTH: usize = 8;
+
+/*
+ * Number of inputs sent to the oracle to confirm a generalization.
+ */
+const ORACLE_PROBES: usize = 3;
+
+/*
+ * Name of the rule for single lines, when line-based inputs are inferred line by line.
+ */
+const LINE_SYMBOL: &str = "Line";
+
+/**
+ * Black-box oracle which tells whether the target accepts an input.
+ */
+pub type Oracle<'o> = &'o dyn Fn(&[u8]) -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum TokenKind {
+    /* Run of letters, digits and '_' */
+    Word,
+    /* Run of spaces and tabs */
+    Space,
+    /* Single punctuation character or line break */
+    Delimiter(u8),
+    /* Run of bytes which are no printable ASCII */
+    Binary,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    text: Vec<u8>,
+}
+
+fn kind_of(b: u8) -> TokenKind {
+    match b {
+        b if b.is_ascii_alphanumeric() || b == b'_' => TokenKind::Word,
+        b' ' | b'\t' => TokenKind::Space,
+        b'\r' | b'\n' | 0x21..=0x7e => TokenKind::Delimiter(b),
+        _ => TokenKind::Binary,
+    }
+}
+
+fn tokenize(input: &[u8]) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    for &b in input {
+        let kind = kind_of(b);
+        match tokens.last_mut() {
+            Some(last) if last.kind == kind && !matches!(kind, TokenKind::Delimiter(_)) => last.text.push(b),
+            _ => tokens.push(Token { kind, text: vec![b] }),
+        }
+    }
+    tokens
+}
+
+/*
+ * Element of an inferred sequence: a single token, or a unit of tokens repeated
+ * one or more times, e.g. the `& key = value` of a query string.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Element {
+    Token(TokenKind),
+    Repeat(Vec<TokenKind>),
+}
+
+/*
+ * A tokenized sample. `slots` assigns every token to (element index, offset in
+ * the repeated unit) of its group. `prefix` and `suffix` surround the sample in
+ * the original input, for samples which are single lines of a larger input.
+ */
+#[derive(Debug, Clone)]
+struct Sample {
+    tokens: Vec<Token>,
+    slots: Vec<(usize, usize)>,
+    counts: HashMap<usize, usize>,
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+}
+
+impl Sample {
+    /*
+     * The full input with every token in `slot` replaced by `text`.
+     */
+    fn with_slot(&self, slot: (usize, usize), text: &[u8]) -> Vec<u8> {
+        let mut input = self.prefix.clone();
+        for (token, s) in self.tokens.iter().zip(&self.slots) {
+            input.extend_from_slice(if *s == slot { text } else { &token.text });
+        }
+        input.extend_from_slice(&self.suffix);
+        input
+    }
+}
+
+/*
+ * Samples sharing the same sequence of elements, they become one alternative.
+ */
+struct Group {
+    elements: Vec<Element>,
+    samples: Vec<Sample>,
+}
+
+/**
+ * Infers a grammar from sample inputs.
+ * Every sample is split into words, spaces, single delimiters and binary runs.
+ * Units of tokens which repeat back to back in some sample, like `&key=value`,
+ * become repetitions, and samples with the same token structure are merged into
+ * one alternative. Words which vary are generalized to a character class with the
+ * observed length range, unless they look like keywords. Inputs consisting of
+ * several lines are inferred line by line.
+ * With an `oracle`, generalizations are only kept if the target accepts inputs
+ * built with them, and repetitions and lengths are made open-ended when the
+ * target accepts longer inputs.
+ * The result always accepts every sample.
+ */
+pub fn infer_grammar(samples: &[Vec<u8>], oracle: Option<Oracle>) -> EbnfGrammar {
+    let mut grammar: EbnfGrammar = HashMap::new();
+    let line_based = samples.iter().all(|s| s.contains(&b'\n'))
+        && samples.iter().any(|s| s.iter().filter(|&&b| b == b'\n').count() >= 2);
+
+    let mut inputs: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> = Vec::new();
+    if line_based {
+        let line_counts: Vec<usize> = samples.iter().map(|s| split_lines(s).len()).collect();
+        let min = *line_counts.iter().min().unwrap();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![
+            EbnfRule::repeat(EbnfRule::NonTerminal(LINE_SYMBOL.to_string()), min, None),
+        ]]);
+        for sample in samples {
+            let mut start = 0;
+            for line in split_lines(sample) {
+                let end = start + line.len();
+                inputs.push((sample[..start].to_vec(), line.to_vec(), sample[end..].to_vec()));
+                start = end;
+            }
+        }
+    } else {
+        inputs = samples.iter().map(|s| (Vec::new(), s.clone(), Vec::new())).collect();
+    }
+
+    let tokenized: Vec<Vec<Token>> = inputs.iter().map(|(_, input, _)| tokenize(input)).collect();
+    let units = find_units(&tokenized);
+    debug!("Inferred {} repeated units", units.len());
+
+    let mut groups: Vec<Group> = Vec::new();
+    for ((prefix, _, suffix), tokens) in inputs.into_iter().zip(tokenized) {
+        let (elements, slots, counts) = compress(&tokens, &units);
+        let sample = Sample { tokens, slots, counts, prefix, suffix };
+        match groups.iter_mut().find(|g| g.elements == elements) {
+            Some(group) => group.samples.push(sample),
+            None => groups.push(Group { elements, samples: vec![sample] }),
+        }
+    }
+    let groups = merge_optional_repeats(groups);
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let alternatives: Vec<Vec<EbnfRule>> = groups.iter()
+        .map(|g| generalize_group(g, oracle, &mut rng))
+        .collect();
+    let symbol = if line_based { LINE_SYMBOL } else { GRAMMAR_ENTRY };
+    grammar.insert(symbol.to_string(), alternatives);
+    grammar
+}
+
+/**
+ * Infers a grammar from every file in `directory`, see infer_grammar().
+ */
+pub fn infer_grammar_from_directory(directory: &Path, oracle: Option<Oracle>) -> io::Result<EbnfGrammar> {
+    let mut paths: Vec<_> = fs::read_dir(directory)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|p| p.is_file());
+    paths.sort();
+    let samples = paths.iter().map(fs::read).collect::<io::Result<Vec<_>>>()?;
+    if samples.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no samples in {}", directory.display())));
+    }
+    Ok(infer_grammar(&samples, oracle))
+}
+
+/*
+ * Splits after every '\n', the last line may lack the line break.
+ */
+fn split_lines(input: &[u8]) -> Vec<&[u8]> {
+    input.split_inclusive(|&b| b == b'\n').collect()
+}
+
+/*
+ * Units of token kinds which occur at least twice back to back in some sample.
+ * For every sample the unit with the best score() is taken.
+ */
+fn find_units(samples: &[Vec<Token>]) -> Vec<Vec<TokenKind>> {
+    let mut units: Vec<Vec<TokenKind>> = Vec::new();
+    for tokens in samples {
+        let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind).collect();
+        let best = (1..=MAX_UNIT_LENGTH.min(kinds.len() / 2))
+            .flat_map(|length| (0..=kinds.len() - 2 * length).map(move |start| (start, length)))
+            .map(|(start, length)| {
+                let unit = &kinds[start..start + length];
+                (start, unit, repetitions(&kinds[start..], unit))
+            })
+            .filter(|(_, _, count)| *count >= 2)
+            .min_by_key(|(start, unit, count)| Reverse(score(&kinds, *start, unit, *count)));
+        if let Some((_, unit, _)) = best {
+            if !units.iter().any(|u| u == unit) {
+                units.push(unit.to_vec());
+            }
+        }
+    }
+    units
+}
+
+/*
+ * Number of times `unit` repeats at the start of `kinds`.
+ */
+fn repetitions(kinds: &[TokenKind], unit: &[TokenKind]) -> usize {
+    kinds.chunks_exact(unit.len()).take_while(|chunk| *chunk == unit).count()
+}
+
+/*
+ * Rank of `count` repetitions of `unit` at `start`, mainly the number of tokens
+ * covered. Units forming a list `x (, x)*`, where the tokens in front of the
+ * repetition equal the unit without its first token, count these tokens as
+ * covered, too. On a tie, units which repeat up to the end and start with a
+ * delimiter rank higher.
+ */
+fn score(kinds: &[TokenKind], start: usize, unit: &[TokenKind], count: usize) -> (usize, bool, bool) {
+    let length = unit.len();
+    let list_head = start + 1 >= length && kinds[start + 1 - length..start] == unit[1..];
+    let covered = count * length + if list_head { length - 1 } else { 0 };
+    let ends = start + count * length == kinds.len();
+    (covered, ends, matches!(unit[0], TokenKind::Delimiter(_)))
+}
+
+type Compressed = (Vec<Element>, Vec<(usize, usize)>, HashMap<usize, usize>);
+
+/*
+ * Replaces occurrences of known units with repetitions, best score() first.
+ * Units of a single token are only used if they repeat, longer ones also for
+ * one occurrence.
+ */
+fn compress(tokens: &[Token], units: &[Vec<TokenKind>]) -> Compressed {
+    let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind).collect();
+    let mut compressed = (Vec::new(), Vec::new(), HashMap::new());
+    compress_range(&kinds, 0..kinds.len(), units, &mut compressed);
+    compressed
+}
+
+fn compress_range(kinds: &[TokenKind], range: Range<usize>, units: &[Vec<TokenKind>], compressed: &mut Compressed) {
+    let best = units.iter()
+        .flat_map(|unit| range.clone().map(move |start| (start, unit)))
+        .map(|(start, unit)| (start, unit, repetitions(&kinds[start..range.end], unit)))
+        .filter(|(_, unit, count)| *count >= 2 || (*count == 1 && unit.len() >= 2))
+        .min_by_key(|(start, unit, count)| Reverse(score(kinds, *start, unit, *count)));
+    let Some((start, unit, count)) = best else {
+        let (elements, slots, _) = compressed;
+        for &kind in &kinds[range] {
+            slots.push((elements.len(), 0));
+            elements.push(Element::Token(kind));
+        }
+        return;
+    };
+    let end = start + count * unit.len();
+    compress_range(kinds, range.start..start, units, compressed);
+    let (elements, slots, counts) = compressed;
+    let index = elements.len();
+    slots.extend((0..end - start).map(|offset| (index, offset % unit.len())));
+    counts.insert(index, count);
+    elements.push(Element::Repeat(unit.clone()));
+    compress_range(kinds, end..range.end, units, compressed);
+}
+
+/*
+ * Merges a group into another one whose elements equal its own plus some
+ * repetitions, the repetitions then also allow zero occurrences.
+ */
+fn merge_optional_repeats(mut groups: Vec<Group>) -> Vec<Group> {
+    let mut i = 0;
+    while i < groups.len() {
+        let target = (0..groups.len()).find(|&j| {
+            j != i && groups[j].elements.iter().any(|e| matches!(e, Element::Repeat(_)))
+                && groups[j].elements.iter().filter(|e| matches!(e, Element::Token(_))).eq(groups[i].elements.iter())
+        });
+        let Some(j) = target else {
+            i += 1;
+            continue;
+        };
+        // index of every element of group i in group j
+        let mapping: Vec<usize> = groups[j].elements.iter().enumerate()
+            .filter(|(_, e)| matches!(e, Element::Token(_)))
+            .map(|(index, _)| index)
+            .collect();
+        let group = groups.remove(i);
+        let j = if j > i { j - 1 } else { j };
+        for mut sample in group.samples {
+            sample.slots = sample.slots.iter().map(|&(index, offset)| (mapping[index], offset)).collect();
+            groups[j].samples.push(sample);
+        }
+    }
+    groups
+}
+
+fn generalize_group(group: &Group, oracle: Option<Oracle>, rng: &mut StdRng) -> Vec<EbnfRule> {
+    group.elements.iter().enumerate()
+        .map(|(index, element)| match element {
+            Element::Token(kind) => generalize_slot(group, (index, 0), *kind, oracle, rng),
+            Element::Repeat(unit) => {
+                let sequence = unit.iter().enumerate()
+                    .map(|(offset, kind)| generalize_slot(group, (index, offset), *kind, oracle, rng))
+                    .collect();
+                let counts: Vec<usize> = group.samples.iter().map(|s| s.counts.get(&index).copied().unwrap_or(0)).collect();
+                let min = *counts.iter().min().unwrap();
+                EbnfRule::repeat(EbnfRule::Group(vec![sequence]), min, None)
+            },
+        })
+        .collect()
+}
+
+/*
+ * Generalizes all values seen at one position of a group.
+ */
+fn generalize_slot(group: &Group, slot: (usize, usize), kind: TokenKind, oracle: Option<Oracle>, rng: &mut StdRng) -> EbnfRule {
+    let examples: Vec<(&Sample, &[u8])> = group.samples.iter()
+        .flat_map(|s| s.tokens.iter().zip(&s.slots)
+            .filter(|(_, &sl)| sl == slot)
+            .map(move |(t, _)| (s, t.text.as_slice())))
+        .collect();
+    let mut values: Vec<&[u8]> = examples.iter().map(|(_, v)| *v).collect();
+    values.sort();
+    values.dedup();
+    if values.len() == 1 || matches!(kind, TokenKind::Delimiter(_)) {
+        return EbnfRule::Terminal(values[0].to_vec());
+    }
+
+    let mut bytes: Vec<u8> = match kind {
+        TokenKind::Word => {
+            let seen = |f: fn(&u8) -> bool| values.iter().any(|v| v.iter().any(f));
+            let mut bytes = Vec::new();
+            if seen(u8::is_ascii_digit) { bytes.extend(b'0'..=b'9'); }
+            if seen(u8::is_ascii_lowercase) { bytes.extend(b'a'..=b'z'); }
+            if seen(u8::is_ascii_uppercase) { bytes.extend(b'A'..=b'Z'); }
+            if seen(|&b| b == b'_') { bytes.push(b'_'); }
+            bytes
+        },
+        TokenKind::Space => vec![b'\t', b' '],
+        _ => values.iter().flat_map(|v| v.iter().copied()).collect(),
+    };
+    bytes.sort();
+    bytes.dedup();
+    let min = values.iter().map(|v| v.len()).min().unwrap();
+    let max = values.iter().map(|v| v.len()).max().unwrap();
+
+    let keywords = EbnfRule::Group(values.iter().map(|v| vec![EbnfRule::Terminal(v.to_vec())]).collect());
+    let looks_like_keywords = kind == TokenKind::Word && values.len() <= MAX_KEYWORDS && examples.len() >= 2 * values.len();
+    let accepts = |length: usize, rng: &mut StdRng| match oracle {
+        Some(oracle) => (0..ORACLE_PROBES).all(|_| {
+            let (sample, _) = examples[rng.gen_range(0..examples.len())];
+            let text: Vec<u8> = (0..length).map(|_| bytes[rng.gen_range(0..bytes.len())]).collect();
+            oracle(&sample.with_slot(slot, &text))
+        }),
+        None => !looks_like_keywords,
+    };
+    if !accepts(max, rng) {
+        return keywords;
+    }
+    let open_ended = oracle.is_some() && accepts(2 * max + 1, rng);
+    let class = EbnfRule::CharClass(bytes);
+    match (min, max, open_ended) {
+        (1, 1, false) => class,
+        (_, _, true) => EbnfRule::repeat(class, min, None),
+        _ => EbnfRule::repeat(class, min, Some(max)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::earley::EarleyParser;
+    use crate::ebnf::{convert_ebnf, LengthBounds};
+    use crate::terminals::TerminalRegistry;
+
+    fn assert_accepts_samples(grammar: &EbnfGrammar, samples: &[Vec<u8>]) {
+        let grammar = convert_ebnf(grammar, &LengthBounds::default()).unwrap();
+        let terminals = TerminalRegistry::empty();
+        let parser = EarleyParser::init(&grammar, &terminals);
+        for sample in samples {
+            assert!(parser.parse(GRAMMAR_ENTRY, sample).is_ok(), "{:?}", String::from_utf8_lossy(sample));
+        }
+    }
+
+    #[test]
+    fn test_infer_query_strings() {
+        let samples: Vec<Vec<u8>> = ["a=1", "key=42&b=x&c=7", "id=9&name=bob", "q=test&page=2&size=10&sort=asc"]
+            .iter().map(|s| s.as_bytes().to_vec()).collect();
+        let grammar = infer_grammar(&samples, None);
+        assert_accepts_samples(&grammar, &samples);
+        // one alternative, with `&key=value` repeated zero or more times
+        assert_eq!(grammar[GRAMMAR_ENTRY].len(), 1);
+        let repeat = grammar[GRAMMAR_ENTRY][0].last().unwrap();
+        assert!(matches!(repeat, EbnfRule::Repeat { min: 0, max: None, .. }), "{}", repeat.to_text());
+    }
+
+    #[test]
+    fn test_infer_keywords_and_lines() {
+        let samples: Vec<Vec<u8>> = [
+            "GET /index 200\nGET /a 404\n",
+            "POST /form 200\nGET /b 200\nPOST /c 500\n",
+            "GET /x 200\n",
+        ].iter().map(|s| s.as_bytes().to_vec()).collect();
+        let grammar = infer_grammar(&samples, None);
+        assert_accepts_samples(&grammar, &samples);
+        assert_eq!(grammar[GRAMMAR_ENTRY][0][0].to_text(), "<Line>+");
+        let line = &grammar[LINE_SYMBOL][0];
+        assert_eq!(line[0].to_text(), "(\"GET\" | \"POST\")");
+    }
+
+    #[test]
+    fn test_oracle_refines_generalization() {
+        let samples: Vec<Vec<u8>> = ["v1:abc", "v2:de"].iter().map(|s| s.as_bytes().to_vec()).collect();
+        // accepts only v1 and v2, but any lowercase payload
+        let oracle = |input: &[u8]| {
+            let text = String::from_utf8_lossy(input);
+            let (version, payload) = text.split_once(':').unwrap();
+            (version == "v1" || version == "v2") && payload.bytes().all(|b| b.is_ascii_lowercase())
+        };
+        let grammar = infer_grammar(&samples, Some(&oracle));
+        assert_accepts_samples(&grammar, &samples);
+        let texts: Vec<String> = grammar[GRAMMAR_ENTRY][0].iter().map(|r| r.to_text()).collect();
+        assert_eq!(texts, vec!["(\"v1\" | \"v2\")", "\":\"", "[a-z]{2,}"]);
+    }
+}
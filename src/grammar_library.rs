@@ -0,0 +1,235 @@
+use crate::constraints::{Constraint, ConstraintKind, ConstraintSet, FieldEncoding};
+use crate::ebnf::LengthBounds;
+use crate::grammar::{create_cgi_grammar, create_grammar_calculation, Grammar, GRAMMAR_ENTRY};
+use crate::grammar_file::parse_grammar;
+
+/**
+ * Names and descriptions of the built-in grammars, see library_grammar().
+ */
+pub const LIBRARY_GRAMMARS: [(&str, &str); 11] = [
+    ("cgi", "CGI-encoded strings with + and %XX escapes"),
+    ("calculation", "arithmetic expressions"),
+    ("url", "URLs with user info, port, path, query string and fragment"),
+    ("http", "HTTP/1.1 requests with headers and a body with matching Content-Length"),
+    ("json", "JSON documents"),
+    ("csv", "CSV files with quoted fields"),
+    ("ini", "INI files with sections, key-value pairs and comments"),
+    ("xml", "XML subset with attributes, entities, CDATA and comments"),
+    ("ipv4", "IPv4 addresses in dotted decimal notation"),
+    ("ipv6", "IPv6 addresses, full, compressed and IPv4-mapped"),
+    ("droneid", "DroneID-like binary telemetry frames with length and CRC-16"),
+];
+
+const URL_GRAMMAR: &str = r##"
+<Start>     ::= <Scheme> "://" <Authority> <Path> ( "?" <Query> )? ( "#" <Fragment> )?
+<Scheme>    ::= "http" | "https" | "ftp" | "file"
+<Authority> ::= ( <UserInfo> "@" )? <Host> ( ":" "MAGIC-u16" )?
+<UserInfo>  ::= /[a-z0-9._~-]{1,8}(:[a-z0-9]{1,8})?/
+<Host>      ::= "localhost" | /[a-z0-9-]{1,10}(\.[a-z0-9-]{1,10}){0,3}/ | <IPv4>
+<IPv4>      ::= "MAGIC-u8" "." "MAGIC-u8" "." "MAGIC-u8" "." "MAGIC-u8"
+<Path>      ::= ( "/" <Segment> ){0,4}
+<Segment>   ::= ( [a-zA-Z0-9._~-] | <Escape> ){0,8}
+<Escape>    ::= "%" /[0-9A-F]{2}/
+<Query>     ::= <Pair> ( "&" <Pair> ){0,3}
+<Pair>      ::= <Segment> "=" <Segment>
+<Fragment>  ::= /[a-z0-9]{0,8}/
+"##;
+
+/* Element 6 of <Start> is the Content-Length of element 8 */
+const HTTP_GRAMMAR: &str = r##"
+<Start>   ::= <Method> " " <Target> " HTTP/1.1\r\n" <Header>{0,4} "Content-Length: " "MAGIC-u16" "\r\n\r\n" <Body>
+<Method>  ::= "GET" | "POST" | "PUT" | "DELETE" | "HEAD" | "OPTIONS" | "PATCH"
+<Target>  ::= "/" | /(\/[a-z0-9._-]{1,8}){1,4}/ ( "?" /[a-z]{1,5}=[a-z0-9]{0,5}/ )?
+<Header>  ::= <Name> ": " /[ -~]{1,20}/ "\r\n"
+<Name>    ::= "Host" | "User-Agent" | "Accept" | "Connection" | "Cookie" | /X-[A-Za-z]{1,10}/
+<Body>    ::= "" | /[ -~]{1,32}/
+"##;
+
+const JSON_GRAMMAR: &str = r##"
+<Start>   ::= <Element>
+<Element> ::= <Ws> <Value> <Ws>
+<Value>   ::= <Object> | <Array> | <String> | <Number> | "true" | "false" | "null"
+<Object>  ::= "{" <Ws> "}" | "{" <Member> ( "," <Member> ){0,2} "}"
+<Member>  ::= <Ws> <String> <Ws> ":" <Element>
+<Array>   ::= "[" <Ws> "]" | "[" <Element> ( "," <Element> ){0,2} "]"
+<String>  ::= "\"" <Char>{0,8} "\""
+<Char>    ::= /[ !#-\[\]-~]/ | "\\" /["\\\/bfnrt]/ | "\\u" /[0-9a-fA-F]{4}/
+<Number>  ::= /-?(0|[1-9][0-9]{0,5})(\.[0-9]{1,4})?([eE][+-]?[0-9]{1,2})?/
+<Ws>      ::= /[ \t\n\r]{0,2}/
+"##;
+
+const CSV_GRAMMAR: &str = r##"
+<Start>  ::= <Record> ( "\r\n" <Record> ){0,4} "\r\n"?
+<Record> ::= <Field> ( "," <Field> ){0,5}
+<Field>  ::= /[ -!#-+\--~]{0,8}/
+           | "\"" ( /[ !#-~\r\n]/ | "\"\"" ){0,8} "\""
+"##;
+
+const INI_GRAMMAR: &str = r##"
+<Start>   ::= ( <Pair> | <Comment> ){0,3} <Section>{0,4}
+<Section> ::= "[" <Name> "]" <Eol> ( <Pair> | <Comment> | <Eol> ){0,4}
+<Pair>    ::= <Name> " "? "=" " "? <Value> <Eol>
+<Name>    ::= /[A-Za-z_][A-Za-z0-9_.]{0,8}/
+<Value>   ::= /[ -~]{0,12}/ | "MAGIC-i32" | "true" | "false" | "\"" /[ !#-~]{0,8}/ "\""
+<Comment> ::= /[;#][ -~]{0,12}/ <Eol>
+<Eol>     ::= "\n" | "\r\n"
+"##;
+
+/* The closing tag name, element 6 of the second <Element> alternative, repeats element 1 */
+const XML_GRAMMAR: &str = r##"
+<Start>     ::= <Prolog>? <Element>
+<Prolog>    ::= "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n"
+<Element>   ::= "<" /[A-Za-z_][A-Za-z0-9_.-]{0,6}/ <Attribute>{0,2} "/>"
+              | "<" /[A-Za-z_][A-Za-z0-9_.-]{0,6}/ <Attribute>{0,2} ">" <Content>{0,3} "</" /[A-Za-z_][A-Za-z0-9_.-]{0,6}/ ">"
+<Attribute> ::= " " /[A-Za-z_][A-Za-z0-9_.-]{0,6}/ "=\"" ( /[ !#-%'-;=-~]/ | <Entity> ){0,8} "\""
+<Content>   ::= <Element> | <Text> | <Entity>
+              | "<![CDATA[" /[a-z <>&]{0,10}/ "]]>"
+              | "<!--" /[a-z ]{0,10}/ "-->"
+<Text>      ::= /[ -%'-;=?-~]{1,10}/
+<Entity>    ::= "&lt;" | "&gt;" | "&amp;" | "&quot;" | "&apos;" | "&#" /[0-9]{1,4}/ ";"
+"##;
+
+const IPV4_GRAMMAR: &str = r##"
+<Start> ::= <Octet> "." <Octet> "." <Octet> "." <Octet>
+<Octet> ::= "MAGIC-u8" | "MAGIC-u8-Boundary"
+"##;
+
+const IPV6_GRAMMAR: &str = r##"
+<Start>      ::= <Full> | <Compressed> | <Mapped>
+<Full>       ::= <H16> ( ":" <H16> ){7}
+<Compressed> ::= ( <H16> ( ":" <H16> ){0,2} )? "::" ( <H16> ( ":" <H16> ){0,2} )?
+<Mapped>     ::= "::ffff:" "MAGIC-u8" "." "MAGIC-u8" "." "MAGIC-u8" "." "MAGIC-u8"
+<H16>        ::= /[0-9a-fA-F]{1,4}/
+"##;
+
+/* Element 1 of <Start> is the length of the message, element 3 the CRC-16 of elements 0 to 2 */
+const DRONEID_GRAMMAR: &str = r##"
+<Start>      ::= "\xAA\x55" "MAGIC-u16le" <Message> "MAGIC-u16le"
+<Message>    ::= <BasicId> | <Location> | <System> | <OperatorId>
+<BasicId>    ::= "\x00" [\x00-\x04] /[A-Z0-9]{20}/
+<Location>   ::= "\x01" [\x00-\x04] "MAGIC-u32le" "MAGIC-u32le" "MAGIC-u16le" "MAGIC-u16le" "MAGIC-Byte" "MAGIC-u16le"
+<System>     ::= "\x04" [\x00-\x01] "MAGIC-u32le" "MAGIC-u32le" "MAGIC-u16le" "MAGIC-Byte"
+<OperatorId> ::= "\x05" "\x00" /[A-Z0-9-]{20}/
+"##;
+
+/**
+ * Built-in grammar by name, see LIBRARY_GRAMMARS, together with the constraints
+ * its inputs need to pass length and checksum checks.
+ */
+pub fn library_grammar(name: &str) -> Option<(Grammar, ConstraintSet)> {
+    let mut constraints = ConstraintSet::empty();
+    let text = match name {
+        "cgi" => return Some((create_cgi_grammar(), constraints)),
+        "calculation" => return Some((create_grammar_calculation(), constraints)),
+        "url" => URL_GRAMMAR,
+        "http" => {
+            constraints.add(Constraint::length(GRAMMAR_ENTRY, 0, 6, vec![8], FieldEncoding::Decimal));
+            HTTP_GRAMMAR
+        },
+        "json" => JSON_GRAMMAR,
+        "csv" => CSV_GRAMMAR,
+        "ini" => INI_GRAMMAR,
+        "xml" => {
+            constraints.add(Constraint::equal("Element", 1, 6, 1));
+            XML_GRAMMAR
+        },
+        "ipv4" => IPV4_GRAMMAR,
+        "ipv6" => IPV6_GRAMMAR,
+        "droneid" => {
+            constraints.add(Constraint::length(GRAMMAR_ENTRY, 0, 1, vec![2], FieldEncoding::little_endian(2).unwrap()));
+            constraints.add(Constraint::checksum(GRAMMAR_ENTRY, 0, 3, vec![0, 1, 2], ConstraintKind::Crc16, FieldEncoding::little_endian(2).unwrap()));
+            DRONEID_GRAMMAR
+        },
+        _ => return None,
+    };
+    let (grammar, _) = parse_grammar(text, &LengthBounds::default())
+        .unwrap_or_else(|e| panic!("built-in grammar {} is invalid: {}", name, e));
+    Some((grammar, constraints))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::constraints::crc16;
+    use crate::earley::EarleyParser;
+    use crate::grammar::{generate_tree, validate_grammar};
+    use crate::terminals::TerminalRegistry;
+
+    /*
+     * Generates inputs from the named grammar with its constraints applied.
+     */
+    fn generate_inputs(name: &str, count: usize) -> Vec<Vec<u8>> {
+        let (grammar, constraints) = library_grammar(name).unwrap();
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..count)
+            .map(|_| {
+                let mut tree = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+                constraints.apply(&mut tree, &mut rng);
+                tree.to_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_library_grammars_are_valid_and_parse_their_inputs() {
+        let terminals = TerminalRegistry::init();
+        for (name, _) in LIBRARY_GRAMMARS {
+            let (grammar, constraints) = library_grammar(name).unwrap();
+            assert!(validate_grammar(&grammar).is_ok(), "{}", name);
+            assert!(constraints.validate(&grammar).is_ok(), "{}", name);
+            let parser = EarleyParser::init(&grammar, &terminals);
+            for input in generate_inputs(name, 20) {
+                assert!(parser.parse(GRAMMAR_ENTRY, &input).is_ok(), "{}: {:?}", name, String::from_utf8_lossy(&input));
+            }
+        }
+        assert!(library_grammar("unknown").is_none());
+    }
+
+    #[test]
+    fn test_ip_addresses() {
+        for input in generate_inputs("ipv4", 50) {
+            let text = String::from_utf8(input).unwrap();
+            assert!(text.parse::<Ipv4Addr>().is_ok(), "{}", text);
+        }
+        for input in generate_inputs("ipv6", 50) {
+            let text = String::from_utf8(input).unwrap();
+            assert!(text.parse::<Ipv6Addr>().is_ok(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_http_content_length() {
+        for input in generate_inputs("http", 50) {
+            let text = String::from_utf8(input).unwrap();
+            let (head, body) = text.split_once("\r\n\r\n").unwrap();
+            let length = head.rsplit_once("Content-Length: ").unwrap().1;
+            assert_eq!(length.parse::<usize>().unwrap(), body.len());
+        }
+    }
+
+    #[test]
+    fn test_xml_tags_match() {
+        for input in generate_inputs("xml", 50) {
+            let text = String::from_utf8(input).unwrap();
+            let root = text.split_once("?>\n").map_or(text.as_str(), |(_, root)| root);
+            let name: String = root[1..].chars().take_while(|c| c.is_alphanumeric() || "_.-".contains(*c)).collect();
+            assert!(root.ends_with("/>") || root.ends_with(&format!("</{}>", name)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_droneid_length_and_crc() {
+        for frame in generate_inputs("droneid", 50) {
+            assert_eq!(frame[..2], [0xAA, 0x55]);
+            let length = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+            assert_eq!(length, frame.len() - 6);
+            let (body, crc) = frame.split_at(frame.len() - 2);
+            assert_eq!(crc, crc16(body).to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::str::FromStr;
+
+use crate::derivation::DerivationTree;
+use crate::grammar_coverage::GrammarCoverage;
+use crate::operator_scheduler::OperatorScheduler;
+use crate::runner::RunnerResult;
+use crate::seeder::{ByteMutation, Seedable};
+use crate::splicing::{replacement_mutations, splice, SpliceOperator};
+
+/*
+ * Largest value added to or subtracted from a byte, word or dword.
+ */
+pub const ARITH_MAX: u32 = 35;
+
+/*
+ * Longest block deleted, duplicated, inserted or overwritten.
+ */
+const HAVOC_BLOCK_MAX: usize = 1024;
+
+/*
+ * Inputs are not grown beyond this length by havoc.
+ */
+const HAVOC_MAX_LENGTH: usize = 1 << 16;
+
+/*
+ * Default maximum number of stacked mutations per seed.
+ */
+pub const DEFAULT_HAVOC_STACKING: usize = 16;
+
+/*
+ * Earlier seeds kept as splicing partners, e.g. the entries of a corpus.
+ */
+const SPLICE_DONORS: usize = 256;
+
+/*
+ * Values which often trigger edge cases, as in AFL. Each table includes the
+ * values of the narrower ones, sign-extended.
+ */
+pub const INTERESTING_8: [i8; 9] = [-128, -1, 0, 1, 16, 32, 64, 100, 127];
+pub const INTERESTING_16: [i16; 19] = [
+    -128, -1, 0, 1, 16, 32, 64, 100, 127,
+    -32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767,
+];
+pub const INTERESTING_32: [i32; 27] = [
+    -128, -1, 0, 1, 16, 32, 64, 100, 127,
+    -32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767,
+    i32::MIN, -100663046, -32769, 32768, 65535, 65536, 100663045, i32::MAX,
+];
+
+/**
+ * Mutation operators of the havoc stage.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HavocOperator {
+    /* Flip a single bit */
+    BitFlip,
+    /* Add or subtract a small value to a byte */
+    ByteArithmetic,
+    /* Same for a 16-bit word, little or big endian */
+    WordArithmetic,
+    /* Same for a 32-bit dword, little or big endian */
+    DwordArithmetic,
+    /* Overwrite 1, 2 or 4 bytes with a value like 0, -1, 0x7f, 0x80 or INT_MAX */
+    InterestingValue,
+    BlockDelete,
+    /* Insert a copy of a block of the input */
+    BlockDuplicate,
+    /* Insert a block of random bytes or of one repeated byte */
+    BlockInsert,
+    /* Overwrite a block with another block of the input or with one repeated byte */
+    BlockOverwrite,
+    /* Exchange two bytes */
+    ByteSwap,
+    /* Shuffle the bytes of a block */
+    ChunkShuffle,
+    /* Combine the input with a random corpus entry, needs a corpus seeder below the layer */
+    Splice,
+}
+
+pub const ALL_HAVOC_OPERATORS: [HavocOperator; 12] = [
+    HavocOperator::BitFlip,
+    HavocOperator::ByteArithmetic,
+    HavocOperator::WordArithmetic,
+    HavocOperator::DwordArithmetic,
+    HavocOperator::InterestingValue,
+    HavocOperator::BlockDelete,
+    HavocOperator::BlockDuplicate,
+    HavocOperator::BlockInsert,
+    HavocOperator::BlockOverwrite,
+    HavocOperator::ByteSwap,
+    HavocOperator::ChunkShuffle,
+    HavocOperator::Splice,
+];
+
+impl HavocOperator {
+    /**
+     * Name on the command line, e.g. "bit-flip".
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            HavocOperator::BitFlip => "bit-flip",
+            HavocOperator::ByteArithmetic => "byte-arith",
+            HavocOperator::WordArithmetic => "word-arith",
+            HavocOperator::DwordArithmetic => "dword-arith",
+            HavocOperator::InterestingValue => "interesting",
+            HavocOperator::BlockDelete => "block-delete",
+            HavocOperator::BlockDuplicate => "block-duplicate",
+            HavocOperator::BlockInsert => "block-insert",
+            HavocOperator::BlockOverwrite => "block-overwrite",
+            HavocOperator::ByteSwap => "byte-swap",
+            HavocOperator::ChunkShuffle => "chunk-shuffle",
+            HavocOperator::Splice => "splice",
+        }
+    }
+}
+
+impl FromStr for HavocOperator {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<HavocOperator, String> {
+        ALL_HAVOC_OPERATORS.into_iter()
+            .find(|o| o.name() == name)
+            .ok_or_else(|| format!("unknown havoc operator '{}'", name))
+    }
+}
+
+/*
+ * Random block length between 1 and `limit`, short blocks are more likely.
+ */
+fn block_length(limit: usize, rng: &mut impl Rng) -> usize {
+    let limit = limit.min(HAVOC_BLOCK_MAX);
+    let upper = if rng.gen_bool(0.75) { limit.min(32) } else { limit };
+    rng.gen_range(1..=upper)
+}
+
+/*
+ * Adds a non-zero value in [-ARITH_MAX, ARITH_MAX] to the integer of `width`
+ * bytes at `position`.
+ */
+fn arithmetic(input: &[u8], width: usize, rng: &mut impl Rng) -> Option<ByteMutation> {
+    if input.len() < width {
+        return None;
+    }
+    let position = rng.gen_range(0..=input.len() - width);
+    let mut bytes = [0u8; 8];
+    let big_endian = width > 1 && rng.gen_bool(0.5);
+    let field = &input[position..position + width];
+    if big_endian {
+        bytes[8 - width..].copy_from_slice(field);
+    } else {
+        bytes[..width].copy_from_slice(field);
+    }
+    let value = if big_endian { u64::from_be_bytes(bytes) } else { u64::from_le_bytes(bytes) };
+    let delta = rng.gen_range(1..=ARITH_MAX) as u64;
+    let value = if rng.gen_bool(0.5) { value.wrapping_add(delta) } else { value.wrapping_sub(delta) };
+    let bytes = if big_endian { value.to_be_bytes()[8 - width..].to_vec() } else { value.to_le_bytes()[..width].to_vec() };
+    Some(ByteMutation::Overwrite { position, bytes })
+}
+
+/**
+ * Chooses one concrete change of `input` for `operator`. Returns no changes if the
+ * operator does not apply, e.g. a dword operation on a 3-byte input. Splicing
+ * needs a second input and is done by HavocSeedModifier.
+ */
+pub fn havoc_mutation(operator: HavocOperator, input: &[u8], rng: &mut impl Rng) -> Vec<ByteMutation> {
+    let length = input.len();
+    let can_grow = length < HAVOC_MAX_LENGTH;
+    let mutation = match operator {
+        _ if length == 0 && operator != HavocOperator::BlockInsert => None,
+        HavocOperator::BitFlip => {
+            let position = rng.gen_range(0..length);
+            Some(ByteMutation::Replace { position, byte: input[position] ^ (1 << rng.gen_range(0..8)) })
+        },
+        HavocOperator::ByteArithmetic => arithmetic(input, 1, rng),
+        HavocOperator::WordArithmetic => arithmetic(input, 2, rng),
+        HavocOperator::DwordArithmetic => arithmetic(input, 4, rng),
+        HavocOperator::InterestingValue => {
+            let widths: Vec<usize> = [1, 2, 4].into_iter().filter(|&w| w <= length).collect();
+            let width = *widths.choose(rng).unwrap();
+            let value = match width {
+                1 => *INTERESTING_8.choose(rng).unwrap() as i64,
+                2 => *INTERESTING_16.choose(rng).unwrap() as i64,
+                _ => *INTERESTING_32.choose(rng).unwrap() as i64,
+            };
+            let bytes = if rng.gen_bool(0.5) { value.to_be_bytes()[8 - width..].to_vec() } else { value.to_le_bytes()[..width].to_vec() };
+            Some(ByteMutation::Overwrite { position: rng.gen_range(0..=length - width), bytes })
+        },
+        HavocOperator::BlockDelete if length >= 2 => {
+            let block = block_length(length - 1, rng);
+            Some(ByteMutation::RemoveBlock { position: rng.gen_range(0..=length - block), length: block })
+        },
+        HavocOperator::BlockDuplicate if can_grow => {
+            let block = block_length(length, rng);
+            let source = rng.gen_range(0..=length - block);
+            Some(ByteMutation::InsertBlock { position: rng.gen_range(0..=length), bytes: input[source..source + block].to_vec() })
+        },
+        HavocOperator::BlockInsert if can_grow => {
+            let block = block_length(HAVOC_BLOCK_MAX, rng);
+            let bytes = if rng.gen_bool(0.5) {
+                (0..block).map(|_| rng.gen()).collect()
+            } else {
+                vec![rng.gen(); block]
+            };
+            Some(ByteMutation::InsertBlock { position: rng.gen_range(0..=length), bytes })
+        },
+        HavocOperator::BlockOverwrite => {
+            let block = block_length(length, rng);
+            let position = rng.gen_range(0..=length - block);
+            let bytes = if length > block && rng.gen_bool(0.75) {
+                let source = rng.gen_range(0..=length - block);
+                input[source..source + block].to_vec()
+            } else {
+                vec![rng.gen(); block]
+            };
+            Some(ByteMutation::Overwrite { position, bytes })
+        },
+        HavocOperator::ByteSwap if length >= 2 => {
+            let first = rng.gen_range(0..length);
+            let second = rng.gen_range(0..length);
+            return vec![
+                ByteMutation::Replace { position: first, byte: input[second] },
+                ByteMutation::Replace { position: second, byte: input[first] },
+            ];
+        },
+        HavocOperator::ChunkShuffle if length >= 2 => {
+            let block = block_length(length - 1, rng) + 1;
+            let position = rng.gen_range(0..=length - block);
+            let mut bytes = input[position..position + block].to_vec();
+            bytes.shuffle(rng);
+            Some(ByteMutation::Overwrite { position, bytes })
+        },
+        _ => None,
+    };
+    mutation.into_iter().collect()
+}
+
+/**
+ * Havoc stage as mutation layer: every seed of the wrapped seeder gets a stack
+ * of random mutations, a power of two up to `max_stacking`, each from a random
+ * operator out of `operators`.
+ */
+pub struct HavocSeedModifier<S: Seedable, R: Rng> {
+    pub seeder: S,
+    rng: R,
+    /* Enabled operators */
+    pub operators: Vec<HavocOperator>,
+    pub max_stacking: usize,
+    /* Operators applied to the last seed, in order */
+    pub last_operators: Vec<HavocOperator>,
+    mutations: Vec<ByteMutation>,
+    /* Adaptive choice among the enabled operators, uniform if None */
+    pub scheduler: Option<OperatorScheduler>,
+    /* Distinct seeds of the wrapped seeder to splice with */
+    donors: Vec<Vec<u8>>,
+}
+
+#[allow(unused)]
+impl<S: Seedable, R: Rng> HavocSeedModifier<S, R> {
+    pub fn init(seeder: S, rng: R) -> HavocSeedModifier<S, R> {
+        HavocSeedModifier::init_with_operators(seeder, rng, ALL_HAVOC_OPERATORS.to_vec(), DEFAULT_HAVOC_STACKING)
+    }
+
+    pub fn init_with_operators(seeder: S, rng: R, operators: Vec<HavocOperator>, max_stacking: usize) -> HavocSeedModifier<S, R> {
+        assert!(!operators.is_empty() && max_stacking >= 1);
+        HavocSeedModifier { seeder, rng, operators, max_stacking, last_operators: Vec::new(), mutations: Vec::new(), scheduler: None, donors: Vec::new() }
+    }
+
+    /**
+     * Chooses the stacked operators by how often they led to new behavior
+     * instead of uniformly.
+     */
+    pub fn enable_scheduler(&mut self) -> &mut OperatorScheduler {
+        let names: Vec<&str> = self.operators.iter().map(|o| o.name()).collect();
+        self.scheduler.insert(OperatorScheduler::init(&names))
+    }
+
+    /*
+     * Keeps `seed` as splicing partner, replacing a random one when there are
+     * enough already.
+     */
+    fn add_donor(&mut self, seed: &[u8]) {
+        if self.donors.iter().any(|d| d == seed) {
+            return;
+        }
+        if self.donors.len() < SPLICE_DONORS {
+            self.donors.push(seed.to_vec());
+        } else {
+            let index = self.rng.gen_range(0..self.donors.len());
+            self.donors[index] = seed.to_vec();
+        }
+    }
+
+    /*
+     * Splices `input` with an earlier seed of the seeder below, if there is one.
+     */
+    fn splice(&mut self, input: &[u8]) -> Vec<ByteMutation> {
+        let Some(donor) = self.donors.choose(&mut self.rng) else {
+            return Vec::new();
+        };
+        let operator = *[SpliceOperator::SinglePoint, SpliceOperator::TwoPoint, SpliceOperator::Token].choose(&mut self.rng).unwrap();
+        match splice(operator, input, donor, &mut self.rng) {
+            Some(result) => replacement_mutations(input, &result),
+            None => Vec::new(),
+        }
+    }
+
+    /**
+     * Applies a stack of havoc mutations to `input`.
+     */
+    pub fn havoc(&mut self, input: &mut Vec<u8>) {
+        self.mutations.clear();
+        self.last_operators.clear();
+        let stacking = 1 << self.rng.gen_range(0..=self.max_stacking.ilog2());
+        for _ in 0..stacking {
+            let operator = match &self.scheduler {
+                Some(scheduler) => self.operators[scheduler.choose(&mut self.rng)],
+                None => *self.operators.choose(&mut self.rng).unwrap(),
+            };
+            let mutations = match operator {
+                HavocOperator::Splice => self.splice(input),
+                _ => havoc_mutation(operator, input, &mut self.rng),
+            };
+            if mutations.is_empty() {
+                continue;
+            }
+            for mutation in mutations {
+                mutation.apply(input);
+                self.mutations.push(mutation);
+            }
+            self.last_operators.push(operator);
+        }
+    }
+}
+
+impl<S: Seedable, R: Rng> Seedable for HavocSeedModifier<S, R> {
+    fn next_seed(&mut self) -> Vec<u8> {
+        let mut seed = self.seeder.next_seed();
+        self.add_donor(&seed);
+        self.havoc(&mut seed);
+        seed
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        if self.mutations.is_empty() {
+            self.seeder.last_derivation_tree()
+        } else {
+            None
+        }
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.seeder.grammar_coverage()
+    }
+
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        let mut mutations = self.seeder.last_mutations();
+        mutations.extend_from_slice(&self.mutations);
+        mutations
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        if let Some(scheduler) = &mut self.scheduler {
+            let used: Vec<usize> = self.last_operators.iter().filter_map(|o| self.operators.iter().position(|e| e == o)).collect();
+            scheduler.reward(&used, result);
+        }
+        self.seeder.report_result(result);
+    }
+
+    fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+        self.seeder.report_foreign(input, result);
+    }
+
+    fn report(&self, out: &mut Vec<String>) {
+        self.seeder.report(out);
+        if let Some(scheduler) = &self.scheduler {
+            out.push(format!("Operators: {}", scheduler.report()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::corpus::{Corpus, CorpusSeeder, SchedulePolicy};
+    use crate::runner::State;
+    use crate::seeder::RandomSeeder;
+
+    #[test]
+    fn test_every_operator_changes_input() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u8> = (0..64).collect();
+        for operator in ALL_HAVOC_OPERATORS {
+            assert_eq!(operator.name().parse::<HavocOperator>(), Ok(operator));
+            if operator == HavocOperator::Splice {
+                continue;
+            }
+            let changed = (0..20).any(|_| ByteMutation::replay(&input, &havoc_mutation(operator, &input, &mut rng)) != input);
+            assert!(changed, "{}", operator.name());
+        }
+        // only insertion works on empty inputs, dwords need 4 bytes
+        assert!(havoc_mutation(HavocOperator::BitFlip, &[], &mut rng).is_empty());
+        assert!(!havoc_mutation(HavocOperator::BlockInsert, &[], &mut rng).is_empty());
+        assert!(havoc_mutation(HavocOperator::DwordArithmetic, &[1, 2, 3], &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_interesting_values_include_narrower() {
+        assert!(INTERESTING_8.iter().all(|&v| INTERESTING_16.contains(&(v as i16))));
+        assert!(INTERESTING_16.iter().all(|&v| INTERESTING_32.contains(&(v as i32))));
+    }
+
+    #[test]
+    fn test_arithmetic_changes_one_field() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let input = vec![0u8; 8];
+            let mutated = ByteMutation::replay(&input, &havoc_mutation(HavocOperator::WordArithmetic, &input, &mut rng));
+            let changed: Vec<usize> = (0..8).filter(|&i| mutated[i] != 0).collect();
+            assert!(!changed.is_empty() && changed.len() <= 2);
+            assert!(changed.len() == 1 || changed[1] == changed[0] + 1);
+        }
+    }
+
+    #[test]
+    fn test_havoc_seeder_stacks_enabled_operators() {
+        let random_seeder = RandomSeeder::<StdRng>::init(3);
+        let mut reference = RandomSeeder::<StdRng>::init(3);
+        let operators = vec![HavocOperator::BitFlip, HavocOperator::ByteSwap];
+        let mut seeder = HavocSeedModifier::init_with_operators(random_seeder, StdRng::seed_from_u64(4), operators.clone(), 8);
+        let mut stack_sizes = Vec::new();
+        for _ in 0..50 {
+            let seed = seeder.next_seed();
+            let original = reference.next_seed();
+            assert_eq!(original.len(), seed.len());
+            assert_eq!(ByteMutation::replay(&original, &seeder.last_mutations()), seed);
+            assert!(seeder.last_operators.iter().all(|o| operators.contains(o)));
+            stack_sizes.push(seeder.last_operators.len());
+        }
+        assert!(stack_sizes.iter().all(|&s| (1..=8).contains(&s)));
+        assert!(stack_sizes.contains(&8));
+    }
+
+    #[test]
+    fn test_splice_with_corpus_entries() {
+        let mut corpus = Corpus::new();
+        corpus.add(b"a=1&b=2".to_vec());
+        corpus.add(b"c=33;d=4".to_vec());
+        let corpus_seeder = CorpusSeeder::init(corpus, SchedulePolicy::RoundRobin, RandomSeeder::<StdRng>::init(0), StdRng::seed_from_u64(0));
+        let mut seeder = HavocSeedModifier::init_with_operators(corpus_seeder, StdRng::seed_from_u64(1), vec![HavocOperator::Splice], 1);
+        let mut spliced = 0;
+        for round in 0..40 {
+            let seed = seeder.next_seed();
+            assert_eq!(ByteMutation::replay(&seeder.seeder.corpus.entries[round % 2].input, &seeder.last_mutations()), seed);
+            spliced += !seeder.last_mutations().is_empty() as usize;
+        }
+        assert!(spliced > 10);
+        // the first seed has nothing to splice with but itself
+        let mut seeder = HavocSeedModifier::init_with_operators(RandomSeeder::<StdRng>::init(0), StdRng::seed_from_u64(1), vec![HavocOperator::Splice], 4);
+        seeder.next_seed();
+        assert!(seeder.last_mutations().is_empty());
+    }
+
+    #[test]
+    fn test_scheduler_learns_useful_operator() {
+        let operators = vec![HavocOperator::BitFlip, HavocOperator::BlockInsert];
+        let mut seeder = HavocSeedModifier::init_with_operators(RandomSeeder::<StdRng>::init(5), StdRng::seed_from_u64(6), operators, 1);
+        seeder.enable_scheduler();
+        // only block insertion changes the length, which is the behavior here
+        for _ in 0..300 {
+            let seed = seeder.next_seed();
+            let behavior = if seeder.last_operators.contains(&HavocOperator::BlockInsert) { seed.len() as u64 } else { 0 };
+            seeder.report_result(&RunnerResult::for_test(State::Pass, &seed, behavior));
+        }
+        let scheduler = seeder.scheduler.as_ref().unwrap();
+        assert_eq!(scheduler.operators[1].name, "block-insert");
+        assert!(scheduler.probability(1) > 0.8);
+        assert!(scheduler.operators[0].uses > 0);
+    }
+}
@@ -1,3 +1,7 @@
+use std::fs;
+use std::path::Path;
+
+use crate::grammar_export::tree_to_dot;
 use crate::runner::RunnerProgramResult;
 
 
@@ -15,6 +19,24 @@ pub fn log_crash(res: &RunnerProgramResult) {
     println!("Seed: \t\t\t{:?}", res.result.seed);
     println!("Seed Ascii: \t\t{:?}", String::from_utf8_lossy(&res.result.seed));
     println!("Return code: \t\t{:?}", res.return_code);
+    if !res.result.mutations.is_empty() {
+        println!("Mutations: \t\t{:?}", res.result.mutations);
+    }
     //println!("Stdout: \t\t{:?}", String::from_utf8_lossy(&res.output_stdout));
     //println!("Stderr: \t\t{:?}", String::from_utf8_lossy(&res.output_stderr));
 }
+
+/**
+ * Writes the derivation tree of the `index`-th crash as crash-<index>.dot into `directory`,
+ * if the seeder provided one.
+ */
+pub fn log_crash_tree(res: &RunnerProgramResult, directory: &Path, index: usize) {
+    let Some(tree) = &res.result.derivation_tree else {
+        return;
+    };
+    let path = directory.join(format!("crash-{}.dot", index));
+    match fs::create_dir_all(directory).and_then(|_| fs::write(&path, tree_to_dot(tree))) {
+        Ok(()) => println!("Derivation tree: \t{}", path.display()),
+        Err(e) => println!("Cannot write derivation tree {}: {}", path.display(), e),
+    }
+}
@@ -1,57 +1,599 @@
+// the original code style, kept as it is
+#![allow(clippy::redundant_field_names, clippy::manual_is_multiple_of, clippy::single_match, clippy::assign_op_pattern, clippy::len_zero)]
+
 mod runner;
 mod seeder;
 mod grammar;
 mod fuzzer;
 mod logger;
+mod derivation;
+mod tree_mutator;
+mod earley;
+mod terminals;
+mod grammar_file;
+mod ebnf;
+mod regex_terminal;
+mod constraints;
+mod grammar_coverage;
+mod enumerator;
+mod grammar_library;
+mod grammar_inference;
+mod grammar_export;
+mod generation_context;
+mod encoders;
+mod havoc;
+mod deterministic;
+mod dictionary;
+mod corpus;
+mod splicing;
+mod power_schedule;
+mod operator_scheduler;
+mod ensemble;
+mod minimizer;
+
 
+use std::path::{Path, PathBuf};
+use std::process::exit;
 
-use clap::Parser;
-use fuzzer::FuzzerProgram;
-use grammar::create_cgi_grammar;
+use clap::builder::PossibleValuesParser;
+use clap::{Parser, Subcommand, ValueEnum};
+use constraints::ConstraintSet;
+use fuzzer::{FuzzerProgram, GeneticConfig};
+use grammar_library::{library_grammar, LIBRARY_GRAMMARS};
+use grammar_file::{format_ebnf_grammar, load_grammar};
+use grammar_inference::infer_grammar_from_directory;
+use grammar_export::{grammar_to_bnf, grammar_to_dot, grammar_to_json};
+use grammar::Grammar;
+use ebnf::LengthBounds;
+use encoders::{EncoderChain, EncodingSeeder, EncodingTarget};
+use dictionary::{binary_tokens, grammar_tokens, Dictionary, DictionarySeedModifier, TokenSource};
+use enumerator::{EnumerationBounds, EnumerationSeeder};
+use corpus::{Corpus, CorpusSeeder, SchedulePolicy};
+use earley::ImportSummary;
+use power_schedule::PowerSchedule;
+use operator_scheduler::OperatorScheduler;
+use minimizer::{minimize, same_crash_bucket};
+use ensemble::{EnsembleMember, EnsembleSeeder, EnsembleSpec, SeederKind};
+use deterministic::{DeterministicSeeder, DeterministicStage, ALL_DETERMINISTIC_STAGES};
+use havoc::{HavocOperator, HavocSeedModifier, ALL_HAVOC_OPERATORS, DEFAULT_HAVOC_STACKING};
 use rand::rngs::StdRng;
 use rand::SeedableRng;
-use runner::RunnerProgram;
-use seeder::{GrammarSeeder, MutationSeedModifier};
+use runner::{RunnableProgram, RunnerProgram, State};
+use seeder::{GrammarSeeder, MutationSeedModifier, RandomSeeder, Seedable, TreeMutationSeeder};
+use splicing::{SpliceOperator, ALL_SPLICE_OPERATORS};
+use terminals::TerminalRegistry;
+use tree_mutator::{TreeMutationStrategy, ALL_TREE_MUTATION_STRATEGIES};
 
 #[derive(Parser)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Program under test
+    #[arg(long, global = true, default_value = "./SUTs/CGI_crashy_asan")]
+    sut: String,
+
     /// Set the logging level (trace, debug, info, warn, error)
     #[arg(short, long, default_value = "info")]
     log_level: String,
+
+    /// Built-in grammar to fuzz with
+    #[arg(long, global = true, default_value = "cgi", value_parser = PossibleValuesParser::new(LIBRARY_GRAMMARS.map(|(name, _)| name)))]
+    grammar: String,
+
+    /// Load the grammar from a grammar file instead of using a built-in grammar
+    #[arg(long, global = true, conflicts_with = "grammar")]
+    grammar_file: Option<PathBuf>,
+
+    /// Maximum number of extra repetitions generated for open-ended repetitions (*, +, {m,}) in grammar files
+    #[arg(long, global = true, default_value_t = LengthBounds::default().max_repetitions)]
+    max_repetitions: usize,
+
+    /// Fraction of the inputs in which each length, checksum or equality constraint of the grammar is broken on purpose
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.0, value_parser = parse_fraction)]
+    constraint_violation: f64,
+
+    /// Instead of random generation, enumerate every input with derivation trees up to this depth
+    #[arg(long)]
+    enumerate_depth: Option<usize>,
+
+    /// Maximum input length in bytes when enumerating
+    #[arg(long, default_value_t = 16)]
+    enumerate_length: usize,
+
+    /// Write the derivation tree of every crashing input as DOT file into this directory
+    #[arg(long)]
+    crash_trees: Option<PathBuf>,
+
+    /// Encoders applied to every input before it is run, comma-separated, e.g. "gzip,base64".
+    /// Available: url, base64, hex, deflate, gzip, slip, cobs, length-u8, length-u16le/be, length-u32le/be, length-u64le/be, length-decimal
+    #[arg(long, default_value = "")]
+    encode: EncoderChain,
+
+    /// Mutate generated inputs with stacks of havoc mutations instead of single byte changes
+    #[arg(long)]
+    havoc: bool,
+
+    /// Maximum number of stacked havoc mutations per input
+    #[arg(long, default_value_t = DEFAULT_HAVOC_STACKING)]
+    havoc_stacking: usize,
+
+    /// Enabled havoc operators, comma-separated, all by default (splice only with --corpus). Available: bit-flip, byte-arith, word-arith,
+    /// dword-arith, interesting, block-delete, block-duplicate, block-insert, block-overwrite, byte-swap, chunk-shuffle,
+    /// splice (with --corpus)
+    #[arg(long, value_delimiter = ',')]
+    havoc_operators: Vec<HavocOperator>,
+
+    /// Run the deterministic stages on every generated input, yielding each of its bit flip, arithmetic,
+    /// interesting value and dictionary mutants once before the next input is generated
+    #[arg(long, conflicts_with = "havoc")]
+    deterministic: bool,
+
+    /// Enabled deterministic stages, comma-separated, all by default. Available: flip1, flip2, flip4, flip8,
+    /// arith8, arith16, arith32, interest8, interest16, interest32, dict-overwrite, dict-insert
+    #[arg(long, value_delimiter = ',')]
+    deterministic_stages: Vec<DeterministicStage>,
+
+    /// AFL-format dictionary file whose tokens are inserted into or written over inputs, can be repeated
+    #[arg(long = "dict")]
+    dictionaries: Vec<PathBuf>,
+
+    /// Highest level of the dictionary tokens loaded, as in name@level="value"
+    #[arg(long, default_value_t = 0)]
+    dict_level: u32,
+
+    /// Add the literals of the grammar and the strings in the read-only data of the target to the dictionary
+    #[arg(long)]
+    auto_dict: bool,
+
+    /// Directory of seed inputs; mutate corpus entries instead of generated inputs and add every
+    /// mutant with new behavior (return code, output size) to the corpus. Entries are parsed with
+    /// the grammar to get their derivation trees
+    #[arg(long)]
+    corpus: Option<PathBuf>,
+
+    /// How corpus entries are picked: round-robin, weighted (small and fast entries more often)
+    /// or favored (mostly the smallest and fastest entry of each behavior)
+    #[arg(long, default_value = "favored", requires = "corpus")]
+    schedule: SchedulePolicy,
+
+    /// Power schedule assigning each picked corpus entry a number of mutants: explore (fast, short,
+    /// deep and recently productive entries get more), exp (doubling with each pick), fast (exp divided
+    /// by how often the entry's behavior was seen), rare (rarely seen behaviors get more) or coe (fast,
+    /// skipping entries whose behavior was seen more often than average). Without it, one mutant per pick
+    #[arg(long, requires = "corpus")]
+    power_schedule: Option<PowerSchedule>,
+
+    /// Run a genetic loop: children are bred by crossover of inputs chosen by tournament, fitness is the
+    /// rarity of their behavior. The seeder provides the first population and fresh immigrants.
+    /// Encoded inputs are crossed as they are, so this cannot be combined with --encode
+    #[arg(long, conflicts_with = "encode")]
+    genetic: bool,
+
+    /// Individuals in the population of the genetic loop
+    #[arg(long, default_value_t = GeneticConfig::default().population_size)]
+    population_size: usize,
+
+    /// Enabled crossover operators of the genetic loop, comma-separated, all by default.
+    /// Available: single-point, two-point, token, subtree
+    #[arg(long, value_delimiter = ',')]
+    splice_operators: Vec<SpliceOperator>,
+
+    /// Choose mutation operators (of --havoc, or insert/remove/replace otherwise) by how often
+    /// they led to new behavior or new crashes, a multi-armed bandit, instead of uniformly
+    #[arg(long, conflicts_with = "deterministic")]
+    adaptive_operators: bool,
+
+    /// File for the learned operator weights: restored at start if it exists, saved every 100 runs
+    #[arg(long, requires = "adaptive_operators")]
+    operator_weights: Option<PathBuf>,
+
+    /// Combine several seeders in one campaign, comma-separated with optional weights, e.g.
+    /// grammar=2,random,tree. Available: grammar, random, corpus (needs --corpus), tree (mutated
+    /// derivation trees, starting from the --corpus files). The mutation options apply to the seeds
+    /// of every member
+    #[arg(long, value_delimiter = ',')]
+    ensemble: Vec<EnsembleSpec>,
+
+    /// Tree mutation strategies of the tree member of --ensemble, comma-separated, all by default.
+    /// Available: regenerate, crossover, expand, minimize, regenerate-token
+    #[arg(long, value_delimiter = ',')]
+    tree_strategies: Vec<TreeMutationStrategy>,
+
+    /// Pick the members of the ensemble by how often their seeds led to new behavior or crashes
+    /// instead of by their weights
+    #[arg(long, requires = "ensemble")]
+    ensemble_adaptive: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Infer a grammar from a directory of sample inputs and print it in the grammar file syntax
+    Infer {
+        /// Directory with one sample input per file
+        samples: PathBuf,
+
+        /// Write the grammar to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Only keep generalizations the program under test accepts (exit code 0)
+        #[arg(long)]
+        oracle: bool,
+    },
+    /// Shrink a crashing input while it still crashes with the same behavior and write the result
+    Minimize {
+        /// File with the crashing input
+        input: PathBuf,
+
+        /// Write the minimized input to this file instead of the input file name with ".min" appended
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Encoders of the target as for fuzzing; the file then holds the input before encoding, and the
+        /// decoded input is shrunk
+        #[arg(long, default_value = "")]
+        encode: EncoderChain,
+    },
+    /// Print the selected grammar
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Bnf)]
+        format: ExportFormat,
+
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// Graphviz graph of the symbols, recursion in red
+    Dot,
+    /// Grammar file syntax
+    Bnf,
+    Json,
+}
+
+fn write_output(text: &str, output: Option<PathBuf>) {
+    match output {
+        Some(path) => std::fs::write(&path, text).unwrap_or_else(|e| {
+            eprintln!("Cannot write {}: {}", path.display(), e);
+            exit(1);
+        }),
+        None => print!("{}", text),
+    }
+}
+
+fn export(grammar: &Grammar, format: ExportFormat, output: Option<PathBuf>) {
+    let text = match format {
+        ExportFormat::Dot => grammar_to_dot(grammar),
+        ExportFormat::Bnf => grammar_to_bnf(grammar),
+        ExportFormat::Json => grammar_to_json(grammar),
+    };
+    write_output(&text, output);
+}
+
+fn infer(sut: &str, samples: &Path, output: Option<PathBuf>, use_oracle: bool) {
+    let program = RunnerProgram::init(sut);
+    let oracle = |input: &[u8]| matches!(program.run(input).result.state, State::Pass);
+    let grammar = infer_grammar_from_directory(samples, if use_oracle { Some(&oracle) } else { None })
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot infer a grammar from {}: {}", samples.display(), e);
+            exit(1);
+        });
+    write_output(&format_ebnf_grammar(&grammar), output);
+}
+
+fn parse_fraction(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("'{}' is no fraction between 0 and 1", text)),
+    }
+}
+
+fn check_nul_output(encode: &EncoderChain) {
+    if let Some(encoder) = encode.nul_output() {
+        eprintln!("The output of the encoder {:?} can contain NUL bytes, which cannot be passed as an argument to the target. End the chain with a text encoder, e.g. gzip,base64", encoder);
+        exit(1);
+    }
+}
+
+fn minimize_file(sut: &str, path: &Path, output: Option<PathBuf>, encode: EncoderChain) {
+    check_nul_output(&encode);
+    let program = EncodingTarget::init(RunnerProgram::init(sut), encode);
+    let mut input = std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Cannot read {}: {}", path.display(), e);
+        exit(1);
+    });
+    program.sanitize_seed(&mut input);
+    let original = program.run(&input).result;
+    if !matches!(original.state, State::Fail) {
+        eprintln!("{} does not crash {}", path.display(), sut);
+        exit(1);
+    }
+    let minimized = minimize(&input, &program, same_crash_bucket(&original));
+    let output = output.unwrap_or_else(|| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".min");
+        PathBuf::from(name)
+    });
+    if let Err(e) = std::fs::write(&output, &minimized.input) {
+        eprintln!("Cannot write {}: {}", output.display(), e);
+        exit(1);
+    }
+    println!("Minimized {} to {} bytes in {} runs: {}", input.len(), minimized.input.len(), minimized.runs, output.display());
+}
 
 fn main() {
 
     let cli = Cli::parse();
     std::env::set_var("RUST_LOG", cli.log_level);
     env_logger::init();
+
+    if let Some(Command::Infer { samples, output, oracle }) = cli.command {
+        infer(&cli.sut, &samples, output, oracle);
+        return;
+    }
+    if let Some(Command::Minimize { input, output, encode }) = cli.command {
+        minimize_file(&cli.sut, &input, output, encode);
+        return;
+    }
     
-    let runner_program: RunnerProgram = RunnerProgram::init("./SUTs/CGI_crashy_asan");
+    let runner_program: RunnerProgram = RunnerProgram::init(&cli.sut);
     let rng = StdRng::from_entropy();
-    let grammar_cgi = create_cgi_grammar();
-    let grammar_seeder = GrammarSeeder::init(grammar_cgi, rng);
-    let mutation_grammar_seeder = MutationSeedModifier::init(grammar_seeder);
+    let (grammar, mut constraints) = match cli.grammar_file {
+        Some(path) => load_grammar(&path, &LengthBounds { max_repetitions: cli.max_repetitions }).unwrap_or_else(|e| {
+            eprintln!("Invalid grammar file {}: {}", path.display(), e);
+            exit(1);
+        }),
+        None => library_grammar(&cli.grammar).unwrap(),
+    };
+    constraints.violation_fraction = cli.constraint_violation;
+    if let Some(Command::Export { format, output }) = cli.command {
+        export(&grammar, format, output);
+        return;
+    }
+    let encode = cli.encode;
+    check_nul_output(&encode);
+    let run = RunOptions {
+        crash_trees: cli.crash_trees,
+        operator_weights: cli.operator_weights,
+        genetic: cli.genetic.then(|| GeneticConfig {
+            population_size: cli.population_size.max(1),
+            operators: if cli.splice_operators.is_empty() { ALL_SPLICE_OPERATORS.to_vec() } else { cli.splice_operators },
+            ..GeneticConfig::default()
+        }),
+    };
+    if let Some(max_depth) = cli.enumerate_depth {
+        let bounds = EnumerationBounds { max_depth, max_length: cli.enumerate_length };
+        let enumeration_seeder = EnumerationSeeder::init(grammar, TerminalRegistry::init(), &constraints, &bounds)
+            .unwrap_or_else(|errors| invalid_constraints(errors));
+        fuzz(runner_program, EncodingSeeder::init(enumeration_seeder, encode), run);
+        return;
+    }
+    let mut dictionary = Dictionary::new();
+    for path in &cli.dictionaries {
+        if let Err(e) = dictionary.load_file(path, cli.dict_level) {
+            eprintln!("Invalid dictionary {}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+    if cli.auto_dict {
+        dictionary.extend(grammar_tokens(&grammar, &TerminalRegistry::init()), TokenSource::Grammar);
+        match binary_tokens(Path::new(&cli.sut)) {
+            Ok(tokens) => {
+                dictionary.extend(tokens, TokenSource::Binary);
+            },
+            Err(e) => eprintln!("Cannot read strings of {}: {}", cli.sut, e),
+        }
+    }
+    let options = MutationOptions {
+        havoc: cli.havoc.then(|| {
+            if cli.corpus.is_none() && cli.havoc_operators.contains(&HavocOperator::Splice) {
+                eprintln!("The havoc operator splice needs --corpus");
+                exit(1);
+            }
+            // splicing needs corpus entries to splice with
+            let operators = if cli.havoc_operators.is_empty() {
+                ALL_HAVOC_OPERATORS.into_iter().filter(|&o| o != HavocOperator::Splice || cli.corpus.is_some()).collect()
+            } else {
+                cli.havoc_operators
+            };
+            (operators, cli.havoc_stacking.max(1))
+        }),
+        deterministic: cli.deterministic.then(|| {
+            if cli.deterministic_stages.is_empty() { ALL_DETERMINISTIC_STAGES.to_vec() } else { cli.deterministic_stages }
+        }),
+        adaptive: cli.adaptive_operators,
+        dictionary,
+        encode,
+        run,
+    };
+    if !cli.ensemble.is_empty() {
+        let members = cli.ensemble.iter().map(|spec| {
+            let seeder: Box<dyn Seedable> = match spec.kind {
+                SeederKind::Grammar => Box::new(grammar_seeder(&grammar, &constraints)),
+                SeederKind::Random => Box::new(RandomSeeder::<StdRng>::init(rand::random())),
+                SeederKind::Corpus => {
+                    let Some(directory) = &cli.corpus else {
+                        eprintln!("The corpus seeder of the ensemble needs --corpus");
+                        exit(1);
+                    };
+                    let fallback = grammar_seeder(&grammar, &constraints);
+                    let mut corpus_seeder = CorpusSeeder::init(load_corpus(directory, &grammar, &runner_program, &options.encode), cli.schedule, fallback, StdRng::from_entropy());
+                    corpus_seeder.power_schedule = cli.power_schedule;
+                    Box::new(corpus_seeder)
+                },
+                SeederKind::Tree => {
+                    let strategies = if cli.tree_strategies.is_empty() { ALL_TREE_MUTATION_STRATEGIES.to_vec() } else { cli.tree_strategies.clone() };
+                    let mut tree_seeder = TreeMutationSeeder::init_with_strategies(grammar_seeder(&grammar, &constraints), strategies);
+                    // the corpus files are the first trees to mutate
+                    if let Some(directory) = &cli.corpus {
+                        match tree_seeder.import_directory(directory) {
+                            Ok(summary) => print_import("Tree seeder", &summary),
+                            Err(e) => eprintln!("Cannot import samples from {}: {}", directory.display(), e),
+                        }
+                    }
+                    Box::new(tree_seeder)
+                },
+            };
+            EnsembleMember { name: spec.kind.name().to_string(), seeder, weight: spec.weight }
+        }).collect();
+        fuzz_mutated(runner_program, EnsembleSeeder::init(members, cli.ensemble_adaptive, StdRng::from_entropy()), options);
+        return;
+    }
+    if let Some(directory) = cli.corpus {
+        let corpus = load_corpus(&directory, &grammar, &runner_program, &options.encode);
+        let grammar_seeder = GrammarSeeder::init_with_constraints(grammar, constraints, rng).unwrap_or_else(|errors| invalid_constraints(errors));
+        let mut corpus_seeder = CorpusSeeder::init(corpus, cli.schedule, grammar_seeder, StdRng::from_entropy());
+        corpus_seeder.power_schedule = cli.power_schedule;
+        fuzz_mutated(runner_program, corpus_seeder, options);
+        return;
+    }
+    let grammar_seeder = GrammarSeeder::init_with_constraints(grammar, constraints, rng).unwrap_or_else(|errors| invalid_constraints(errors));
+    fuzz_mutated(runner_program, grammar_seeder, options);
+}
 
-    //let mut fuzzer = FuzzerProgram::init(runner_program, grammar_seeder);
-    let mut fuzzer = FuzzerProgram::init(runner_program, mutation_grammar_seeder);
-    
-    fuzzer.run_forever();
+/*
+ * Grammar seeder for a member of an ensemble.
+ */
+fn grammar_seeder(grammar: &Grammar, constraints: &ConstraintSet) -> GrammarSeeder<StdRng> {
+    GrammarSeeder::init_with_constraints(grammar.clone(), constraints.clone(), StdRng::from_entropy())
+        .unwrap_or_else(|errors| invalid_constraints(errors))
+}
+
+fn invalid_constraints(errors: Vec<String>) -> ! {
+    for error in errors {
+        eprintln!("Invalid constraint: {}", error);
+    }
+    exit(1);
+}
+
+/*
+ * Prints the samples which did not parse and the summary of an import.
+ */
+fn print_import(name: &str, summary: &ImportSummary) {
+    for failure in &summary.failures {
+        eprintln!("Cannot parse {}", failure);
+    }
+    println!("{}: {}", name, summary.report());
+}
+
+/*
+ * Loads the seed inputs of `directory`, parses them with the grammar and runs
+ * each one to measure it.
+ */
+fn load_corpus(directory: &Path, grammar: &Grammar, runner_program: &RunnerProgram, encode: &EncoderChain) -> Corpus {
+    let mut corpus = Corpus::new();
+    if let Err(e) = corpus.load_directory(directory) {
+        eprintln!("Cannot load corpus {}: {}", directory.display(), e);
+        exit(1);
+    }
+    print_import("Corpus trees", &corpus.parse_trees(grammar, &TerminalRegistry::init()));
+    corpus.calibrate(runner_program, |input| encode.encode(input));
+    println!("Corpus: {}", corpus.report());
+    corpus
+}
+
+/*
+ * Mutation layers put on top of the base seeder, and what happens to their output.
+ */
+struct MutationOptions {
+    /* Enabled operators and maximum stacking */
+    havoc: Option<(Vec<HavocOperator>, usize)>,
+    /* Enabled stages */
+    deterministic: Option<Vec<DeterministicStage>>,
+    /* Adaptive operator scheduling of the havoc or default mutation layer */
+    adaptive: bool,
+    dictionary: Dictionary,
+    encode: EncoderChain,
+    run: RunOptions,
+}
+
+/*
+ * How the fuzzer runs the seeds.
+ */
+struct RunOptions {
+    crash_trees: Option<PathBuf>,
+    /* Where the operator scheduler loads and saves its weights */
+    operator_weights: Option<PathBuf>,
+    /* Genetic loop instead of running every seed of the seeder */
+    genetic: Option<GeneticConfig>,
+}
 
+fn fuzz_mutated(runner_program: RunnerProgram, seeder: impl Seedable, options: MutationOptions) {
+    let MutationOptions { havoc, deterministic, adaptive, dictionary, encode, run } = options;
+    if let Some((operators, max_stacking)) = havoc {
+        let mut havoc_seeder = HavocSeedModifier::init_with_operators(seeder, StdRng::from_entropy(), operators, max_stacking);
+        if adaptive {
+            load_operator_weights(havoc_seeder.enable_scheduler(), &run.operator_weights);
+        }
+        fuzz_with_dictionary(runner_program, havoc_seeder, dictionary, encode, run);
+    } else if let Some(stages) = deterministic {
+        let deterministic_seeder = DeterministicSeeder::init_with_stages(seeder, stages, dictionary.values());
+        fuzz(runner_program, EncodingSeeder::init(deterministic_seeder, encode), run);
+    } else {
+        let mut mutation_seeder = MutationSeedModifier::init(seeder, StdRng::from_entropy());
+        if adaptive {
+            load_operator_weights(mutation_seeder.enable_scheduler(), &run.operator_weights);
+        }
+        fuzz_with_dictionary(runner_program, mutation_seeder, dictionary, encode, run);
+    }
+}
+
+/*
+ * Restores the weights saved by an earlier session, if there is a file already,
+ * and saves the weights to the file from now on.
+ */
+fn load_operator_weights(scheduler: &mut OperatorScheduler, path: &Option<PathBuf>) {
+    scheduler.file = path.clone();
+    let Some(path) = path.as_ref().filter(|p| p.exists()) else {
+        return;
+    };
+    match scheduler.load(path) {
+        Ok(count) => println!("Restored the weights of {} operators from {}", count, path.display()),
+        Err(e) => {
+            eprintln!("Invalid operator weights {}: {}", path.display(), e);
+            exit(1);
+        },
+    }
+}
+
+/*
+ * Puts the dictionary layer on top of `seeder`, unless the dictionary is empty.
+ */
+fn fuzz_with_dictionary(runner_program: RunnerProgram, seeder: impl Seedable, dictionary: Dictionary, encode: EncoderChain, run: RunOptions) {
+    if dictionary.is_empty() {
+        fuzz(runner_program, EncodingSeeder::init(seeder, encode), run);
+    } else {
+        let dictionary_seeder = DictionarySeedModifier::init(seeder, dictionary, StdRng::from_entropy());
+        fuzz(runner_program, EncodingSeeder::init(dictionary_seeder, encode), run);
+    }
+}
+
+fn fuzz(runner_program: RunnerProgram, seeder: impl Seedable, run: RunOptions) {
+    let mut fuzzer = FuzzerProgram::init(runner_program, seeder);
+    fuzzer.crash_tree_directory = run.crash_trees;
+    match run.genetic {
+        Some(config) => fuzzer.run_genetic(&config, None, &mut StdRng::from_entropy()),
+        None => fuzzer.run_forever(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use grammar::create_cgi_grammar;
     use rand::rngs::{StdRng, ThreadRng};
     use runner::{Runnable, RunnerPrinter, RunnerResult, State};
-    use seeder::{RandomSeeder, Seedable};
+    use seeder::{ByteMutation, RandomSeeder, Seedable, TreeMutationSeeder};
 
     use super::*;
 
     #[test]
     fn test_runner_printer() {
         let runner_printer: RunnerPrinter = RunnerPrinter::init();
-        let res: RunnerResult = runner_printer.run(&"test_seed".as_bytes().to_vec());
+        let res: RunnerResult = runner_printer.run("test_seed".as_bytes());
         matches!(res.state, State::Pass);
     }
 
@@ -76,5 +618,108 @@ mod tests {
         assert_ne!(seed2, seed3);
     }
 
+    #[test]
+    fn test_stacked_mutation_layers() {
+        let random_seeder: RandomSeeder<StdRng> = RandomSeeder::<StdRng>::init(7);
+        let mut reference: RandomSeeder<StdRng> = RandomSeeder::<StdRng>::init(7);
+        let inner = MutationSeedModifier::init(random_seeder, StdRng::seed_from_u64(1));
+        let mut stacked = MutationSeedModifier::init(inner, StdRng::seed_from_u64(2));
+        let mut mutated = 0;
+        for _ in 0..50 {
+            let seed = stacked.next_seed();
+            let mutations = stacked.last_mutations();
+            assert!(mutations.len() <= 2);
+            mutated += mutations.len();
+            // replaying the logged mutations on the original seed gives the mutated seed
+            assert_eq!(ByteMutation::replay(&reference.next_seed(), &mutations), seed);
+        }
+        assert!(mutated > 50);
+        let mut input = b"abc".to_vec();
+        ByteMutation::Remove { position: 0 }.apply(&mut input);
+        ByteMutation::Insert { position: 2, byte: b'd' }.apply(&mut input);
+        assert_eq!(input, b"bcd".to_vec());
+    }
+
+    #[test]
+    fn test_mutation_layer_drops_stale_tree() {
+        let grammar_seeder = GrammarSeeder::init(create_cgi_grammar(), StdRng::seed_from_u64(3));
+        let mut seeder = MutationSeedModifier::init(grammar_seeder, StdRng::seed_from_u64(4));
+        for _ in 0..30 {
+            let seed = seeder.next_seed();
+            match seeder.last_derivation_tree() {
+                Some(tree) => assert_eq!(tree.to_bytes(), seed),
+                None => assert!(!seeder.last_mutations().is_empty()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_mutation_seeder() {
+        let grammar_seeder = GrammarSeeder::init(create_cgi_grammar(), StdRng::seed_from_u64(5));
+        let mut tree_seeder = TreeMutationSeeder::init(grammar_seeder);
+        tree_seeder.population_size = 4;
+        for _ in 0..50 {
+            let seed = tree_seeder.next_seed();
+            assert_eq!(tree_seeder.last_derivation_tree().unwrap().to_bytes(), seed);
+        }
+        assert_eq!(tree_seeder.population.len(), 4);
+
+        // mutated trees with a new behavior join the population
+        for behavior in 0.. {
+            tree_seeder.next_seed();
+            tree_seeder.report_result(&RunnerResult::for_test(State::Pass, b"", behavior));
+            if tree_seeder.last_strategy.is_some() {
+                break;
+            }
+        }
+        let tree = tree_seeder.last_derivation_tree().unwrap().clone();
+        assert!(tree_seeder.population.contains(&tree));
+        assert_eq!("regenerate-token".parse::<TreeMutationStrategy>(), Ok(TreeMutationStrategy::RegenerateToken));
+    }
+
+    #[test]
+    fn test_tree_mutation_seeder_import_sample() {
+        let grammar_seeder = GrammarSeeder::init(create_cgi_grammar(), StdRng::seed_from_u64(5));
+        let mut tree_seeder = TreeMutationSeeder::init(grammar_seeder);
+        tree_seeder.import_sample(b"a+%4F").unwrap();
+        assert!(tree_seeder.import_sample(b"a b").is_err());
+        assert_eq!(tree_seeder.population.len(), 1);
+        assert_eq!(tree_seeder.population[0].to_bytes(), b"a+%4F".to_vec());
+
+        let directory = std::env::temp_dir().join(format!("ruzzer-samples-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("1"), b"ab+%41").unwrap();
+        std::fs::write(directory.join("2"), b"a b").unwrap();
+        let summary = tree_seeder.import_directory(&directory).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!((summary.parsed, summary.failures.len()), (1, 1));
+        assert_eq!(tree_seeder.population[1].to_bytes(), b"ab+%41".to_vec());
+    }
+
+    #[test]
+    fn test_constraints_hold_after_mutation() {
+        // Start -> String "#" Checksum, Checksum is the CRC-16 of String
+        let mut grammar = create_cgi_grammar();
+        grammar.insert("Start".to_string(), vec![vec![
+            grammar::GrammarRule::NonTerminal("String".to_string()),
+            grammar::GrammarRule::Terminal(b"#".to_vec()),
+            grammar::GrammarRule::Terminal(b"MAGIC-u16be".to_vec()),
+        ]]);
+        let mut constraint_set = constraints::ConstraintSet::empty();
+        constraint_set.add(constraints::Constraint::checksum("Start", 0, 2, vec![0],
+            constraints::ConstraintKind::Crc16, constraints::FieldEncoding::big_endian(2).unwrap()));
+        let mut invalid = constraint_set.clone();
+        invalid.add(constraints::Constraint::equal("Start", 0, 7, 0));
+        assert!(GrammarSeeder::init_with_constraints(grammar.clone(), invalid, StdRng::seed_from_u64(6)).is_err());
+        let grammar_seeder = GrammarSeeder::init_with_constraints(grammar, constraint_set, StdRng::seed_from_u64(6)).unwrap();
+        let mut tree_seeder = TreeMutationSeeder::init(grammar_seeder);
+        tree_seeder.population_size = 4;
+        for _ in 0..50 {
+            let seed = tree_seeder.next_seed();
+            let (string, crc) = seed.split_at(seed.len() - 3);
+            assert_eq!(crc[1..], constraints::crc16(string).to_be_bytes());
+        }
+    }
+
     
 }
\ No newline at end of file
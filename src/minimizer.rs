@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+
+use crate::runner::{RunnableProgram, RunnerResult, State};
+
+/*
+ * Replacements tried for every byte by the simplification pass, simplest first.
+ */
+const SIMPLE_BYTES: [u8; 2] = [b'0', b'a'];
+
+/**
+ * Result of a minimization.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Minimization {
+    pub input: Vec<u8>,
+    /* Runs of the target, including the one of the original input */
+    pub runs: usize,
+}
+
+/**
+ * Predicate which accepts crashing runs with the behavior signature of
+ * `original`, i.e. the same crash bucket.
+ */
+pub fn same_crash_bucket(original: &RunnerResult) -> impl Fn(&RunnerResult) -> bool {
+    let behavior = original.behavior;
+    move |result: &RunnerResult| matches!(result.state, State::Fail) && result.behavior == behavior
+}
+
+/*
+ * Runs candidates and remembers which were tried, so no input runs twice.
+ */
+struct Tester<'a, R: RunnableProgram, P: Fn(&RunnerResult) -> bool> {
+    runner: &'a R,
+    reproduces: P,
+    tried: HashSet<Vec<u8>>,
+}
+
+impl<R: RunnableProgram, P: Fn(&RunnerResult) -> bool> Tester<'_, R, P> {
+    fn test(&mut self, candidate: &[u8]) -> bool {
+        if !self.tried.insert(candidate.to_vec()) {
+            return false;
+        }
+        (self.reproduces)(&self.runner.run(candidate).result)
+    }
+
+    /*
+     * Removes blocks of halving size from the end, then from the start.
+     */
+    fn trim(&mut self, input: &mut Vec<u8>) {
+        for from_end in [true, false] {
+            let mut size = input.len() / 2;
+            while size > 0 {
+                let candidate = if from_end { &input[..input.len() - size] } else { &input[size..] };
+                if self.test(candidate) {
+                    *input = candidate.to_vec();
+                    size = size.min(input.len());
+                } else {
+                    size /= 2;
+                }
+            }
+        }
+    }
+
+    /*
+     * ddmin: removes one of `n` chunks at a time, starting with halves. After a
+     * success the granularity is coarsened by one, otherwise it is doubled
+     * until the chunks are single bytes.
+     */
+    fn ddmin(&mut self, input: &mut Vec<u8>) {
+        let mut n = 2;
+        while input.len() >= 2 {
+            let chunk = input.len().div_ceil(n);
+            let removed = (0..input.len()).step_by(chunk).find_map(|start| {
+                let candidate = [&input[..start], &input[(start + chunk).min(input.len())..]].concat();
+                self.test(&candidate).then_some(candidate)
+            });
+            match removed {
+                Some(candidate) => {
+                    *input = candidate;
+                    n = (n - 1).max(2);
+                },
+                None if n >= input.len() => break,
+                None => n = (2 * n).min(input.len()),
+            }
+        }
+        if input.len() == 1 && self.test(&[]) {
+            input.clear();
+        }
+    }
+
+    /*
+     * Replaces every byte by the simplest byte which still reproduces.
+     */
+    fn simplify(&mut self, input: &mut [u8]) {
+        for position in 0..input.len() {
+            for byte in SIMPLE_BYTES {
+                if input[position] == byte {
+                    break;
+                }
+                let original = input[position];
+                input[position] = byte;
+                if self.test(input) {
+                    break;
+                }
+                input[position] = original;
+            }
+        }
+    }
+}
+
+/**
+ * Shrinks `input` while `reproduces` accepts its runs: trimming the ends,
+ * ddmin chunk removal and replacing bytes with '0' or 'a', repeated until
+ * nothing changes. The input is returned unchanged if it does not reproduce.
+ */
+pub fn minimize(input: &[u8], runner: &impl RunnableProgram, reproduces: impl Fn(&RunnerResult) -> bool) -> Minimization {
+    let mut tester = Tester { runner, reproduces, tried: HashSet::new() };
+    let mut input = input.to_vec();
+    if tester.test(&input) {
+        loop {
+            let before = input.clone();
+            tester.trim(&mut input);
+            tester.ddmin(&mut input);
+            tester.simplify(&mut input);
+            if input == before {
+                break;
+            }
+        }
+    }
+    Minimization { input, runs: tester.tried.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::FnTarget;
+
+    /* Crashes on "%z" with behavior 1 and on a trailing "%" with behavior 2 */
+    fn percent_target(seed: &[u8]) -> (State, u64) {
+        let behavior = if seed.windows(2).any(|w| w == b"%z") { 1 } else if seed.len() >= 4 && seed.ends_with(b"%") { 2 } else { 0 };
+        (if behavior > 0 { State::Fail } else { State::Pass }, behavior)
+    }
+
+    #[test]
+    fn test_minimize_keeps_crash_bucket() {
+        let input = b"name=alice&comment=hello%zworld&x=%";
+        let original = FnTarget(percent_target).run(input).result;
+        let minimized = minimize(input, &FnTarget(percent_target), same_crash_bucket(&original));
+        assert_eq!(minimized.input, b"%z");
+        assert!(minimized.runs < 200);
+
+        // any crash will do, here the trailing "%"
+        let minimized = minimize(b"name=alice&x=%", &FnTarget(percent_target), |r| matches!(r.state, State::Fail));
+        assert_eq!(minimized.input, b"000%");
+    }
+
+    #[test]
+    fn test_minimize_without_crash() {
+        let minimized = minimize(b"name=alice", &FnTarget(percent_target), |r| matches!(r.state, State::Fail));
+        assert_eq!(minimized, Minimization { input: b"name=alice".to_vec(), runs: 1 });
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rand::Rng;
+
+use crate::runner::{RunnerResult, State};
+
+/*
+ * Share of the picks which are uniform, so that operators with a low weight
+ * are still tried now and then (gamma of EXP3).
+ */
+const EXPLORATION: f64 = 0.1;
+
+/*
+ * Limits of a weight relative to the mean weight. They keep the scheduler
+ * adaptive: an operator that was lucky early can be overtaken later.
+ */
+const MIN_WEIGHT: f64 = 0.05;
+const MAX_WEIGHT: f64 = 20.0;
+
+/*
+ * Rewards of a run for the operators applied to its input.
+ */
+const NEW_BEHAVIOR_REWARD: f64 = 0.5;
+const NEW_CRASH_REWARD: f64 = 1.0;
+
+/*
+ * Rewarded runs between two saves to the weights file, as often as the
+ * fuzzer prints its status.
+ */
+const SAVE_EVERY: u64 = 100;
+
+/*
+ * Labels of the lines with the seen behaviors in the weights file.
+ */
+const BEHAVIORS_LABEL: &str = "behaviors:";
+const CRASH_BEHAVIORS_LABEL: &str = "crash-behaviors:";
+
+/**
+ * Learned weight and success counts of a mutation operator.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperatorStats {
+    pub name: String,
+    pub weight: f64,
+    /* Runs of inputs the operator was applied to */
+    pub uses: u64,
+    /* Runs with a behavior not seen before */
+    pub finds: u64,
+    /* Crashing runs with a behavior not seen in a crash before */
+    pub crashes: u64,
+}
+
+/**
+ * Adaptive choice among mutation operators, a multi-armed bandit (EXP3).
+ * Operators whose inputs lead to new behavior (return code, output size) or
+ * new crash buckets are rewarded and chosen more often.
+ */
+#[derive(Debug, Clone)]
+pub struct OperatorScheduler {
+    pub operators: Vec<OperatorStats>,
+    /* File the weights are saved to regularly, see save() */
+    pub file: Option<PathBuf>,
+    rewards: u64,
+    behaviors: HashSet<u64>,
+    crash_behaviors: HashSet<u64>,
+}
+
+impl OperatorScheduler {
+    pub fn init(names: &[&str]) -> OperatorScheduler {
+        assert!(!names.is_empty());
+        let operators = names.iter()
+            .map(|name| OperatorStats { name: name.to_string(), weight: 1.0, uses: 0, finds: 0, crashes: 0 })
+            .collect();
+        OperatorScheduler { operators, file: None, rewards: 0, behaviors: HashSet::new(), crash_behaviors: HashSet::new() }
+    }
+
+    /**
+     * Probability of choosing the operator `index`.
+     */
+    pub fn probability(&self, index: usize) -> f64 {
+        let total: f64 = self.operators.iter().map(|o| o.weight).sum();
+        let count = self.operators.len() as f64;
+        (1.0 - EXPLORATION) * self.operators[index].weight / total + EXPLORATION / count
+    }
+
+    /**
+     * Index of the operator to apply next.
+     */
+    pub fn choose(&self, rng: &mut impl Rng) -> usize {
+        let mut target = rng.gen::<f64>();
+        for index in 0..self.operators.len() {
+            target -= self.probability(index);
+            if target < 0.0 {
+                return index;
+            }
+        }
+        self.operators.len() - 1
+    }
+
+    /**
+     * Rewards the operators `used` for the input of `result`. An operator
+     * applied several times to the input is rewarded once.
+     */
+    pub fn reward(&mut self, used: &[usize], result: &RunnerResult) {
+        let new_behavior = self.behaviors.insert(result.behavior);
+        let new_crash = matches!(result.state, State::Fail) && self.crash_behaviors.insert(result.behavior);
+        let reward = if new_crash { NEW_CRASH_REWARD } else if new_behavior { NEW_BEHAVIOR_REWARD } else { 0.0 };
+        let mut used = used.to_vec();
+        used.sort_unstable();
+        used.dedup();
+        let count = self.operators.len() as f64;
+        // importance-weighted estimate, the probabilities before any update
+        let estimates: Vec<f64> = used.iter().map(|&index| reward / self.probability(index)).collect();
+        for (&index, estimate) in used.iter().zip(estimates) {
+            let operator = &mut self.operators[index];
+            operator.uses += 1;
+            operator.finds += new_behavior as u64;
+            operator.crashes += new_crash as u64;
+            operator.weight *= (EXPLORATION * estimate / count).exp();
+        }
+        if reward > 0.0 {
+            self.normalize();
+        }
+        self.rewards += 1;
+        if let Some(path) = self.file.as_ref().filter(|_| self.rewards.is_multiple_of(SAVE_EVERY)) {
+            if let Err(e) = self.save(path) {
+                eprintln!("Cannot write operator weights {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /*
+     * Scales the weights to a mean of 1 and clamps them.
+     */
+    fn normalize(&mut self) {
+        let mean = self.operators.iter().map(|o| o.weight).sum::<f64>() / self.operators.len() as f64;
+        for operator in &mut self.operators {
+            operator.weight = (operator.weight / mean).clamp(MIN_WEIGHT, MAX_WEIGHT);
+        }
+    }
+
+    /**
+     * Summary like "insert 52% (9 finds, 1 crash), remove 24% (2 finds, 0 crashes)".
+     */
+    pub fn report(&self) -> String {
+        let operators: Vec<String> = (0..self.operators.len()).map(|index| {
+            let operator = &self.operators[index];
+            let crashes = if operator.crashes == 1 { "crash" } else { "crashes" };
+            format!("{} {:.0}% ({} finds, {} {})", operator.name, 100.0 * self.probability(index), operator.finds, operator.crashes, crashes)
+        }).collect();
+        operators.join(", ")
+    }
+
+    /**
+     * Writes one line per operator: name, weight, uses, finds and crashes,
+     * then the behaviors and crash behaviors seen so far, which decide what
+     * is new after a restart.
+     */
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::from("# operator weight uses finds crashes\n");
+        for o in &self.operators {
+            text.push_str(&format!("{} {} {} {} {}\n", o.name, o.weight, o.uses, o.finds, o.crashes));
+        }
+        for (label, behaviors) in [(BEHAVIORS_LABEL, &self.behaviors), (CRASH_BEHAVIORS_LABEL, &self.crash_behaviors)] {
+            let mut behaviors: Vec<&u64> = behaviors.iter().collect();
+            behaviors.sort_unstable();
+            let behaviors: Vec<String> = behaviors.iter().map(|b| b.to_string()).collect();
+            text.push_str(&format!("{} {}\n", label, behaviors.join(" ")));
+        }
+        fs::write(path, text)
+    }
+
+    /**
+     * Restores the operators and behaviors saved by save(), ignoring operators
+     * which are not enabled. Returns the number of restored operators.
+     */
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid operator weight line '{}'", line));
+        let mut restored = 0;
+        for line in fs::read_to_string(path)?.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let behaviors = match fields[0] {
+                BEHAVIORS_LABEL => Some(&mut self.behaviors),
+                CRASH_BEHAVIORS_LABEL => Some(&mut self.crash_behaviors),
+                _ => None,
+            };
+            if let Some(behaviors) = behaviors {
+                for behavior in &fields[1..] {
+                    behaviors.insert(behavior.parse().map_err(|_| invalid(line))?);
+                }
+                continue;
+            }
+            let [name, weight, uses, finds, crashes] = fields[..] else {
+                return Err(invalid(line));
+            };
+            let weight: f64 = weight.parse().ok().filter(|w: &f64| w.is_finite() && *w > 0.0).ok_or_else(|| invalid(line))?;
+            let counts: Vec<u64> = [uses, finds, crashes].iter().map(|c| c.parse()).collect::<Result<_, _>>().map_err(|_| invalid(line))?;
+            if let Some(operator) = self.operators.iter_mut().find(|o| o.name == name) {
+                *operator = OperatorStats { name: name.to_string(), weight, uses: counts[0], finds: counts[1], crashes: counts[2] };
+                restored += 1;
+            }
+        }
+        self.normalize();
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn result(state: State, behavior: u64) -> RunnerResult {
+        RunnerResult::for_test(state, b"", behavior)
+    }
+
+    #[test]
+    fn test_rewarded_operator_is_preferred() {
+        let mut scheduler = OperatorScheduler::init(&["insert", "remove", "replace"]);
+        let mut rng = StdRng::seed_from_u64(0);
+        // only "remove" ever finds something new
+        for behavior in 0..300 {
+            let index = scheduler.choose(&mut rng);
+            let behavior = if index == 1 { behavior } else { 1000 };
+            scheduler.reward(&[index], &result(State::Pass, behavior));
+        }
+        assert!(scheduler.probability(1) > 0.8);
+        assert!(scheduler.probability(0) >= EXPLORATION / 3.0);
+        let counts: Vec<u64> = (0..1000).map(|_| scheduler.choose(&mut rng)).fold(vec![0; 3], |mut c, i| { c[i] += 1; c });
+        assert!(counts[1] > 700);
+
+        let uses = scheduler.operators[0].uses;
+        scheduler.reward(&[0, 0], &result(State::Fail, 5000));
+        assert_eq!((scheduler.operators[0].uses, scheduler.operators[0].crashes), (uses + 1, 1));
+        assert!(scheduler.report().starts_with("insert "));
+        assert!(scheduler.report().contains(" 1 crash), remove "));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut scheduler = OperatorScheduler::init(&["insert", "remove"]);
+        scheduler.reward(&[1], &result(State::Pass, 7));
+        let path = std::env::temp_dir().join(format!("ruzzer-operators-{}", std::process::id()));
+        scheduler.save(&path).unwrap();
+
+        let mut restored = OperatorScheduler::init(&["remove", "replace"]);
+        assert_eq!(restored.load(&path).unwrap(), 1);
+        let remove = &restored.operators[0];
+        assert_eq!((remove.uses, remove.finds), (1, 1));
+        assert!(restored.probability(0) > restored.probability(1));
+        // the restored behaviors are not new anymore
+        restored.reward(&[0], &result(State::Pass, 7));
+        assert_eq!(restored.operators[0].finds, 1);
+
+        fs::write(&path, "insert heavy 1 0 0\n").unwrap();
+        assert_eq!(restored.load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+
+        // with a file, the weights are saved regularly
+        restored.file = Some(path.clone());
+        for behavior in 0..SAVE_EVERY {
+            restored.reward(&[0], &result(State::Pass, behavior));
+        }
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
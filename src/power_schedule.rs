@@ -0,0 +1,197 @@
+use std::str::FromStr;
+
+use crate::corpus::Corpus;
+
+/*
+ * Seeds per pick for an entry with an average performance score of 100.
+ */
+const BASE_ENERGY: f64 = 16.0;
+const MAX_ENERGY: usize = 1024;
+
+/*
+ * Limit of the schedule factor, M in AFLFast.
+ */
+const MAX_FACTOR: f64 = 32.0;
+
+/*
+ * Picks without a new entry after which an entry counts as stale.
+ */
+const STALE_PICKS: u64 = 16;
+
+/**
+ * Power schedules deciding how many seeds the corpus seeder derives from an
+ * entry each time it is picked, following AFLFast.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PowerSchedule {
+    /* Performance score only */
+    Explore,
+    /* Doubling with each pick, divided by how often the entry's behavior was hit */
+    Fast,
+    /* Doubling with each pick */
+    Exponential,
+    /* Inversely proportional to how often the entry's behavior was hit */
+    Rare,
+    /* Like fast, but entries whose behavior is hit more often than average are skipped */
+    Coe,
+}
+
+pub const ALL_POWER_SCHEDULES: [PowerSchedule; 5] = [
+    PowerSchedule::Explore,
+    PowerSchedule::Fast,
+    PowerSchedule::Exponential,
+    PowerSchedule::Rare,
+    PowerSchedule::Coe,
+];
+
+impl PowerSchedule {
+    /**
+     * Name on the command line, e.g. "exp".
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerSchedule::Explore => "explore",
+            PowerSchedule::Fast => "fast",
+            PowerSchedule::Exponential => "exp",
+            PowerSchedule::Rare => "rare",
+            PowerSchedule::Coe => "coe",
+        }
+    }
+}
+
+impl FromStr for PowerSchedule {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<PowerSchedule, String> {
+        ALL_POWER_SCHEDULES.into_iter()
+            .find(|s| s.name() == name)
+            .ok_or_else(|| format!("unknown power schedule '{}'", name))
+    }
+}
+
+/*
+ * Factor for a value relative to the corpus mean, as AFL's calculate_score()
+ * does for the execution time: far above the mean gives less, far below more.
+ */
+fn relative_factor(value: f64, mean: f64) -> f64 {
+    if mean <= 0.0 {
+        return 1.0;
+    }
+    match value / mean {
+        r if r > 10.0 => 0.1,
+        r if r > 4.0 => 0.25,
+        r if r > 2.0 => 0.5,
+        r if r > 1.33 => 0.75,
+        r if r < 0.25 => 3.0,
+        r if r < 0.33 => 2.0,
+        r if r < 0.5 => 1.5,
+        _ => 1.0,
+    }
+}
+
+/**
+ * AFL-style performance score of the entry `index`, 100 for an average entry:
+ * fast, short and deep entries score higher, as do entries which led to a new
+ * entry on their last pick, while entries stale for long score lower.
+ */
+pub fn performance_score(corpus: &Corpus, index: usize) -> f64 {
+    let entry = &corpus.entries[index];
+    let times: Vec<f64> = corpus.entries.iter().filter_map(|e| e.exec_time).map(|t| t.as_secs_f64()).collect();
+    let mean_time = times.iter().sum::<f64>() / times.len().max(1) as f64;
+    let mean_len = corpus.entries.iter().map(|e| e.input.len()).sum::<usize>() as f64 / corpus.len().max(1) as f64;
+
+    let mut score = 100.0;
+    if let Some(time) = entry.exec_time {
+        score *= relative_factor(time.as_secs_f64(), mean_time);
+    }
+    score *= relative_factor(entry.input.len() as f64, mean_len);
+    score *= match entry.depth {
+        0..=3 => 1.0,
+        4..=7 => 2.0,
+        8..=13 => 3.0,
+        14..=25 => 4.0,
+        _ => 5.0,
+    };
+    let since_find = entry.selected - entry.last_find;
+    if entry.finds > 0 && since_find <= 1 {
+        score *= 2.0;
+    } else if since_find >= STALE_PICKS {
+        score *= 0.5;
+    }
+    score
+}
+
+/**
+ * Seeds to derive from the entry `index` on this pick under `schedule`,
+ * between 1 and MAX_ENERGY, or 0 if the coe schedule skips the entry.
+ */
+pub fn energy(corpus: &Corpus, index: usize, schedule: PowerSchedule) -> usize {
+    let entry = &corpus.entries[index];
+    let picks = 2f64.powi(entry.selected.min(16) as i32);
+    let hits = entry.behavior.map_or(1, |b| corpus.hits(b).max(1)) as f64;
+    let mean_hits = corpus.mean_hits().max(1.0);
+    let factor = match schedule {
+        PowerSchedule::Explore => 1.0,
+        PowerSchedule::Exponential => picks,
+        PowerSchedule::Fast => picks * mean_hits / hits,
+        PowerSchedule::Rare => mean_hits / hits,
+        PowerSchedule::Coe if hits > mean_hits => return 0,
+        PowerSchedule::Coe => picks * mean_hits / hits,
+    };
+    let energy = BASE_ENERGY * performance_score(corpus, index) / 100.0 * factor.min(MAX_FACTOR);
+    (energy.round() as usize).clamp(1, MAX_ENERGY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::runner::{RunnerResult, State};
+
+    fn corpus(entries: &[(&[u8], u64, u64, u64)]) -> Corpus {
+        let mut corpus = Corpus::new();
+        for &(input, micros, behavior, hits) in entries {
+            let index = corpus.add(input.to_vec());
+            let result = RunnerResult { exec_time: Duration::from_micros(micros), ..RunnerResult::for_test(State::Pass, input, behavior) };
+            corpus.record(index, &result);
+            for _ in 0..hits {
+                corpus.hit(behavior);
+            }
+        }
+        corpus
+    }
+
+    #[test]
+    fn test_performance_score() {
+        let mut corpus = corpus(&[(b"a=1", 100, 1, 1), (b"a=2", 100, 2, 1), (b"a=3", 400, 3, 1), (b"a=4", 100, 4, 1)]);
+        assert_eq!(performance_score(&corpus, 0), 100.0);
+        assert_eq!(performance_score(&corpus, 2), 50.0);
+        corpus.entries[1].depth = 9;
+        assert_eq!(performance_score(&corpus, 1), 300.0);
+        corpus.entries[3].selected = STALE_PICKS;
+        assert_eq!(energy(&corpus, 3, PowerSchedule::Explore), 8);
+        corpus.entries[3].finds = 1;
+        corpus.entries[3].last_find = STALE_PICKS;
+        assert_eq!(energy(&corpus, 3, PowerSchedule::Explore), 32);
+    }
+
+    #[test]
+    fn test_schedules() {
+        // behavior 1 is hit far more often than the others
+        let mut corpus = corpus(&[(b"a=1", 100, 1, 70), (b"a=2", 100, 2, 5), (b"a=3", 100, 3, 5)]);
+        assert_eq!(energy(&corpus, 0, PowerSchedule::Coe), 0);
+        assert!(energy(&corpus, 1, PowerSchedule::Coe) > 0);
+        assert!(energy(&corpus, 0, PowerSchedule::Rare) < energy(&corpus, 1, PowerSchedule::Rare));
+        assert!(energy(&corpus, 0, PowerSchedule::Fast) < energy(&corpus, 1, PowerSchedule::Fast));
+        assert_eq!(energy(&corpus, 0, PowerSchedule::Explore), energy(&corpus, 1, PowerSchedule::Explore));
+
+        let before = energy(&corpus, 1, PowerSchedule::Exponential);
+        corpus.entries[1].selected = 2;
+        corpus.entries[1].last_find = 2;
+        assert_eq!(energy(&corpus, 1, PowerSchedule::Exponential), 4 * before);
+        corpus.entries[1].selected = 40;
+        assert!(energy(&corpus, 1, PowerSchedule::Exponential) <= MAX_ENERGY);
+        assert_eq!("exp".parse::<PowerSchedule>(), Ok(PowerSchedule::Exponential));
+    }
+}
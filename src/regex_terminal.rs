@@ -0,0 +1,275 @@
+use rand::{Rng, RngCore};
+use regex_syntax::hir::{Class, Hir, HirKind};
+use regex_syntax::ParserBuilder;
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::ebnf::LengthBounds;
+
+/**
+ * Terminal whose value is generated from a regular expression, e.g. [0-9A-F]{2}.
+ * Supports the full regex syntax of the regex crate, including Unicode classes
+ * like \p{Greek} and byte-oriented patterns like (?-u:\xFF).
+ * Open-ended repetitions (*, +, {m,}) produce at most `max_repetitions`
+ * occurrences above their minimum.
+ */
+#[derive(Clone)]
+pub struct RegexTerminal {
+    pattern: String,
+    hir: Hir,
+    max_repetitions: u32,
+}
+
+impl fmt::Debug for RegexTerminal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{}/", self.pattern)
+    }
+}
+
+impl PartialEq for RegexTerminal {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.max_repetitions == other.max_repetitions
+    }
+}
+
+impl Eq for RegexTerminal {}
+
+impl RegexTerminal {
+    pub fn init(pattern: &str) -> Result<RegexTerminal, String> {
+        RegexTerminal::with_bounds(pattern, &LengthBounds::default())
+    }
+
+    pub fn with_bounds(pattern: &str, bounds: &LengthBounds) -> Result<RegexTerminal, String> {
+        let hir = ParserBuilder::new()
+            .utf8(false)
+            .build()
+            .parse(pattern)
+            .map_err(|e| format!("invalid regex /{}/: {}", pattern, e))?;
+        if matches_nothing(&hir) {
+            return Err(format!("regex /{}/ matches nothing", pattern));
+        }
+        Ok(RegexTerminal { pattern: pattern.to_string(), hir, max_repetitions: bounds.max_repetitions as u32 })
+    }
+
+    /**
+     * Name of the terminal in derivation trees and grammar files, e.g. "/[0-9]+/".
+     */
+    pub fn name(&self) -> String {
+        format!("/{}/", self.pattern)
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.generate_hir(&self.hir, rng, &mut out);
+        out
+    }
+
+    fn generate_hir(&self, hir: &Hir, rng: &mut dyn RngCore, out: &mut Vec<u8>) {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Look(_) => {},
+            HirKind::Literal(literal) => out.extend_from_slice(&literal.0),
+            HirKind::Class(Class::Bytes(class)) => {
+                let total: u32 = class.ranges().iter().map(|r| r.end() as u32 - r.start() as u32 + 1).sum();
+                let mut index = rng.gen_range(0..total);
+                for range in class.ranges() {
+                    let size = range.end() as u32 - range.start() as u32 + 1;
+                    if index < size {
+                        out.push(range.start() + index as u8);
+                        break;
+                    }
+                    index -= size;
+                }
+            },
+            HirKind::Class(Class::Unicode(class)) => {
+                let total: u32 = class.ranges().iter().map(|r| r.end() as u32 - r.start() as u32 + 1).sum();
+                let mut index = rng.gen_range(0..total);
+                for range in class.ranges() {
+                    let size = range.end() as u32 - range.start() as u32 + 1;
+                    if index < size {
+                        // Unicode class ranges never span surrogates
+                        let c = char::from_u32(range.start() as u32 + index).unwrap_or(range.start());
+                        let mut buffer = [0; 4];
+                        out.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                        break;
+                    }
+                    index -= size;
+                }
+            },
+            HirKind::Repetition(repetition) => {
+                let max = repetition.max.unwrap_or(repetition.min + self.max_repetitions);
+                for _ in 0..rng.gen_range(repetition.min..=max) {
+                    self.generate_hir(&repetition.sub, rng, out);
+                }
+            },
+            HirKind::Capture(capture) => self.generate_hir(&capture.sub, rng, out),
+            HirKind::Concat(parts) => {
+                for part in parts {
+                    self.generate_hir(part, rng, out);
+                }
+            },
+            HirKind::Alternation(alternatives) => {
+                let alternative = &alternatives[rng.gen_range(0..alternatives.len())];
+                self.generate_hir(alternative, rng, out);
+            },
+        }
+    }
+
+    /**
+     * Lengths of all prefixes of `input` matched by the regex, used for parsing.
+     * Anchors are ignored, the token is always matched at the start of `input`.
+     */
+    pub fn match_prefix(&self, input: &[u8]) -> Vec<usize> {
+        match_ends(&self.hir, input, 0).into_iter().collect()
+    }
+}
+
+/*
+ * All positions where a match of `hir` starting at `start` can end.
+ */
+fn match_ends(hir: &Hir, input: &[u8], start: usize) -> BTreeSet<usize> {
+    let mut ends = BTreeSet::new();
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => {
+            ends.insert(start);
+        },
+        HirKind::Literal(literal) => {
+            if input[start..].starts_with(&literal.0) {
+                ends.insert(start + literal.0.len());
+            }
+        },
+        HirKind::Class(Class::Bytes(class)) => {
+            if let Some(&b) = input.get(start) {
+                if class.ranges().iter().any(|r| r.start() <= b && b <= r.end()) {
+                    ends.insert(start + 1);
+                }
+            }
+        },
+        HirKind::Class(Class::Unicode(class)) => {
+            if let Some((c, length)) = decode_char(&input[start..]) {
+                if class.ranges().iter().any(|r| r.start() <= c && c <= r.end()) {
+                    ends.insert(start + length);
+                }
+            }
+        },
+        HirKind::Repetition(repetition) => {
+            let mut current: BTreeSet<usize> = BTreeSet::from([start]);
+            if repetition.min == 0 {
+                ends.insert(start);
+            }
+            let mut count = 0;
+            while !current.is_empty() && repetition.max.is_none_or(|max| count < max) {
+                count += 1;
+                let next: BTreeSet<usize> = current.iter()
+                    .flat_map(|&p| match_ends(&repetition.sub, input, p))
+                    .collect();
+                if count >= repetition.min {
+                    let before = ends.len();
+                    ends.extend(next.iter().copied());
+                    // only empty matches left, further rounds find nothing new
+                    if ends.len() == before && next.is_subset(&current) {
+                        break;
+                    }
+                }
+                current = next;
+            }
+        },
+        HirKind::Capture(capture) => ends = match_ends(&capture.sub, input, start),
+        HirKind::Concat(parts) => {
+            ends.insert(start);
+            for part in parts {
+                ends = ends.iter().flat_map(|&p| match_ends(part, input, p)).collect();
+            }
+        },
+        HirKind::Alternation(alternatives) => {
+            for alternative in alternatives {
+                ends.extend(match_ends(alternative, input, start));
+            }
+        },
+    }
+    ends
+}
+
+/*
+ * Decodes the UTF-8 character at the start of `input` and returns it with its length.
+ */
+fn decode_char(input: &[u8]) -> Option<(char, usize)> {
+    let length = match *input.first()? {
+        b if b < 0x80 => 1,
+        b if b >> 5 == 0b110 => 2,
+        b if b >> 4 == 0b1110 => 3,
+        b if b >> 3 == 0b11110 => 4,
+        _ => return None,
+    };
+    let text = std::str::from_utf8(input.get(..length)?).ok()?;
+    text.chars().next().map(|c| (c, length))
+}
+
+/*
+ * Whether no input matches, e.g. empty classes like [a&&b] or \P{any}. An
+ * empty class in an optional part counts as well, so generation never meets one.
+ */
+fn matches_nothing(hir: &Hir) -> bool {
+    hir.properties().minimum_len().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_generate_percent_escape() {
+        let regex = RegexTerminal::init("%[0-9A-F]{2}").unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let value = regex.generate(&mut rng);
+            assert_eq!(value.len(), 3);
+            assert_eq!(value[0], b'%');
+            assert!(value[1..].iter().all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(b)));
+            assert_eq!(regex.match_prefix(&value), vec![3]);
+        }
+    }
+
+    #[test]
+    fn test_generate_and_match_roundtrip() {
+        let patterns = [
+            r"\d{4}-(0[1-9]|1[0-2])-(0[1-9]|[12]\d|3[01])",
+            r"[a-z0-9._]+@[a-z]+\.(com|org|de)",
+            r"\p{Greek}{1,5}",
+            r"(?-u:[\x00-\xff]{3})",
+            r"a*b?",
+        ];
+        let mut rng = StdRng::seed_from_u64(1);
+        for pattern in patterns {
+            let regex = RegexTerminal::init(pattern).unwrap();
+            for _ in 0..50 {
+                let value = regex.generate(&mut rng);
+                assert!(regex.match_prefix(&value).contains(&value.len()), "{} did not match {:?}", pattern, value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reject_regex_matching_nothing() {
+        // an empty class anywhere rejects the regex, even where it could be skipped
+        for pattern in ["[a&&b]", r"(?-u:[^\x00-\xff])", r"\P{any}", "x[a&&b]+", "x|[a&&b]", "x[a&&b]*"] {
+            assert_eq!(RegexTerminal::init(pattern).unwrap_err(), format!("regex /{}/ matches nothing", pattern));
+        }
+        assert!(RegexTerminal::init("x[a&&b]{0}").is_ok());
+    }
+
+    #[test]
+    fn test_match_prefix() {
+        let regex = RegexTerminal::init("ab+").unwrap();
+        assert_eq!(regex.match_prefix(b"abbbc"), vec![2, 3, 4]);
+        assert!(regex.match_prefix(b"ac").is_empty());
+        assert_eq!(RegexTerminal::init("x?").unwrap().match_prefix(b""), vec![0]);
+        assert!(RegexTerminal::init("(").is_err());
+    }
+}
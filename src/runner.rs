@@ -1,5 +1,16 @@
 use core::str;
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsString;
+use std::hash::{Hash, Hasher};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::ExitStatusExt;
 use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crate::derivation::DerivationTree;
+use crate::seeder::ByteMutation;
+#[cfg(test)]
+use crate::seeder::Seedable;
 
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
@@ -12,7 +23,7 @@ pub enum State {
 
 #[allow(unused)]
 pub trait Runnable {
-    fn run(&self, seed: &Vec<u8>) -> RunnerResult;
+    fn run(&self, seed: &[u8]) -> RunnerResult;
 }
 
 pub trait RunnableProgram {
@@ -21,7 +32,7 @@ pub trait RunnableProgram {
      * For example, nul bytes are removed if they are not allowed by the SUT setup. 
      */
     fn sanitize_seed(&self, seed: &mut Vec<u8>);
-    fn run(&self, seed: &Vec<u8>) -> RunnerProgramResult;
+    fn run(&self, seed: &[u8]) -> RunnerProgramResult;
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +40,14 @@ pub struct RunnerResult {
     /* Result of the fuzzing run */
     pub state: State,
     pub seed: Vec<u8>,
+    /* Structure of the seed, if the seeder that produced it knows it */
+    pub derivation_tree: Option<DerivationTree>,
+    /* Byte mutations the seeder applied after producing the seed */
+    pub mutations: Vec<ByteMutation>,
+    /* Time the target ran, zero if it was not run as a process */
+    pub exec_time: Duration,
+    /* Coarse fingerprint of the observable behavior, see behavior_signature() */
+    pub behavior: u64,
 }
 
 
@@ -44,11 +63,15 @@ impl RunnerPrinter {
 }
 
 impl Runnable for RunnerPrinter {
-    fn run(&self, seed: &Vec<u8>) -> RunnerResult {
+    fn run(&self, seed: &[u8]) -> RunnerResult {
         println!("{}", String::from_utf8_lossy(seed));
         RunnerResult { 
             state: (State::Pass),
-            seed: seed.clone() }
+            seed: seed.to_vec(),
+            derivation_tree: None,
+            mutations: Vec::new(),
+            exec_time: Duration::ZERO,
+            behavior: 0 }
     }
 }
 
@@ -58,7 +81,8 @@ pub struct RunnerProgramResult {
     /*
      * Defines the return_code of a run program.
      * Only valid if result != RunnerResult.Unresolved
-     * 256 posix return codes posible 
+     * 256 posix return codes posible, a target killed by a signal gets
+     * 128 + signal like in a shell
      * https://www.gnu.org/savannah-checkouts/gnu/libc/manual/html_node/Exit-Status.html
      */
     pub return_code: u8,
@@ -81,6 +105,21 @@ pub fn print_runner_program_result(result: RunnerProgramResult) {
     println!("Stderr Ascii: {:?}", String::from_utf8_lossy(&result.output_stderr));
 }
 
+/**
+ * Fingerprint of a run from the return code and the magnitude of the output
+ * lengths. The targets are not instrumented, so this stands in for coverage:
+ * inputs with a new signature likely took a different path through the target.
+ * Lengths are bucketed by powers of two so that echoed inputs of similar size
+ * do not count as new behavior. The terminating signal, if any, tells a crash
+ * apart from a target exiting with the same code.
+ */
+pub fn behavior_signature(return_code: u8, signal: Option<i32>, stdout: &[u8], stderr: &[u8]) -> u64 {
+    let bucket = |output: &[u8]| (output.len() + 1).ilog2();
+    let mut hasher = DefaultHasher::new();
+    (return_code, signal, bucket(stdout), bucket(stderr)).hash(&mut hasher);
+    hasher.finish()
+}
+
 fn evaluate_return_code(return_code: u8) -> State {
     match return_code {
         0 => State::Pass,
@@ -97,23 +136,33 @@ impl RunnableProgram for RunnerProgram {
         seed.retain(|&byte| byte != 0);    
     }
 
-    fn run(&self, seed: &Vec<u8>) -> RunnerProgramResult {
-        let arg: String = seed.iter()
-        .filter_map(|&b| if b.is_ascii() { Some(b as char) } else { None })
-        .collect();
+    fn run(&self, seed: &[u8]) -> RunnerProgramResult {
+        // raw bytes, binary encoders need more than ASCII
+        let arg = OsString::from_vec(seed.to_vec());
 
+        let start = Instant::now();
         let output_res = Command::new(self.program_name.clone())
         .arg(&arg)
         .output();
+        let exec_time = start.elapsed();
         
         //handle run program:
         match output_res {
             Ok(value) => {
-                let return_code = value.status.code().unwrap() as u8;
+                // no exit code if the target was killed by a signal
+                let signal = value.status.signal();
+                let return_code = match value.status.code() {
+                    Some(code) => code as u8,
+                    None => 128u8.wrapping_add(signal.unwrap_or(0) as u8),
+                };
                 RunnerProgramResult {
                     result: RunnerResult { 
                         state: evaluate_return_code(return_code),
-                        seed: seed.clone()
+                        seed: seed.to_vec(),
+                        derivation_tree: None,
+                        mutations: Vec::new(),
+                        exec_time,
+                        behavior: behavior_signature(return_code, signal, &value.stdout, &value.stderr),
                      },
                     output_stdout: value.stdout,
                     output_stderr: value.stderr,
@@ -125,7 +174,11 @@ impl RunnableProgram for RunnerProgram {
                 RunnerProgramResult {
                     result: RunnerResult { 
                         state: State::InternalError,
-                        seed: seed.clone()
+                        seed: seed.to_vec(),
+                        derivation_tree: None,
+                        mutations: Vec::new(),
+                        exec_time,
+                        behavior: 0,
                     },
                     output_stdout: Vec::new(),
                     output_stderr: Vec::new(),
@@ -147,3 +200,67 @@ impl RunnerProgram {
     }
 }
 
+#[cfg(test)]
+impl RunnerResult {
+    /**
+     * Result of a run of `seed` for tests, without mutations and run time.
+     */
+    pub fn for_test(state: State, seed: &[u8], behavior: u64) -> RunnerResult {
+        RunnerResult { state, seed: seed.to_vec(), derivation_tree: None, mutations: Vec::new(), exec_time: Duration::ZERO, behavior }
+    }
+}
+
+/**
+ * Target for tests which runs no process, the function maps an input to the
+ * state and behavior of its run.
+ */
+#[cfg(test)]
+pub struct FnTarget<F: Fn(&[u8]) -> (State, u64)>(pub F);
+
+#[cfg(test)]
+impl<F: Fn(&[u8]) -> (State, u64)> RunnableProgram for FnTarget<F> {
+    fn sanitize_seed(&self, _seed: &mut Vec<u8>) {}
+
+    fn run(&self, seed: &[u8]) -> RunnerProgramResult {
+        let (state, behavior) = (self.0)(seed);
+        RunnerProgramResult {
+            result: RunnerResult::for_test(state, seed, behavior),
+            return_code: matches!(state, State::Fail) as u8,
+            output_stdout: Vec::new(),
+            output_stderr: Vec::new(),
+        }
+    }
+}
+
+/**
+ * Seeder for tests which always yields the same seed.
+ */
+#[cfg(test)]
+pub struct ConstantSeeder(pub Vec<u8>);
+
+#[cfg(test)]
+impl Seedable for ConstantSeeder {
+    fn next_seed(&mut self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_killed_target_fails() {
+        let path = std::env::temp_dir().join(format!("ruzzer-segv-{}", std::process::id()));
+        std::fs::write(&path, "#!/bin/sh\nkill -SEGV $$\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let result = RunnerProgram::init(path.to_str().unwrap()).run(b"input");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result.result.state, State::Fail));
+        assert_eq!(result.return_code, 128 + 11);
+        assert_ne!(result.result.behavior, behavior_signature(128 + 11, None, &[], &[]));
+        assert_eq!(result.result.behavior, behavior_signature(128 + 11, Some(11), &[], &[]));
+    }
+}
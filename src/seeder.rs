@@ -1,6 +1,18 @@
+use std::collections::HashSet;
+use std::{fs, io, path::Path};
+
+use log::debug;
 use rand::{rngs::{StdRng, ThreadRng}, Rng, RngCore, SeedableRng};
 
-use crate::grammar::{generate, Grammar, GRAMMAR_ENTRY};
+use crate::constraints::ConstraintSet;
+use crate::derivation::DerivationTree;
+use crate::earley::{EarleyParser, ImportSummary, ParseError};
+use crate::grammar::{Grammar, GRAMMAR_ENTRY};
+use crate::grammar_coverage::{generate_tree_guided, GrammarCoverage, DEFAULT_COVERAGE_K};
+use crate::operator_scheduler::OperatorScheduler;
+use crate::runner::RunnerResult;
+use crate::terminals::TerminalRegistry;
+use crate::tree_mutator::{mutate_tree, shortest_derivations, ShortestDerivations, TreeMutationStrategy, ALL_TREE_MUTATION_STRATEGIES};
 
 /*
  * This is the maximum length of a generated seed in the configuration
@@ -9,8 +21,59 @@ use crate::grammar::{generate, Grammar, GRAMMAR_ENTRY};
 #[allow(unused)]
 const MAX_RANDOM_SEEDER_LENGTH: u64 = 4096;
 
+/*
+ * Number of derivation trees a TreeMutationSeeder keeps as mutation base.
+ * Until the population is full, fresh trees are generated from the grammar.
+ */
+const TREE_POPULATION_SIZE: usize = 64;
+
 pub trait Seedable {
     fn next_seed(&mut self) -> Vec<u8>;
+
+    /*
+     * Derivation tree of the seed returned by the last call to next_seed(),
+     * if the seeder knows the structure of that seed.
+     */
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        None
+    }
+
+    /*
+     * Expansions and k-paths of the grammar covered by the seeds so far,
+     * if the seeder generates from a grammar.
+     */
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        None
+    }
+
+    /*
+     * Byte mutations applied to the last seed after it was produced, in order,
+     * for logging. Empty if the seed was not mutated on the byte level.
+     */
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        Vec::new()
+    }
+
+    /*
+     * Result of running the last seed, so the seeder can learn which of its
+     * choices were useful. Layers pass it on to the seeder below.
+     */
+    fn report_result(&mut self, _result: &RunnerResult) {}
+
+    /*
+     * Result of running `input`, which the seeder did not produce, e.g. a
+     * child bred by the genetic loop. Seeders which keep inputs, like the
+     * corpus, can learn from it. Layers pass it on without crediting their
+     * last choice, which belongs to another seed.
+     */
+    fn report_foreign(&mut self, _input: &[u8], _result: &RunnerResult) {}
+
+    /*
+     * Lines for the status report, like "Corpus: 12 entries, ...", about the
+     * state the seeder learned, e.g. a corpus or operator weights. Layers add
+     * their lines after those of the seeder below.
+     */
+    fn report(&self, _out: &mut Vec<String>) {}
 }
 
 #[allow(unused)]
@@ -71,71 +134,355 @@ impl<T: Rng> Seedable for RandomSeeder<T> {
 
 pub struct GrammarSeeder<T: Rng> {
     pub grammar: Grammar,
-    pub rng: T
+    /* Generators for the MAGIC-* terminals, custom generators can be registered here */
+    pub terminals: TerminalRegistry,
+    /* Length fields, checksums and other dependencies fixed after generation */
+    pub constraints: ConstraintSet,
+    /* Covered expansions, generation prefers alternatives which are not covered yet */
+    pub coverage: GrammarCoverage,
+    pub rng: T,
+    /* Derivation tree of the most recently generated seed */
+    pub last_tree: Option<DerivationTree>,
 }
 
 impl<T: Rng> GrammarSeeder<T> {
     pub fn init(grammar: Grammar, rng: T) -> GrammarSeeder<T> {
-        GrammarSeeder { grammar: grammar, rng: rng }
+        let coverage = GrammarCoverage::init(&grammar, DEFAULT_COVERAGE_K);
+        GrammarSeeder { grammar, terminals: TerminalRegistry::init(), constraints: ConstraintSet::empty(), coverage, rng, last_tree: None }
+    }
+
+    /**
+     * Seeder which applies `constraints` to every tree. Fails with the problems
+     * found by ConstraintSet::validate() if the constraints do not fit the grammar.
+     */
+    pub fn init_with_constraints(grammar: Grammar, constraints: ConstraintSet, rng: T) -> Result<GrammarSeeder<T>, Vec<String>> {
+        constraints.validate(&grammar)?;
+        Ok(GrammarSeeder { constraints, ..GrammarSeeder::init(grammar, rng) })
+    }
+
+    /**
+     * Applies the constraints to a generated or mutated tree.
+     */
+    fn apply_constraints(&mut self, tree: &mut DerivationTree) {
+        let violations = self.constraints.apply(tree, &mut self.rng);
+        if violations > 0 {
+            debug!("Violated {} constraints on purpose", violations);
+        }
     }
 }
 
 impl<T: Rng> Seedable for GrammarSeeder<T> {
     fn next_seed(&mut self) -> Vec<u8> {
-        generate(&self.grammar, GRAMMAR_ENTRY, &mut self.rng).into_bytes()
+        let mut tree = generate_tree_guided(&self.grammar, &self.terminals, &mut self.coverage, GRAMMAR_ENTRY, &mut self.rng);
+        self.apply_constraints(&mut tree);
+        let seed = tree.to_bytes();
+        self.last_tree = Some(tree);
+        seed
+    }
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        self.last_tree.as_ref()
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        Some(&self.coverage)
+    }
+}
+
+/**
+ * Byte-level change made by a mutation layer, positions refer to the seed
+ * as it was before this change.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteMutation {
+    Insert { position: usize, byte: u8 },
+    Remove { position: usize },
+    Replace { position: usize, byte: u8 },
+    /* Inserts `bytes` in front of `position` */
+    InsertBlock { position: usize, bytes: Vec<u8> },
+    RemoveBlock { position: usize, length: usize },
+    /* Replaces the bytes from `position` on with `bytes` */
+    Overwrite { position: usize, bytes: Vec<u8> },
+}
+
+impl ByteMutation {
+    /**
+     * `input` with all `mutations` applied in order.
+     */
+    pub fn replay(input: &[u8], mutations: &[ByteMutation]) -> Vec<u8> {
+        let mut input = input.to_vec();
+        for mutation in mutations {
+            mutation.apply(&mut input);
+        }
+        input
+    }
+
+    pub fn apply(&self, input: &mut Vec<u8>) {
+        match self {
+            ByteMutation::Insert { position, byte } => input.insert(*position, *byte),
+            ByteMutation::Remove { position } => {
+                input.remove(*position);
+            },
+            ByteMutation::Replace { position, byte } => input[*position] = *byte,
+            ByteMutation::InsertBlock { position, bytes } => {
+                input.splice(*position..*position, bytes.iter().copied());
+            },
+            ByteMutation::RemoveBlock { position, length } => {
+                input.drain(*position..*position + *length);
+            },
+            ByteMutation::Overwrite { position, bytes } => input[*position..*position + bytes.len()].copy_from_slice(bytes),
+        }
     }
 }
 
-pub struct MutationSeedModifier<T: Rng> {
-    grammar_seeder: GrammarSeeder<T>
+/**
+ * Mutation layer on top of any seeder: with its own RNG it inserts, removes or
+ * replaces a random byte in three of four seeds and passes the fourth unchanged.
+ * Layers can be stacked, each one mutates the output of the one below.
+ */
+pub struct MutationSeedModifier<S: Seedable, R: Rng> {
+    pub seeder: S,
+    rng: R,
+    /* Mutations of this layer applied to the last seed */
+    mutations: Vec<ByteMutation>,
+    /* Adaptive choice of the operator, uniform if None */
+    pub scheduler: Option<OperatorScheduler>,
+    /* Operator applied to the last seed */
+    last_operator: Option<usize>,
 }
 
-impl<T: Rng> MutationSeedModifier<T> {
-    pub fn init(grammar_seeder: GrammarSeeder<T>) -> MutationSeedModifier<T> {
+/*
+ * Operators of MutationSeedModifier, in the order of choose_mutation().
+ */
+const MUTATION_OPERATORS: [&str; 3] = ["insert", "remove", "replace"];
+
+impl<S: Seedable, R: Rng> MutationSeedModifier<S, R> {
+    pub fn init(seeder: S, rng: R) -> MutationSeedModifier<S, R> {
         MutationSeedModifier {
-            grammar_seeder: grammar_seeder
+            seeder,
+            rng,
+            mutations: Vec::new(),
+            scheduler: None,
+            last_operator: None,
         }
     }
 
-    fn insert_byte(&mut self, input: &mut Vec<u8>) {
-        let byte = self.grammar_seeder.rng.gen::<u8>();
-        let position = self.grammar_seeder.rng.gen_range(0..=input.len()); // Can insert at the end as well
-        input.insert(position, byte);
+    /**
+     * Chooses the operator by how often it led to new behavior instead of
+     * uniformly. Every seed is mutated then.
+     */
+    pub fn enable_scheduler(&mut self) -> &mut OperatorScheduler {
+        self.scheduler.insert(OperatorScheduler::init(&MUTATION_OPERATORS))
+    }
+
+    fn choose_mutation(&mut self, operator: usize, input: &[u8]) -> Option<ByteMutation> {
+        match operator {
+            // Can insert at the end as well
+            0 => Some(ByteMutation::Insert { position: self.rng.gen_range(0..=input.len()), byte: self.rng.gen() }),
+            1 if !input.is_empty() => Some(ByteMutation::Remove { position: self.rng.gen_range(0..input.len()) }),
+            2 if !input.is_empty() => Some(ByteMutation::Replace { position: self.rng.gen_range(0..input.len()), byte: self.rng.gen() }),
+            _ => None,
+        }
     }
-    
-    fn remove_byte(&mut self, input: &mut Vec<u8>) {
-        if !input.is_empty() {
-            let position = self.grammar_seeder.rng.gen_range(0..input.len());
-            input.remove(position);
+}
+
+impl<S: Seedable, R: Rng> Seedable for MutationSeedModifier<S, R> {
+    fn next_seed(&mut self) -> Vec<u8> {
+        let mut seed = self.seeder.next_seed();
+        self.mutations.clear();
+        self.last_operator = None;
+        let operator = match &self.scheduler {
+            Some(scheduler) => scheduler.choose(&mut self.rng),
+            None => self.rng.gen_range(0..4),
+        };
+        if let Some(mutation) = self.choose_mutation(operator, &seed) {
+            mutation.apply(&mut seed);
+            self.mutations.push(mutation);
+            self.last_operator = Some(operator);
         }
+        seed
     }
-    
-    fn modify_byte(&mut self, input: &mut Vec<u8>) {
-        if !input.is_empty() {
-            let position = self.grammar_seeder.rng.gen_range(0..input.len());
-            let new_byte = self.grammar_seeder.rng.gen::<u8>();
-            input[position] = new_byte;
+
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        // the tree is stale once the bytes were changed
+        if self.mutations.is_empty() {
+            self.seeder.last_derivation_tree()
+        } else {
+            None
+        }
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.seeder.grammar_coverage()
+    }
+
+    fn last_mutations(&self) -> Vec<ByteMutation> {
+        let mut mutations = self.seeder.last_mutations();
+        mutations.extend_from_slice(&self.mutations);
+        mutations
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        if let (Some(scheduler), Some(operator)) = (&mut self.scheduler, self.last_operator) {
+            scheduler.reward(&[operator], result);
+        }
+        self.seeder.report_result(result);
+    }
+
+    fn report_foreign(&mut self, input: &[u8], result: &RunnerResult) {
+        self.seeder.report_foreign(input, result);
+    }
+
+    fn report(&self, out: &mut Vec<String>) {
+        self.seeder.report(out);
+        if let Some(scheduler) = &self.scheduler {
+            out.push(format!("Operators: {}", scheduler.report()));
         }
     }
 }
 
-impl<T: Rng> Seedable for MutationSeedModifier<T> {
+/**
+ * Seeder which mutates derivation trees instead of bytes.
+ * It keeps a population of trees, picks one and applies one of the
+ * configured tree mutation strategies to it. Mutated trees whose run shows a
+ * new behavior join the population, so mutations build on each other.
+ */
+#[allow(unused)]
+pub struct TreeMutationSeeder<T: Rng> {
+    grammar_seeder: GrammarSeeder<T>,
+    shortest: ShortestDerivations,
+    pub strategies: Vec<TreeMutationStrategy>,
+    pub population: Vec<DerivationTree>,
+    pub population_size: usize,
+    /* Strategy applied to the last seed, None if it was freshly generated */
+    pub last_strategy: Option<TreeMutationStrategy>,
+    last_tree: Option<DerivationTree>,
+    /* Behaviors seen so far, trees with a new one join the population */
+    behaviors: HashSet<u64>,
+}
+
+#[allow(unused)]
+impl<T: Rng> TreeMutationSeeder<T> {
+    pub fn init(grammar_seeder: GrammarSeeder<T>) -> TreeMutationSeeder<T> {
+        Self::init_with_strategies(grammar_seeder, ALL_TREE_MUTATION_STRATEGIES.to_vec())
+    }
+
+    pub fn init_with_strategies(grammar_seeder: GrammarSeeder<T>, strategies: Vec<TreeMutationStrategy>) -> TreeMutationSeeder<T> {
+        assert!(!strategies.is_empty());
+        let shortest = shortest_derivations(&grammar_seeder.grammar);
+        TreeMutationSeeder {
+            grammar_seeder,
+            shortest,
+            strategies,
+            population: Vec::new(),
+            population_size: TREE_POPULATION_SIZE,
+            last_strategy: None,
+            last_tree: None,
+            behaviors: HashSet::new(),
+        }
+    }
+
+    /**
+     * Adds a tree to the population, replacing a random one if it is full.
+     */
+    pub fn add_tree(&mut self, tree: DerivationTree) {
+        if self.population.len() < self.population_size {
+            self.population.push(tree);
+        } else {
+            let index = self.grammar_seeder.rng.gen_range(0..self.population.len());
+            self.population[index] = tree;
+        }
+    }
+
+    /**
+     * Parses an existing input with the grammar and adds its derivation to the population.
+     * For ambiguous inputs only the first derivation is used.
+     */
+    pub fn import_sample(&mut self, sample: &[u8]) -> Result<(), ParseError> {
+        let parser = EarleyParser::init(&self.grammar_seeder.grammar, &self.grammar_seeder.terminals);
+        let mut result = parser.parse(GRAMMAR_ENTRY, sample)?;
+        if result.ambiguous {
+            debug!("Sample {:?} is ambiguous, using the first of {} derivations",
+                String::from_utf8_lossy(sample), result.trees.len());
+        }
+        self.add_tree(result.trees.swap_remove(0));
+        Ok(())
+    }
+
+    /**
+     * Imports every file of a directory as a sample, in the order of the
+     * file names. Files which do not parse are skipped and listed in the
+     * returned summary.
+     */
+    pub fn import_directory(&mut self, directory: &Path) -> io::Result<ImportSummary> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let parser = EarleyParser::init(&self.grammar_seeder.grammar, &self.grammar_seeder.terminals);
+        let mut summary = ImportSummary::default();
+        let mut trees = Vec::new();
+        for path in paths {
+            trees.extend(summary.record(&path.display().to_string(), parser.parse(GRAMMAR_ENTRY, &fs::read(&path)?)));
+        }
+        for tree in trees {
+            self.add_tree(tree);
+        }
+        Ok(summary)
+    }
+}
+
+impl<T: Rng> Seedable for TreeMutationSeeder<T> {
     fn next_seed(&mut self) -> Vec<u8> {
-        //get seed
-        let mut seed = self.grammar_seeder.next_seed();
-        //modifiy seed sometimes
-        let operation = self.grammar_seeder.rng.gen_range(0..4);
-
-        match operation {
-            0 => self.insert_byte(&mut seed),
-            1 => self.remove_byte(&mut seed),
-            2 => self.modify_byte(&mut seed),
-            _ => { 
-                //dont modify
-            },
+        if self.population.len() < self.population_size {
+            let seed = self.grammar_seeder.next_seed();
+            let tree = self.grammar_seeder.last_tree.clone().unwrap();
+            self.population.push(tree.clone());
+            self.last_tree = Some(tree);
+            self.last_strategy = None;
+            return seed;
         }
 
+        let rng = &mut self.grammar_seeder.rng;
+        let mut tree = self.population[rng.gen_range(0..self.population.len())].clone();
+        let donor = &self.population[rng.gen_range(0..self.population.len())];
+        let strategy = self.strategies[rng.gen_range(0..self.strategies.len())];
+        let applied = mutate_tree(&self.grammar_seeder.grammar, &self.grammar_seeder.terminals, &self.shortest, strategy, &mut tree, Some(donor), rng);
+        self.last_strategy = applied.then_some(strategy);
+        self.grammar_seeder.apply_constraints(&mut tree);
+        self.grammar_seeder.coverage.record(&tree);
+
+        let seed = tree.to_bytes();
+        self.last_tree = Some(tree);
         seed
     }
-}
 
+    fn last_derivation_tree(&self) -> Option<&DerivationTree> {
+        self.last_tree.as_ref()
+    }
+
+    fn grammar_coverage(&self) -> Option<&GrammarCoverage> {
+        self.grammar_seeder.grammar_coverage()
+    }
+
+    fn report_result(&mut self, result: &RunnerResult) {
+        // generated trees joined the population already, mutated ones only if they found something
+        if self.behaviors.insert(result.behavior) && self.last_strategy.is_some() {
+            if let Some(tree) = self.last_tree.clone() {
+                self.add_tree(tree);
+            }
+        }
+    }
+
+    fn report_foreign(&mut self, _input: &[u8], result: &RunnerResult) {
+        if self.behaviors.insert(result.behavior) {
+            if let Some(tree) = result.derivation_tree.clone() {
+                self.add_tree(tree);
+            }
+        }
+    }
+}
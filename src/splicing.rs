@@ -0,0 +1,202 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::str::FromStr;
+
+use crate::derivation::DerivationTree;
+use crate::seeder::ByteMutation;
+use crate::tree_mutator::crossover_subtree;
+
+/*
+ * Attempts to find a subtree exchange which changes the input.
+ */
+const SUBTREE_ATTEMPTS: usize = 8;
+
+/**
+ * Crossover operators combining two inputs into one.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpliceOperator {
+    /* Head of the first input and tail of the second, split where they differ */
+    SinglePoint,
+    /* The first input with a block of the second in the range where they differ */
+    TwoPoint,
+    /* Head of the first input and tail of the second, split before a delimiter, the same one if possible */
+    Token,
+    /* Subtree of the first derivation tree replaced by one of the same nonterminal from the second */
+    Subtree,
+}
+
+pub const ALL_SPLICE_OPERATORS: [SpliceOperator; 4] = [
+    SpliceOperator::SinglePoint,
+    SpliceOperator::TwoPoint,
+    SpliceOperator::Token,
+    SpliceOperator::Subtree,
+];
+
+impl SpliceOperator {
+    /**
+     * Name on the command line, e.g. "two-point".
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpliceOperator::SinglePoint => "single-point",
+            SpliceOperator::TwoPoint => "two-point",
+            SpliceOperator::Token => "token",
+            SpliceOperator::Subtree => "subtree",
+        }
+    }
+
+    /**
+     * Whether the operator works on derivation trees instead of bytes.
+     */
+    pub fn needs_trees(&self) -> bool {
+        *self == SpliceOperator::Subtree
+    }
+}
+
+impl FromStr for SpliceOperator {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<SpliceOperator, String> {
+        ALL_SPLICE_OPERATORS.into_iter()
+            .find(|o| o.name() == name)
+            .ok_or_else(|| format!("unknown splice operator '{}'", name))
+    }
+}
+
+/*
+ * First and last offset at which the common length of `a` and `b` differs.
+ */
+fn differing_range(a: &[u8], b: &[u8]) -> Option<(usize, usize)> {
+    let first = a.iter().zip(b).position(|(x, y)| x != y)?;
+    let last = a.iter().zip(b).rposition(|(x, y)| x != y)?;
+    Some((first, last))
+}
+
+/*
+ * Delimiters for token splicing: everything except ASCII letters and digits.
+ */
+fn is_delimiter(b: u8) -> bool {
+    b.is_ascii() && !b.is_ascii_alphanumeric()
+}
+
+/**
+ * Combines `a` with parts of `b` using a byte-level operator. Returns None if
+ * the operator does not apply, e.g. because the inputs differ in at most one
+ * byte, or if the result equals `a`. Subtree splicing needs splice_trees().
+ */
+pub fn splice(operator: SpliceOperator, a: &[u8], b: &[u8], rng: &mut impl Rng) -> Option<Vec<u8>> {
+    let result = match operator {
+        SpliceOperator::SinglePoint => {
+            // as in AFL, split between two differing bytes so both inputs contribute
+            let (first, last) = differing_range(a, b).filter(|(first, last)| last > first)?;
+            let split = rng.gen_range(first + 1..=last);
+            [&a[..split], &b[split..]].concat()
+        },
+        SpliceOperator::TwoPoint => {
+            let (first, last) = differing_range(a, b)?;
+            let start = rng.gen_range(first..=last);
+            let end = rng.gen_range(start + 1..=last + 1);
+            [&a[..start], &b[start..end], &a[end..]].concat()
+        },
+        SpliceOperator::Token => {
+            let boundaries = |input: &[u8]| -> Vec<usize> { (0..input.len()).filter(|&i| is_delimiter(input[i])).collect() };
+            let split = *boundaries(a).choose(rng)?;
+            let b_boundaries = boundaries(b);
+            let same: Vec<usize> = b_boundaries.iter().copied().filter(|&j| b[j] == a[split]).collect();
+            let other = *same.choose(rng).or_else(|| b_boundaries.choose(rng))?;
+            [&a[..split], &b[other..]].concat()
+        },
+        SpliceOperator::Subtree => return None,
+    };
+    (result != a).then_some(result)
+}
+
+/**
+ * Copy of `a` with a subtree replaced by a subtree of `b` labeled with the same
+ * nonterminal, None if there is no such exchange which changes the input.
+ */
+pub fn splice_trees(a: &DerivationTree, b: &DerivationTree, rng: &mut impl Rng) -> Option<DerivationTree> {
+    let bytes = a.to_bytes();
+    (0..SUBTREE_ATTEMPTS).find_map(|_| {
+        let mut tree = a.clone();
+        (crossover_subtree(&mut tree, b, rng) && tree.to_bytes() != bytes).then_some(tree)
+    })
+}
+
+/**
+ * Mutations turning `original` into `result`: the part between their common
+ * prefix and suffix is overwritten, or removed and inserted if its length changed.
+ */
+pub fn replacement_mutations(original: &[u8], result: &[u8]) -> Vec<ByteMutation> {
+    let prefix = original.iter().zip(result).take_while(|(x, y)| x == y).count();
+    let suffix = original[prefix..].iter().rev().zip(result[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+    let removed = original.len() - prefix - suffix;
+    let inserted = result[prefix..result.len() - suffix].to_vec();
+    if removed == inserted.len() {
+        return if removed == 0 { Vec::new() } else { vec![ByteMutation::Overwrite { position: prefix, bytes: inserted }] };
+    }
+    let mut mutations = Vec::new();
+    if removed > 0 {
+        mutations.push(ByteMutation::RemoveBlock { position: prefix, length: removed });
+    }
+    if !inserted.is_empty() {
+        mutations.push(ByteMutation::InsertBlock { position: prefix, bytes: inserted });
+    }
+    mutations
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::grammar::{create_cgi_grammar, generate_tree, GRAMMAR_ENTRY};
+    use crate::terminals::TerminalRegistry;
+
+    #[test]
+    fn test_byte_splicing() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (a, b) = (b"name=alice&id=1".as_slice(), b"name=bob;id=22&x".as_slice());
+        for _ in 0..50 {
+            let single = splice(SpliceOperator::SinglePoint, a, b, &mut rng).unwrap();
+            let split = single.iter().zip(a).take_while(|(x, y)| x == y).count();
+            assert!(split >= 5 && b.ends_with(&single[split..]));
+
+            let two = splice(SpliceOperator::TwoPoint, a, b, &mut rng).unwrap();
+            assert_eq!(two.len(), a.len());
+            assert!(two.starts_with(b"name=") && two != a);
+
+            // the split is before the same delimiter in both inputs
+            let token = splice(SpliceOperator::Token, a, b, &mut rng).unwrap();
+            assert!((0..a.len()).any(|i| (0..b.len()).any(|j| {
+                is_delimiter(a[i]) && a[i] == b[j] && token == [&a[..i], &b[j..]].concat()
+            })));
+        }
+        assert_eq!(splice(SpliceOperator::SinglePoint, b"abc", b"abd", &mut rng), None);
+        assert_eq!(splice(SpliceOperator::TwoPoint, b"abc", b"abc", &mut rng), None);
+        assert_eq!(splice(SpliceOperator::Token, b"abc", b"a=b", &mut rng), None);
+    }
+
+    #[test]
+    fn test_replacement_mutations() {
+        for (original, result) in [("a=1&b=2", "a=1;c=2"), ("abc", "abXYZc"), ("abcdef", "af"), ("same", "same"), ("", "new"), ("aaa", "aa")] {
+            assert_eq!(ByteMutation::replay(original.as_bytes(), &replacement_mutations(original.as_bytes(), result.as_bytes())), result.as_bytes());
+        }
+        assert_eq!(replacement_mutations(b"a=1", b"a=2"), vec![ByteMutation::Overwrite { position: 2, bytes: b"2".to_vec() }]);
+    }
+
+    #[test]
+    fn test_subtree_splicing() {
+        let grammar = create_cgi_grammar();
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(1);
+        let a = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+        let b = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+        let child = splice_trees(&a, &b, &mut rng).unwrap();
+        assert_ne!(child.to_bytes(), a.to_bytes());
+        assert_eq!(child.symbol(), GRAMMAR_ENTRY);
+        assert_eq!("subtree".parse::<SpliceOperator>(), Ok(SpliceOperator::Subtree));
+    }
+}
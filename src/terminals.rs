@@ -0,0 +1,460 @@
+use rand::{Rng, RngCore};
+use std::collections::HashMap;
+
+use crate::generation_context::{ContextTerminal, GenerationContext};
+
+/*
+ * Generators with at most this many different values list them all in values().
+ */
+const MAX_ENUMERABLE_VALUES: i128 = 256;
+
+/**
+ * A named terminal which is substituted with generated bytes in the generation phase,
+ * for example "MAGIC-u8" expands to a random decimal number between 0 and 255.
+ */
+pub trait TerminalGenerator {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8>;
+
+    /**
+     * Lengths of all prefixes of `input` this generator could have produced.
+     * Used to parse existing inputs, generators which cannot be parsed back keep the default.
+     */
+    fn match_prefix(&self, _input: &[u8]) -> Vec<usize> {
+        Vec::new()
+    }
+
+    /**
+     * Every value this generator can produce, if there are few enough to enumerate them.
+     */
+    fn values(&self) -> Option<Vec<Vec<u8>>> {
+        None
+    }
+
+    /**
+     * Generates a value with access to the context of the input being generated,
+     * e.g. to reuse values of earlier terminals. Generators which do not need the
+     * context keep the default.
+     */
+    fn generate_with_context(&self, _context: &mut GenerationContext, rng: &mut dyn RngCore) -> Vec<u8> {
+        self.generate(rng)
+    }
+}
+
+/**
+ * Any closure producing bytes can be registered as a generator.
+ */
+impl<F: Fn(&mut dyn RngCore) -> Vec<u8>> TerminalGenerator for F {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8> {
+        self(rng)
+    }
+}
+
+/**
+ * Generator from a closure which reads or writes the generation context.
+ * Outside of a generation, e.g. in TerminalRegistry::expand(), it gets an empty context.
+ */
+#[allow(unused)]
+pub struct ContextGenerator<F: Fn(&mut GenerationContext, &mut dyn RngCore) -> Vec<u8>>(pub F);
+
+impl<F: Fn(&mut GenerationContext, &mut dyn RngCore) -> Vec<u8>> TerminalGenerator for ContextGenerator<F> {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8> {
+        (self.0)(&mut GenerationContext::new(), rng)
+    }
+
+    fn generate_with_context(&self, context: &mut GenerationContext, rng: &mut dyn RngCore) -> Vec<u8> {
+        (self.0)(context, rng)
+    }
+}
+
+/**
+ * Decimal integer uniformly chosen from the inclusive range [min, max].
+ */
+pub struct IntegerRange {
+    pub min: i128,
+    pub max: i128,
+}
+
+impl TerminalGenerator for IntegerRange {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8> {
+        rng.gen_range(self.min..=self.max).to_string().into_bytes()
+    }
+
+    fn match_prefix(&self, input: &[u8]) -> Vec<usize> {
+        decimal_prefixes(input)
+            .into_iter()
+            .filter(|&(_, value)| self.min <= value && value <= self.max)
+            .map(|(length, _)| length)
+            .collect()
+    }
+
+    fn values(&self) -> Option<Vec<Vec<u8>>> {
+        (self.max - self.min < MAX_ENUMERABLE_VALUES)
+            .then(|| (self.min..=self.max).map(|v| v.to_string().into_bytes()).collect())
+    }
+}
+
+/**
+ * Decimal integer chosen from the values at the edges of the inclusive range
+ * [min, max]: min, min + 1, max - 1, max and -1, 0, 1 if they lie inside.
+ */
+pub struct BoundaryValues {
+    values: Vec<i128>,
+}
+
+impl BoundaryValues {
+    pub fn init(min: i128, max: i128) -> BoundaryValues {
+        assert!(min <= max);
+        let mut values: Vec<i128> = [min, min + 1, max - 1, max, -1, 0, 1]
+            .into_iter()
+            .filter(|v| min <= *v && *v <= max)
+            .collect();
+        values.sort();
+        values.dedup();
+        BoundaryValues { values }
+    }
+}
+
+impl TerminalGenerator for BoundaryValues {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8> {
+        self.values[rng.gen_range(0..self.values.len())].to_string().into_bytes()
+    }
+
+    fn match_prefix(&self, input: &[u8]) -> Vec<usize> {
+        decimal_prefixes(input)
+            .into_iter()
+            .filter(|(_, value)| self.values.contains(value))
+            .map(|(length, _)| length)
+            .collect()
+    }
+
+    fn values(&self) -> Option<Vec<Vec<u8>>> {
+        Some(self.values.iter().map(|v| v.to_string().into_bytes()).collect())
+    }
+}
+
+/**
+ * A single byte out of a set of allowed bytes.
+ */
+pub struct CharacterClass {
+    chars: Vec<u8>,
+}
+
+impl CharacterClass {
+    pub fn init(chars: &[u8]) -> CharacterClass {
+        assert!(!chars.is_empty());
+        CharacterClass { chars: chars.to_vec() }
+    }
+
+    /**
+     * Class of all bytes in the inclusive ranges, e.g. [(b'a', b'f'), (b'0', b'9')].
+     */
+    pub fn from_ranges(ranges: &[(u8, u8)]) -> CharacterClass {
+        let chars: Vec<u8> = ranges.iter().flat_map(|&(from, to)| from..=to).collect();
+        CharacterClass::init(&chars)
+    }
+}
+
+impl TerminalGenerator for CharacterClass {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8> {
+        vec![self.chars[rng.gen_range(0..self.chars.len())]]
+    }
+
+    fn match_prefix(&self, input: &[u8]) -> Vec<usize> {
+        match input.first() {
+            Some(b) if self.chars.contains(b) => vec![1],
+            _ => Vec::new(),
+        }
+    }
+
+    fn values(&self) -> Option<Vec<Vec<u8>>> {
+        Some(self.chars.iter().map(|&b| vec![b]).collect())
+    }
+}
+
+/**
+ * Raw fixed-width unsigned integer of `width` bytes in little or big endian.
+ */
+pub struct BinaryInteger {
+    pub width: usize,
+    pub big_endian: bool,
+}
+
+impl TerminalGenerator for BinaryInteger {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<u8> {
+        let value: u64 = rng.gen();
+        if self.big_endian {
+            value.to_be_bytes()[8 - self.width..].to_vec()
+        } else {
+            value.to_le_bytes()[..self.width].to_vec()
+        }
+    }
+
+    fn match_prefix(&self, input: &[u8]) -> Vec<usize> {
+        if input.len() >= self.width { vec![self.width] } else { Vec::new() }
+    }
+
+    fn values(&self) -> Option<Vec<Vec<u8>>> {
+        (self.width == 1).then(|| (0..=255).map(|b| vec![b]).collect())
+    }
+}
+
+/**
+ * Lengths and values of the prefixes of `input` which are decimal numbers
+ * with an optional minus sign and without leading zeros.
+ */
+fn decimal_prefixes(input: &[u8]) -> Vec<(usize, i128)> {
+    let (sign, digits) = match input.first() {
+        Some(b'-') => (-1, &input[1..]),
+        _ => (1, input),
+    };
+    let offset = input.len() - digits.len();
+    let mut prefixes = Vec::new();
+    let mut value: i128 = 0;
+    for (i, b) in digits.iter().enumerate() {
+        if !b.is_ascii_digit() || (i == 1 && digits[0] == b'0') {
+            break;
+        }
+        value = match value.checked_mul(10).and_then(|v| v.checked_add((b - b'0') as i128)) {
+            Some(v) => v,
+            None => break,
+        };
+        prefixes.push((offset + i + 1, sign * value));
+    }
+    prefixes
+}
+
+/**
+ * Registry of named terminal generators.
+ * Terminals of a grammar whose text is registered here are substituted with
+ * the output of the generator, all other terminals are used literally.
+ */
+pub struct TerminalRegistry {
+    generators: HashMap<String, Box<dyn TerminalGenerator>>,
+}
+
+impl Default for TerminalRegistry {
+    fn default() -> Self {
+        TerminalRegistry::init()
+    }
+}
+
+impl TerminalRegistry {
+    /**
+     * Registry without any generators, every terminal is used literally.
+     */
+    pub fn empty() -> TerminalRegistry {
+        TerminalRegistry { generators: HashMap::new() }
+    }
+
+    /**
+     * Registry with the built-in MAGIC-* generators.
+     */
+    pub fn init() -> TerminalRegistry {
+        let mut registry = TerminalRegistry::empty();
+        let integers: [(&str, i128, i128); 8] = [
+            ("u8", 0, u8::MAX as i128),
+            ("u16", 0, u16::MAX as i128),
+            ("u32", 0, u32::MAX as i128),
+            ("u64", 0, u64::MAX as i128),
+            ("i8", i8::MIN as i128, i8::MAX as i128),
+            ("i16", i16::MIN as i128, i16::MAX as i128),
+            ("i32", i32::MIN as i128, i32::MAX as i128),
+            ("i64", i64::MIN as i128, i64::MAX as i128),
+        ];
+        for (name, min, max) in integers {
+            registry.register(&format!("MAGIC-{}", name), IntegerRange { min, max });
+            registry.register(&format!("MAGIC-{}-Boundary", name), BoundaryValues::init(min, max));
+        }
+        for width in [2, 4, 8] {
+            registry.register(&format!("MAGIC-u{}le", width * 8), BinaryInteger { width, big_endian: false });
+            registry.register(&format!("MAGIC-u{}be", width * 8), BinaryInteger { width, big_endian: true });
+        }
+        registry.register("MAGIC-Byte", BinaryInteger { width: 1, big_endian: false });
+
+        let classes: [(&str, &[(u8, u8)]); 9] = [
+            ("AsciiDigit", &[(b'0', b'9')]),
+            ("LowerCaseLetter", &[(b'a', b'z')]),
+            ("UpperCaseLetter", &[(b'A', b'Z')]),
+            ("Letter", &[(b'a', b'z'), (b'A', b'Z')]),
+            ("Alphanumeric", &[(b'a', b'z'), (b'A', b'Z'), (b'0', b'9')]),
+            ("HexDigit", &[(b'0', b'9'), (b'A', b'F')]),
+            ("LowerHexDigit", &[(b'0', b'9'), (b'a', b'f')]),
+            ("Printable", &[(b' ', b'~')]),
+            ("Whitespace", &[(b' ', b' '), (b'\t', b'\n'), (b'\r', b'\r')]),
+        ];
+        for (name, ranges) in classes {
+            registry.register(&format!("MAGIC-{}", name), CharacterClass::from_ranges(ranges));
+        }
+        registry
+    }
+
+    /**
+     * Registers a generator, replacing an existing one with the same name.
+     */
+    pub fn register(&mut self, name: &str, generator: impl TerminalGenerator + 'static) {
+        self.generators.insert(name.to_string(), Box::new(generator));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn TerminalGenerator> {
+        self.generators.get(name).map(|g| g.as_ref())
+    }
+
+    /*
+     * Generator for a grammar terminal, terminals which are no valid names are always literal.
+     */
+    fn lookup(&self, terminal: &[u8]) -> Option<&dyn TerminalGenerator> {
+        std::str::from_utf8(terminal).ok().and_then(|name| self.get(name))
+    }
+
+    #[allow(unused)]
+    pub fn contains(&self, name: &str) -> bool {
+        self.generators.contains_key(name)
+    }
+
+    /**
+     * Whether a terminal is generated, by a registered generator or as context terminal.
+     */
+    pub fn is_generated(&self, terminal: &[u8]) -> bool {
+        self.lookup(terminal).is_some() || ContextTerminal::parse(terminal).is_some()
+    }
+
+    /**
+     * Expands a terminal: registered names are generated, everything else is literal.
+     * Context terminals get an empty context.
+     */
+    pub fn expand(&self, terminal: &[u8], rng: &mut dyn RngCore) -> Vec<u8> {
+        self.expand_with_context(terminal, &mut GenerationContext::new(), rng)
+    }
+
+    /**
+     * Expands a terminal within the generation of an input, context terminals
+     * like "MAGIC-Counter:seq" and generators read and update `context`.
+     */
+    pub fn expand_with_context(&self, terminal: &[u8], context: &mut GenerationContext, rng: &mut dyn RngCore) -> Vec<u8> {
+        if let Some(context_terminal) = ContextTerminal::parse(terminal) {
+            return context_terminal.expand(context, &mut |inner, context| self.expand_with_context(inner, context, rng));
+        }
+        match self.lookup(terminal) {
+            Some(generator) => generator.generate_with_context(context, rng),
+            None => terminal.to_vec(),
+        }
+    }
+
+    /**
+     * Every value of a terminal: the literal itself, or all values of its
+     * generator if the generator can enumerate them.
+     */
+    pub fn values(&self, terminal: &[u8]) -> Option<Vec<Vec<u8>>> {
+        match ContextTerminal::parse(terminal) {
+            Some(ContextTerminal::Set(_, inner) | ContextTerminal::Var(_, inner) | ContextTerminal::Unique(_, inner)) => return self.values(inner),
+            Some(ContextTerminal::Counter(_) | ContextTerminal::Get(_)) => return None,
+            None => {},
+        }
+        match self.lookup(terminal) {
+            Some(generator) => generator.values(),
+            None => Some(vec![terminal.to_vec()]),
+        }
+    }
+
+    /**
+     * Lengths of all prefixes of `input` the terminal can produce.
+     * Without a context, "MAGIC-Get:..." matches every prefix.
+     */
+    pub fn match_prefix(&self, terminal: &[u8], input: &[u8]) -> Vec<usize> {
+        match ContextTerminal::parse(terminal) {
+            Some(ContextTerminal::Set(_, inner) | ContextTerminal::Var(_, inner) | ContextTerminal::Unique(_, inner)) => return self.match_prefix(inner, input),
+            Some(ContextTerminal::Counter(_)) => return decimal_prefixes(input).into_iter()
+                .filter(|&(_, value)| value >= 0)
+                .map(|(length, _)| length)
+                .collect(),
+            Some(ContextTerminal::Get(_)) => return (0..=input.len()).collect(),
+            None => {},
+        }
+        match self.lookup(terminal) {
+            Some(generator) => generator.match_prefix(input),
+            None if input.starts_with(terminal) => vec![terminal.len()],
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_hex_digit_covers_all_digits() {
+        let registry = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        let produced: HashSet<Vec<u8>> = (0..2000).map(|_| registry.expand(b"MAGIC-HexDigit", &mut rng)).collect();
+        let expected: HashSet<Vec<u8>> = b"0123456789ABCDEF".iter().map(|b| vec![*b]).collect();
+        assert_eq!(produced, expected);
+    }
+
+    #[test]
+    fn test_integer_range_includes_max() {
+        let registry = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        let produced: HashSet<Vec<u8>> = (0..20000).map(|_| registry.expand(b"MAGIC-u8", &mut rng)).collect();
+        assert!(produced.contains(b"255".as_slice()));
+        assert!(produced.contains(b"0".as_slice()));
+        assert_eq!(produced.len(), 256);
+        assert_eq!(registry.match_prefix(b"MAGIC-u8", b"2557"), vec![1, 2, 3]);
+        assert_eq!(registry.match_prefix(b"MAGIC-u8", b"256"), vec![1, 2]);
+        assert_eq!(registry.match_prefix(b"MAGIC-i8", b"-128"), vec![2, 3, 4]);
+        assert!(registry.match_prefix(b"MAGIC-u8", b"-1").is_empty());
+    }
+
+    #[test]
+    fn test_boundary_and_binary_integers() {
+        let registry = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let value = registry.expand(b"MAGIC-i16-Boundary", &mut rng);
+            assert!([&b"-32768"[..], b"-32767", b"-1", b"0", b"1", b"32766", b"32767"].contains(&value.as_slice()));
+            assert_eq!(registry.expand(b"MAGIC-u32be", &mut rng).len(), 4);
+        }
+        let generator = BinaryInteger { width: 2, big_endian: true };
+        let mut rng = StdRng::seed_from_u64(1);
+        let big = generator.generate(&mut rng);
+        let generator = BinaryInteger { width: 2, big_endian: false };
+        let mut rng = StdRng::seed_from_u64(1);
+        let little = generator.generate(&mut rng);
+        assert_eq!(big.len(), 2);
+        assert_eq!(big, little.into_iter().rev().collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_register_custom_generator() {
+        let mut registry = TerminalRegistry::init();
+        registry.register("MAGIC-Hello", |_: &mut dyn RngCore| b"hello".to_vec());
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(registry.expand(b"MAGIC-Hello", &mut rng), b"hello".to_vec());
+        assert_eq!(registry.expand(b"literal", &mut rng), b"literal".to_vec());
+        assert!(registry.match_prefix(b"MAGIC-Hello", b"hello").is_empty());
+    }
+
+    #[test]
+    fn test_context_generator() {
+        let mut registry = TerminalRegistry::init();
+        // repeats the session id, or starts a session
+        registry.register("MAGIC-Session", ContextGenerator(|context: &mut GenerationContext, _: &mut dyn RngCore| {
+            if context.get("session").is_none() {
+                context.set("session", b"s1".to_vec());
+            }
+            context.get("session").unwrap().to_vec()
+        }));
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut context = GenerationContext::new();
+        let id = registry.expand_with_context(b"MAGIC-Set:session:MAGIC-u16", &mut context, &mut rng);
+        assert_eq!(registry.expand_with_context(b"MAGIC-Session", &mut context, &mut rng), id);
+        assert_eq!(registry.expand(b"MAGIC-Session", &mut rng), b"s1".to_vec());
+        assert!(registry.is_generated(b"MAGIC-Counter:seq"));
+        assert_eq!(registry.match_prefix(b"MAGIC-Counter:seq", b"12,"), vec![1, 2]);
+        assert_eq!(registry.values(b"MAGIC-Var:x:MAGIC-AsciiDigit").unwrap().len(), 10);
+    }
+}
@@ -0,0 +1,346 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::derivation::DerivationTree;
+use crate::generation_context::GenerationContext;
+use crate::grammar::{generate_element, generate_tree_with_context, Grammar, GrammarRule};
+use crate::terminals::TerminalRegistry;
+
+/**
+ * Structure-aware mutations, which operate on derivation trees instead of bytes.
+ * The mutated tree always stays a valid derivation of the grammar.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeMutationStrategy {
+    /* Replace a random subtree with a freshly generated one of the same symbol */
+    Regenerate,
+    /* Replace a random subtree with a subtree of the same symbol taken from another tree */
+    Crossover,
+    /* Wrap a subtree into a recursive alternative of its own symbol, duplicating it */
+    Expand,
+    /* Replace a random subtree with the shortest derivation of its symbol */
+    Minimize,
+    /* Give a single regex or generator token a new value, keeping the rest of the tree */
+    RegenerateToken,
+}
+
+pub const ALL_TREE_MUTATION_STRATEGIES: [TreeMutationStrategy; 5] = [
+    TreeMutationStrategy::Regenerate,
+    TreeMutationStrategy::Crossover,
+    TreeMutationStrategy::Expand,
+    TreeMutationStrategy::Minimize,
+    TreeMutationStrategy::RegenerateToken,
+];
+
+impl TreeMutationStrategy {
+    /**
+     * Name on the command line, e.g. "regenerate-token".
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            TreeMutationStrategy::Regenerate => "regenerate",
+            TreeMutationStrategy::Crossover => "crossover",
+            TreeMutationStrategy::Expand => "expand",
+            TreeMutationStrategy::Minimize => "minimize",
+            TreeMutationStrategy::RegenerateToken => "regenerate-token",
+        }
+    }
+}
+
+impl FromStr for TreeMutationStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<TreeMutationStrategy, String> {
+        ALL_TREE_MUTATION_STRATEGIES.into_iter()
+            .find(|s| s.name() == name)
+            .ok_or_else(|| format!("unknown tree mutation strategy '{}'", name))
+    }
+}
+
+/**
+ * For every nonterminal the alternative with the smallest derivation (in nodes)
+ * and the size of that derivation.
+ */
+pub type ShortestDerivations = HashMap<String, (usize, usize)>;
+
+/**
+ * Computes the shortest derivation of every nonterminal with a fixpoint iteration.
+ * Nonterminals that cannot derive a finite tree are missing from the result.
+ */
+pub fn shortest_derivations(grammar: &Grammar) -> ShortestDerivations {
+    let mut shortest: ShortestDerivations = HashMap::new();
+    loop {
+        let mut changed = false;
+        for (symbol, rules) in grammar {
+            for (alternative, rule) in rules.iter().enumerate() {
+                let cost = rule.iter().try_fold(1, |acc, r| match r {
+                    GrammarRule::Terminal(_) | GrammarRule::Regex(_) => Some(acc + 1),
+                    GrammarRule::NonTerminal(nt) if !grammar.contains_key(nt) => Some(acc + 1),
+                    GrammarRule::NonTerminal(nt) => shortest.get(nt).map(|(_, c)| acc + c),
+                });
+                if let Some(cost) = cost {
+                    if shortest.get(symbol).is_none_or(|(_, c)| cost < *c) {
+                        shortest.insert(symbol.clone(), (alternative, cost));
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            return shortest;
+        }
+    }
+}
+
+/**
+ * Builds the smallest derivation tree for `symbol`. Terminals are still expanded
+ * randomly, so magic terminals get a fresh value.
+ */
+pub fn shortest_tree(grammar: &Grammar, terminals: &TerminalRegistry, shortest: &ShortestDerivations, context: &mut GenerationContext,
+    symbol: &str, rng: &mut impl Rng) -> DerivationTree {
+    match (grammar.get(symbol), shortest.get(symbol)) {
+        (Some(rules), Some(&(alternative, _))) => {
+            let children = rules[alternative].iter()
+                .map(|r| match r {
+                    GrammarRule::NonTerminal(nt) => shortest_tree(grammar, terminals, shortest, context, nt, rng),
+                    r => generate_element(grammar, terminals, context, r, rng),
+                })
+                .collect();
+            DerivationTree::NonTerminal { symbol: symbol.to_string(), alternative, children }
+        },
+        _ => generate_tree_with_context(grammar, terminals, context, symbol, rng),
+    }
+}
+
+/**
+ * Replaces a random nonterminal subtree with a newly generated one.
+ * Here and in the other mutations, new subtrees are generated with the
+ * context of the tree left of them, so e.g. counters continue and variables are reused.
+ */
+pub fn regenerate_subtree(grammar: &Grammar, terminals: &TerminalRegistry, tree: &mut DerivationTree, rng: &mut impl Rng) -> bool {
+    let paths = tree.nonterminal_paths(None);
+    let Some(path) = paths.choose(rng) else {
+        return false;
+    };
+    let symbol = tree.get(path).unwrap().symbol().to_string();
+    let mut context = GenerationContext::before(tree, path);
+    tree.replace(path, generate_tree_with_context(grammar, terminals, &mut context, &symbol, rng));
+    true
+}
+
+/**
+ * Replaces a random nonterminal subtree of `tree` with a copy of a subtree of
+ * `donor` that is labeled with the same nonterminal.
+ */
+pub fn crossover_subtree(tree: &mut DerivationTree, donor: &DerivationTree, rng: &mut impl Rng) -> bool {
+    let mut paths = tree.nonterminal_paths(None);
+    paths.shuffle(rng);
+    for path in paths {
+        let symbol = tree.get(&path).unwrap().symbol().to_string();
+        if let Some(donor_path) = donor.nonterminal_paths(Some(&symbol)).choose(rng) {
+            tree.replace(&path, donor.get(donor_path).unwrap().clone());
+            return true;
+        }
+    }
+    false
+}
+
+/**
+ * Picks a subtree of symbol S and an alternative of S which references S itself,
+ * then rebuilds the node with that alternative: every occurrence of S gets a copy
+ * of the original subtree, all other symbols are generated.
+ * For `String -> Letter String` this prepends a letter, for `Expr -> Expr + Expr`
+ * it duplicates the expression.
+ */
+pub fn expand_subtree(grammar: &Grammar, terminals: &TerminalRegistry, tree: &mut DerivationTree, rng: &mut impl Rng) -> bool {
+    let mut paths = tree.nonterminal_paths(None);
+    paths.shuffle(rng);
+    for path in paths {
+        let node = tree.get(&path).unwrap();
+        let symbol = node.symbol().to_string();
+        let Some(rules) = grammar.get(&symbol) else {
+            continue;
+        };
+        let recursive: Vec<usize> = rules.iter().enumerate()
+            .filter(|(_, rule)| rule.iter().any(|r| matches!(r, GrammarRule::NonTerminal(nt) if *nt == symbol)))
+            .map(|(i, _)| i)
+            .collect();
+        let Some(&alternative) = recursive.choose(rng) else {
+            continue;
+        };
+        let original = node.clone();
+        let mut context = GenerationContext::before(tree, &path);
+        let children = rules[alternative].iter()
+            .map(|r| match r {
+                GrammarRule::NonTerminal(nt) if *nt == symbol => {
+                    context.replay(&original);
+                    original.clone()
+                },
+                r => generate_element(grammar, terminals, &mut context, r, rng),
+            })
+            .collect();
+        tree.replace(&path, DerivationTree::NonTerminal { symbol, alternative, children });
+        return true;
+    }
+    false
+}
+
+/**
+ * Replaces a random subtree, which is larger than necessary, with the shortest
+ * derivation of its symbol.
+ */
+pub fn minimize_subtree(grammar: &Grammar, terminals: &TerminalRegistry, shortest: &ShortestDerivations, tree: &mut DerivationTree, rng: &mut impl Rng) -> bool {
+    let candidates: Vec<Vec<usize>> = tree.nonterminal_paths(None)
+        .into_iter()
+        .filter(|p| {
+            let node = tree.get(p).unwrap();
+            shortest.get(node.symbol()).is_some_and(|&(_, cost)| node.size() > cost)
+        })
+        .collect();
+    let Some(path) = candidates.choose(rng) else {
+        return false;
+    };
+    let symbol = tree.get(path).unwrap().symbol().to_string();
+    let mut context = GenerationContext::before(tree, path);
+    tree.replace(path, shortest_tree(grammar, terminals, shortest, &mut context, &symbol, rng));
+    true
+}
+
+/**
+ * Replaces the value of a random leaf that was produced by a regex or a registered
+ * generator like MAGIC-u16 with a new value. The tree structure is unchanged.
+ */
+pub fn regenerate_token(grammar: &Grammar, terminals: &TerminalRegistry, tree: &mut DerivationTree, rng: &mut impl Rng) -> bool {
+    let candidates: Vec<(Vec<usize>, GrammarRule)> = tree.paths()
+        .into_iter()
+        .filter_map(|path| {
+            let (&index, parent) = path.split_last()?;
+            let DerivationTree::NonTerminal { symbol, alternative, .. } = tree.get(parent)? else {
+                return None;
+            };
+            match grammar.get(symbol)?.get(*alternative)?.get(index)? {
+                r @ GrammarRule::Regex(_) => Some((path, r.clone())),
+                r @ GrammarRule::Terminal(t) if terminals.is_generated(t) => Some((path, r.clone())),
+                _ => None,
+            }
+        })
+        .collect();
+    let Some((path, rule)) = candidates.choose(rng) else {
+        return false;
+    };
+    let mut context = GenerationContext::before(tree, path);
+    tree.replace(path, generate_element(grammar, terminals, &mut context, rule, rng));
+    true
+}
+
+/**
+ * Applies one strategy to `tree`. Crossover needs a donor and does nothing without one.
+ * Returns whether the tree was changed.
+ */
+pub fn mutate_tree(grammar: &Grammar, terminals: &TerminalRegistry, shortest: &ShortestDerivations, strategy: TreeMutationStrategy,
+    tree: &mut DerivationTree, donor: Option<&DerivationTree>, rng: &mut impl Rng) -> bool {
+    match strategy {
+        TreeMutationStrategy::Regenerate => regenerate_subtree(grammar, terminals, tree, rng),
+        TreeMutationStrategy::Crossover => match donor {
+            Some(donor) => crossover_subtree(tree, donor, rng),
+            None => false,
+        },
+        TreeMutationStrategy::Expand => expand_subtree(grammar, terminals, tree, rng),
+        TreeMutationStrategy::Minimize => minimize_subtree(grammar, terminals, shortest, tree, rng),
+        TreeMutationStrategy::RegenerateToken => regenerate_token(grammar, terminals, tree, rng),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::grammar::{create_cgi_grammar, create_grammar_calculation, generate_tree, GRAMMAR_ENTRY};
+    use crate::grammar::tests::assert_tree_follows_grammar;
+
+    #[test]
+    fn test_shortest_derivations() {
+        let grammar = create_cgi_grammar();
+        let shortest = shortest_derivations(&grammar);
+        // Plus -> "+"
+        assert_eq!(shortest["Plus"], (0, 2));
+        // String -> Letter -> Plus -> "+"
+        assert_eq!(shortest["String"], (0, 4));
+        assert_eq!(shortest.len(), grammar.len());
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let tree = shortest_tree(&grammar, &TerminalRegistry::init(), &shortest, &mut GenerationContext::new(), GRAMMAR_ENTRY, &mut rng);
+        assert_eq!(tree.size(), shortest[GRAMMAR_ENTRY].1);
+    }
+
+    #[test]
+    fn test_strategies_keep_tree_valid() {
+        let grammar = create_grammar_calculation();
+        let terminals = TerminalRegistry::init();
+        let shortest = shortest_derivations(&grammar);
+        let mut rng = StdRng::seed_from_u64(7);
+        let donor = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+        for strategy in ALL_TREE_MUTATION_STRATEGIES {
+            for _ in 0..20 {
+                let mut tree = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+                mutate_tree(&grammar, &terminals, &shortest, strategy, &mut tree, Some(&donor), &mut rng);
+                assert_eq!(tree.symbol(), GRAMMAR_ENTRY);
+                assert_tree_follows_grammar(&grammar, &tree);
+            }
+        }
+    }
+
+    #[test]
+    fn test_expand_grows_and_minimize_shrinks() {
+        let grammar = create_cgi_grammar();
+        let terminals = TerminalRegistry::init();
+        let shortest = shortest_derivations(&grammar);
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut tree = shortest_tree(&grammar, &terminals, &shortest, &mut GenerationContext::new(), GRAMMAR_ENTRY, &mut rng);
+        let size = tree.size();
+        assert!(expand_subtree(&grammar, &terminals, &mut tree, &mut rng));
+        assert!(tree.size() > size);
+        while minimize_subtree(&grammar, &terminals, &shortest, &mut tree, &mut rng) {}
+        assert_eq!(tree.size(), size);
+    }
+
+    #[test]
+    fn test_regenerate_token_keeps_structure() {
+        let mut grammar = create_cgi_grammar();
+        grammar.insert("Percent".to_string(), vec![vec![GrammarRule::regex("%[0-9A-F]{2}")]]);
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(5);
+        let tree = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+        for _ in 0..20 {
+            let mut mutated = tree.clone();
+            assert!(regenerate_token(&grammar, &terminals, &mut mutated, &mut rng));
+            assert_tree_follows_grammar(&grammar, &mutated);
+            assert_eq!(mutated.paths(), tree.paths());
+            // only generated tokens are replaced, literal "+" stays
+            assert_eq!(mutated.to_bytes().iter().filter(|&&b| b == b'+').count(),
+                tree.to_bytes().iter().filter(|&&b| b == b'+').count());
+        }
+    }
+
+    #[test]
+    fn test_regenerated_middle_subtree_continues_counter() {
+        // Start -> Item Item Item, each Item is the next value of a counter
+        let mut grammar = HashMap::new();
+        grammar.insert(GRAMMAR_ENTRY.to_string(), vec![vec![GrammarRule::NonTerminal("Item".to_string()); 3]]);
+        grammar.insert("Item".to_string(), vec![vec![GrammarRule::Terminal(b"MAGIC-Counter:n".to_vec())]]);
+        let terminals = TerminalRegistry::init();
+        let mut rng = StdRng::seed_from_u64(2);
+        let tree = generate_tree(&grammar, &terminals, GRAMMAR_ENTRY, &mut rng);
+        assert_eq!(tree.to_bytes(), b"012");
+        // whichever subtree is regenerated, its counter continues from the items left of it
+        for _ in 0..20 {
+            let mut mutated = tree.clone();
+            assert!(regenerate_subtree(&grammar, &terminals, &mut mutated, &mut rng));
+            assert_eq!(mutated.to_bytes(), b"012");
+        }
+    }
+}